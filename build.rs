@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::configure()
+        .build_client(true)
+        .build_server(true)
+        .compile(&["proto/matchengine_ext.proto"], &["proto"])?;
+    Ok(())
+}
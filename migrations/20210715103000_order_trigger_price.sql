@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN trigger_price DECIMAL(30, 8) NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN time_in_force VARCHAR(30) NOT NULL DEFAULT 'gtc';
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN priority BIGINT CHECK (priority >= 0) NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN self_trade_prevention VARCHAR(30) NOT NULL DEFAULT 'cancel_newest';
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN client_order_id BIGINT CHECK (client_order_id >= 0) NOT NULL DEFAULT 0;
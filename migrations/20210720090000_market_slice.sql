@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE market_slice (
+    slice_id BIGINT NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    phase VARCHAR(30) NOT NULL DEFAULT 'continuous',
+    PRIMARY KEY (slice_id, market)
+);
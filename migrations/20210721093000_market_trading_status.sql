@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE market ADD COLUMN status VARCHAR(30) NOT NULL DEFAULT 'open';
+ALTER TABLE market_slice ADD COLUMN trading_status VARCHAR(30) NOT NULL DEFAULT 'open';
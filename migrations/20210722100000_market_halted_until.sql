@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE market_slice ADD COLUMN halted_until TIMESTAMP(0);
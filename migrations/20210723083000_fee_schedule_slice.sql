@@ -0,0 +1,18 @@
+-- Add migration script here
+CREATE TABLE fee_override_slice (
+    slice_id BIGINT NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    taker_fee DECIMAL(30, 16) NOT NULL,
+    maker_fee DECIMAL(30, 16) NOT NULL,
+    PRIMARY KEY (slice_id, market, user_id)
+);
+
+CREATE TABLE user_volume_slice (
+    slice_id BIGINT NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    day INT CHECK (day >= 0) NOT NULL,
+    volume DECIMAL(30, 16) NOT NULL,
+    PRIMARY KEY (slice_id, market, user_id, day)
+);
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN display_amount DECIMAL(30, 8) NOT NULL DEFAULT 0;
+ALTER TABLE order_slice ADD COLUMN visible DECIMAL(30, 8) NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN group_id BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE order_slice ADD COLUMN oco_trigger VARCHAR(30) NOT NULL DEFAULT 'partial_fill';
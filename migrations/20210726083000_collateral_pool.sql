@@ -0,0 +1,10 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN shared_collateral BOOL NOT NULL DEFAULT false;
+
+CREATE TABLE collateral_pool_slice (
+    slice_id BIGINT NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    amount DECIMAL(30, 16) NOT NULL,
+    PRIMARY KEY (slice_id, user_id, asset)
+);
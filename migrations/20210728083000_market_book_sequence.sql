@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE market_slice ADD COLUMN book_sequence BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN peg VARCHAR(30) NOT NULL DEFAULT 'none';
+ALTER TABLE order_slice ADD COLUMN peg_offset DECIMAL(30, 8) NOT NULL DEFAULT 0;
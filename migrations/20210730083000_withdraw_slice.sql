@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE withdraw_slice (
+    slice_id BIGINT NOT NULL,
+    business_id BIGINT NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    business VARCHAR(30) NOT NULL,
+    amount DECIMAL(30, 16) NOT NULL,
+    status VARCHAR(30) NOT NULL,
+    PRIMARY KEY (slice_id, business_id)
+);
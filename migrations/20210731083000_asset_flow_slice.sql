@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE asset_flow_slice (
+    slice_id BIGINT NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    deposit DECIMAL(30, 16) NOT NULL,
+    withdraw DECIMAL(30, 16) NOT NULL,
+    PRIMARY KEY (slice_id, asset)
+);
@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE balance_update_slice (
+    slice_id BIGINT NOT NULL,
+    seq BIGINT NOT NULL,
+    balance_type SMALLINT NOT NULL,
+    business_type SMALLINT NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    business VARCHAR(30) NOT NULL,
+    business_id BIGINT NOT NULL,
+    PRIMARY KEY (slice_id, seq)
+);
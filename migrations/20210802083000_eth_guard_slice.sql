@@ -0,0 +1,19 @@
+-- Add migration script here
+ALTER TABLE slice_history ADD COLUMN eth_block_number BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE eth_block_slice (
+    slice_id BIGINT NOT NULL,
+    block_number BIGINT NOT NULL,
+    block_hash VARCHAR(66),
+    PRIMARY KEY (slice_id, block_number)
+);
+
+CREATE TABLE eth_log_slice (
+    slice_id BIGINT NOT NULL,
+    seq BIGINT NOT NULL,
+    block_number BIGINT NOT NULL,
+    tx_hash VARCHAR(66) NOT NULL,
+    log_index VARCHAR(66) NOT NULL,
+    effect TEXT NOT NULL,
+    PRIMARY KEY (slice_id, seq)
+);
@@ -0,0 +1,12 @@
+-- Add migration script here
+ALTER TABLE market ADD COLUMN self_trade_prevention VARCHAR(30);
+ALTER TABLE market ADD COLUMN price_band DECIMAL(30, 16) NOT NULL DEFAULT 0;
+ALTER TABLE market ADD COLUMN halt_band DECIMAL(30, 16) NOT NULL DEFAULT 0;
+ALTER TABLE market ADD COLUMN halt_cooldown BIGINT CHECK (halt_cooldown >= 0) NOT NULL DEFAULT 300;
+ALTER TABLE market ADD COLUMN taker_fee DECIMAL(30, 16) NOT NULL DEFAULT 0;
+ALTER TABLE market ADD COLUMN maker_fee DECIMAL(30, 16) NOT NULL DEFAULT 0;
+-- json array of {volume, taker_fee, maker_fee}
+ALTER TABLE market ADD COLUMN fee_tiers TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE market ADD COLUMN matching VARCHAR(30) NOT NULL DEFAULT 'price_time';
+ALTER TABLE market ADD COLUMN pro_rata_top_order_share DECIMAL(30, 16) NOT NULL DEFAULT 0;
+ALTER TABLE market ADD COLUMN pro_rata_min_allocation DECIMAL(30, 16) NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE client_order_slice (
+    slice_id BIGINT NOT NULL,
+    order_id BIGINT NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    detail TEXT NOT NULL,
+    PRIMARY KEY (slice_id, order_id)
+);
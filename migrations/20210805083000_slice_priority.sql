@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE slice_history ADD COLUMN end_priority BIGINT NOT NULL DEFAULT 0;
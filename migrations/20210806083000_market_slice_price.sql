@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE market_slice ADD COLUMN price DECIMAL(30, 8) NOT NULL DEFAULT 0;
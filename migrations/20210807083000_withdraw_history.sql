@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE withdraw_history (
+    time TIMESTAMP(0) NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    business_id BIGINT NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    business VARCHAR(30) NOT NULL,
+    amount DECIMAL(30, 16) NOT NULL,
+    status VARCHAR(30) NOT NULL,
+    detail TEXT NOT NULL
+);
+
+CREATE INDEX withdraw_history_idx_user_time ON withdraw_history (user_id, time DESC);
+CREATE INDEX withdraw_history_idx_business_id ON withdraw_history (business_id);
+
+SELECT create_hypertable('withdraw_history', 'time');
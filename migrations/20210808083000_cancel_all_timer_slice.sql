@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE cancel_all_timer_slice (
+    slice_id BIGINT NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    trigger_time TIMESTAMP NOT NULL,
+    PRIMARY KEY (slice_id, user_id)
+);
@@ -23,6 +23,7 @@ fn get_msg_tag_from_topic(t: &str) -> Option<&'static str> {
         "registeruser" => "UserMessage",
         "trades" => "TradeMessage",
         "withdraws" => "WithdrawMessage",
+        "withdrawstatus" => "WithdrawStatusMessage",
         _ => {
             println!("skip msg of type {}", t);
             return None;
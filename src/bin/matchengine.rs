@@ -8,6 +8,8 @@
 use dingir_exchange::config;
 use dingir_exchange::controller::create_controller;
 use dingir_exchange::persist;
+use dingir_exchange::rpc_ext::matchengine_admin_server::MatchengineAdminServer;
+use dingir_exchange::rpc_ext::matchengine_ext_server::MatchengineExtServer;
 use dingir_exchange::server::GrpcHandler;
 //use dingir_exchange::sqlxextend;
 
@@ -62,15 +64,35 @@ async fn grpc_run(mut grpc: GrpcHandler) -> Result<(), Box<dyn std::error::Error
     log::info!("Starting gprc service");
 
     let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let (admin_tx, admin_rx) = tokio::sync::oneshot::channel::<()>();
     let on_leave = grpc.on_leave();
 
     tokio::spawn(async move {
         tokio::signal::ctrl_c().await.ok();
         log::info!("Ctrl-c received, shutting down");
         tx.send(()).ok();
+        admin_tx.send(()).ok();
     });
 
+    // the admin service is kept off the public address
+    let admin_listen = grpc.settings().admin_listen.clone();
+    if !admin_listen.is_empty() {
+        let admin_addr = admin_listen.parse()?;
+        log::info!("Starting admin gprc service at {}", admin_listen);
+        let admin_server = tonic::transport::Server::builder()
+            .add_service(MatchengineAdminServer::new(grpc.clone()))
+            .serve_with_shutdown(admin_addr, async {
+                admin_rx.await.ok();
+            });
+        tokio::spawn(async move {
+            if let Err(e) = admin_server.await {
+                log::error!("admin gprc service error: {}", e);
+            }
+        });
+    }
+
     tonic::transport::Server::builder()
+        .add_service(MatchengineExtServer::new(grpc.clone()))
         .add_service(MatchengineServer::new(grpc))
         .serve_with_shutdown(addr, async {
             rx.await.ok();
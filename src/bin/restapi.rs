@@ -73,7 +73,8 @@ async fn main() -> std::io::Result<()> {
                             web::scope("/market")
                                 .route("/reload", web::post().to(market::reload))
                                 .route("/tradepairs", web::post().to(market::add_pair))
-                                .route("/assets", web::post().to(market::add_assets)),
+                                .route("/assets", web::post().to(market::add_assets))
+                                .route("/status", web::post().to(market::update_status)),
                         )
                     } else {
                         web::scope("/manage")
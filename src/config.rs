@@ -1,8 +1,11 @@
+use crate::types::{MatchingMode, SelfTradePrevention};
 use config_rs::{Config, File};
+use fluidex_common::rust_decimal::prelude::Zero;
 use fluidex_common::rust_decimal::Decimal;
 use paperclip::actix::Apiv2Schema;
 use serde::de;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default, Apiv2Schema)]
@@ -36,6 +39,30 @@ pub struct Market {
     pub price_prec: u32,
     pub fee_prec: u32,
     pub min_amount: Decimal,
+    // falls back to `Settings::disable_self_trade` when not set
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    // price bands, as a ratio around the last price, zero disables them.
+    // Limit orders beyond `price_band` are rejected and other orders stop matching there,
+    // trading beyond `halt_band` halts the market for `halt_cooldown` seconds
+    pub price_band: Decimal,
+    pub halt_band: Decimal,
+    pub halt_cooldown: u64,
+    // the default fee rates, users reaching a tier by their 30-day traded quote volume pay its rates
+    pub taker_fee: Decimal,
+    pub maker_fee: Decimal,
+    pub fee_tiers: Vec<FeeTier>,
+    pub matching: MatchingMode,
+    // pro-rata only: the first order of a level by time priority takes this share of the incoming amount
+    // before the rest is split by size, splits smaller than the minimum allocation go by time priority
+    pub pro_rata_top_order_share: Decimal,
+    pub pro_rata_min_allocation: Decimal,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct FeeTier {
+    pub volume: Decimal,
+    pub taker_fee: Decimal,
+    pub maker_fee: Decimal,
 }
 
 impl Default for MarketUnit {
@@ -57,6 +84,16 @@ impl Default for Market {
             quote: Default::default(),
             amount_prec: 0,
             price_prec: 0,
+            self_trade_prevention: None,
+            price_band: Decimal::zero(),
+            halt_band: Decimal::zero(),
+            halt_cooldown: 300,
+            taker_fee: Decimal::zero(),
+            maker_fee: Decimal::zero(),
+            fee_tiers: Vec::new(),
+            matching: MatchingMode::PRICE_TIME,
+            pro_rata_top_order_share: Decimal::zero(),
+            pro_rata_min_allocation: Decimal::zero(),
         }
     }
 }
@@ -125,6 +162,17 @@ pub struct Settings {
     pub disable_market_order: bool,
     pub check_eddsa_signatue: OrderSignatrueCheck,
     pub user_order_num_limit: usize,
+    // the user credited with the trading fees of an asset, `fee_receiver` for the assets not listed
+    pub fee_receiver: u32,
+    pub fee_receivers: HashMap<String, u32>,
+    // refuse new orders while the last balance audit has hard mismatches
+    pub audit_refuse_orders: bool,
+    // how many of the latest deposits, withdrawals and transfers are remembered to refuse duplicates
+    pub balance_update_retention: usize,
+    // how many finished orders of each market are remembered by their client order ids
+    pub client_order_retention: usize,
+    // the address of the admin service, it is not served when empty
+    pub admin_listen: String,
 }
 
 impl Default for Settings {
@@ -148,11 +196,21 @@ impl Default for Settings {
             disable_market_order: false,
             check_eddsa_signatue: OrderSignatrueCheck::None,
             user_order_num_limit: 1000,
+            fee_receiver: 0,
+            fee_receivers: HashMap::new(),
+            audit_refuse_orders: false,
+            balance_update_retention: 1_000_000,
+            client_order_retention: 100_000,
+            admin_listen: "127.0.0.1:50052".to_string(),
         }
     }
 }
 
 impl Settings {
+    pub fn fee_receiver(&self, asset: &str) -> u32 {
+        self.fee_receivers.get(asset).copied().unwrap_or(self.fee_receiver)
+    }
+
     pub fn new() -> Self {
         // Initializes with `config/default.yaml`.
         let mut conf = Config::default();
@@ -1,6 +1,7 @@
 use super::asset_manager::AssetManager;
 use crate::config;
 pub use crate::models::BalanceHistory;
+use crate::types::WithdrawStatus;
 
 use anyhow::Result;
 use fluidex_common::rust_decimal::prelude::Zero;
@@ -8,13 +9,18 @@ use fluidex_common::rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
 use num_enum::TryFromPrimitive;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
+#[allow(non_camel_case_types)]
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash, Copy, TryFromPrimitive)]
 #[repr(i16)]
 pub enum BalanceType {
     AVAILABLE = 1,
     FREEZE = 2,
+    // held by a withdrawal which is neither confirmed nor rejected yet
+    WITHDRAW_PENDING = 3,
+    // what is left of a deposit undone by a reorg after it has been spent, held until settled by hand
+    REORG_HOLD = 4,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash)]
@@ -24,6 +30,23 @@ pub struct BalanceMapKey {
     pub asset: String,
 }
 
+// a two-phase withdrawal, by business id
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Withdrawal {
+    pub user_id: u32,
+    pub asset: String,
+    pub business: String,
+    pub amount: Decimal,
+    pub status: WithdrawStatus,
+}
+
+// what has been deposited and withdrawn of an asset so far, see `audit`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct AssetFlow {
+    pub deposit: Decimal,
+    pub withdraw: Decimal,
+}
+
 #[derive(Default)]
 pub struct BalanceStatus {
     pub total: Decimal,
@@ -31,12 +54,25 @@ pub struct BalanceStatus {
     pub available: Decimal,
     pub frozen_count: u32,
     pub frozen: Decimal,
+    pub withdraw_pending_count: u32,
+    pub withdraw_pending: Decimal,
+    pub reorg_hold_count: u32,
+    pub reorg_hold: Decimal,
 }
 
 //#[derive(default)]
 pub struct BalanceManager {
     pub asset_manager: AssetManager,
     pub balances: HashMap<BalanceMapKey, Decimal>,
+    // Shared reservations by user and asset. A pool is a part of the frozen balance which all the
+    // orders with `shared_collateral` paying in the asset draw on, instead of each freezing its own.
+    pub pools: BTreeMap<(u32, String), Decimal>,
+    // finished withdrawals are kept too, so that a retried confirm or reject is answered the same
+    pub withdrawals: BTreeMap<u64, Withdrawal>,
+    pub flows: BTreeMap<String, AssetFlow>,
+    // Assets held in a slice which has no flows of them. Their flows only count what happened
+    // since, so their supply is not checked and the partial flows are not saved in later slices.
+    pub unknown_flows: BTreeSet<String>,
 }
 
 impl BalanceManager {
@@ -45,11 +81,19 @@ impl BalanceManager {
         Ok(BalanceManager {
             asset_manager,
             balances: HashMap::new(),
+            pools: BTreeMap::new(),
+            withdrawals: BTreeMap::new(),
+            flows: BTreeMap::new(),
+            unknown_flows: BTreeSet::new(),
         })
     }
 
     pub fn reset(&mut self) {
-        self.balances.clear()
+        self.balances.clear();
+        self.pools.clear();
+        self.withdrawals.clear();
+        self.flows.clear();
+        self.unknown_flows.clear();
     }
     pub fn get(&self, user_id: u32, balance_type: BalanceType, asset: &str) -> Decimal {
         self.get_by_key(&BalanceMapKey {
@@ -160,6 +204,52 @@ impl BalanceManager {
         self.add(user_id, BalanceType::AVAILABLE, asset, &amount);
         self.sub(user_id, BalanceType::FREEZE, asset, &amount);
     }
+    pub fn pool_get(&self, user_id: u32, asset: &str) -> Decimal {
+        *self.pools.get(&(user_id, asset.to_owned())).unwrap_or(&Decimal::zero())
+    }
+    // move available balance into the pool
+    pub fn pool_add(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+        debug_assert!(amount.is_sign_positive());
+        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        self.frozen(user_id, asset, &amount);
+        *self.pools.entry((user_id, asset.to_owned())).or_insert_with(Decimal::zero) += amount;
+    }
+    // give pool balance back as available balance
+    pub fn pool_sub(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        self.pool_draw(user_id, asset, &amount);
+        self.unfrozen(user_id, asset, &amount);
+    }
+    // the pool balance is spent, the frozen balance is taken by the caller
+    pub fn pool_draw(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+        debug_assert!(amount.is_sign_positive());
+        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        let key = (user_id, asset.to_owned());
+        let old_value = self.pools.get(&key).copied().unwrap_or_else(Decimal::zero);
+        debug_assert!(old_value.ge(&amount), "draw larger than pool {} > {}", amount, old_value);
+        let new_value = old_value - amount;
+        if new_value.is_zero() {
+            self.pools.remove(&key);
+        } else {
+            self.pools.insert(key, new_value);
+        }
+    }
+    // move available balance into the pending balance of a withdrawal
+    pub fn withdraw_hold(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+        self.sub(user_id, BalanceType::AVAILABLE, asset, amount);
+        self.add(user_id, BalanceType::WITHDRAW_PENDING, asset, amount);
+    }
+    // give the pending balance of a rejected withdrawal back as available balance
+    pub fn withdraw_release(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+        self.sub(user_id, BalanceType::WITHDRAW_PENDING, asset, amount);
+        self.add(user_id, BalanceType::AVAILABLE, asset, amount);
+    }
+    pub fn flow_deposit(&mut self, asset: &str, amount: &Decimal) {
+        self.flows.entry(asset.to_owned()).or_default().deposit += amount.round_dp(self.asset_manager.asset_prec(asset));
+    }
+    pub fn flow_withdraw(&mut self, asset: &str, amount: &Decimal) {
+        self.flows.entry(asset.to_owned()).or_default().withdraw += amount.round_dp(self.asset_manager.asset_prec(asset));
+    }
     pub fn total(&self, user_id: u32, asset: &str) -> Decimal {
         self.get(user_id, BalanceType::AVAILABLE, asset) + self.get(user_id, BalanceType::FREEZE, asset)
     }
@@ -168,12 +258,23 @@ impl BalanceManager {
         for (k, amount) in self.balances.iter() {
             if k.asset.eq(asset) && !amount.is_zero() {
                 result.total += amount;
-                if k.balance_type == BalanceType::AVAILABLE {
-                    result.available_count += 1;
-                    result.available += amount;
-                } else {
-                    result.frozen_count += 1;
-                    result.frozen += amount;
+                match k.balance_type {
+                    BalanceType::AVAILABLE => {
+                        result.available_count += 1;
+                        result.available += amount;
+                    }
+                    BalanceType::FREEZE => {
+                        result.frozen_count += 1;
+                        result.frozen += amount;
+                    }
+                    BalanceType::WITHDRAW_PENDING => {
+                        result.withdraw_pending_count += 1;
+                        result.withdraw_pending += amount;
+                    }
+                    BalanceType::REORG_HOLD => {
+                        result.reorg_hold_count += 1;
+                        result.reorg_hold += amount;
+                    }
                 }
             }
         }
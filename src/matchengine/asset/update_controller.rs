@@ -6,12 +6,14 @@ pub use models::BalanceHistory;
 
 use anyhow::{bail, Result};
 use fluidex_common::rust_decimal::Decimal;
-use ttl_cache::TtlCache;
+use num_enum::TryFromPrimitive;
 
-use std::time::Duration;
+use std::collections::{HashSet, VecDeque};
 
 const BALANCE_MAP_INIT_SIZE_ASSET: usize = 64;
 const PERSIST_ZERO_BALANCE_UPDATE: bool = false;
+// how many of the latest balance updates are remembered to refuse duplicates
+const DEFAULT_UPDATE_RETENTION: usize = 1_000_000;
 
 pub struct BalanceUpdateParams {
     pub balance_type: BalanceType,
@@ -26,16 +28,17 @@ pub struct BalanceUpdateParams {
     pub signature: Vec<u8>,
 }
 
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, TryFromPrimitive)]
+#[repr(i16)]
 pub enum BusinessType {
-    Deposit,
-    Trade,
-    Transfer,
-    Withdraw,
+    Deposit = 1,
+    Trade = 2,
+    Transfer = 3,
+    Withdraw = 4,
 }
 
-#[derive(PartialEq, Eq, Hash)]
-struct BalanceUpdateKey {
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct BalanceUpdateKey {
     pub balance_type: BalanceType,
     pub business_type: BusinessType,
     pub user_id: u32,
@@ -50,25 +53,53 @@ struct BalanceUpdateKey {
 
 // TODO: this class needs to be refactored
 // Currently it has two purpose: (1) filter duplicate (2) generate message
+//
+// The updates applied are part of the engine state, they are kept in slices and rebuilt by
+// replaying the operation log. Only the latest `retention` of them are remembered, pruning
+// by count rather than by time keeps the replay deterministic.
 pub struct BalanceUpdateController {
-    cache: TtlCache<BalanceUpdateKey, bool>,
+    // oldest first
+    keys: VecDeque<BalanceUpdateKey>,
+    applied: HashSet<BalanceUpdateKey>,
+    retention: usize,
 }
 
 impl BalanceUpdateController {
     pub fn new() -> BalanceUpdateController {
-        let capacity = 1_000_000;
+        Self::with_retention(DEFAULT_UPDATE_RETENTION)
+    }
+    pub fn with_retention(retention: usize) -> BalanceUpdateController {
         BalanceUpdateController {
-            cache: TtlCache::new(capacity),
+            keys: VecDeque::new(),
+            applied: HashSet::new(),
+            retention,
         }
     }
     pub fn reset(&mut self) {
-        self.cache.clear()
+        self.keys.clear();
+        self.applied.clear();
+    }
+    pub fn is_applied(&self, key: &BalanceUpdateKey) -> bool {
+        self.applied.contains(key)
+    }
+    // oldest first, which is also the order to remember them in
+    pub fn applied_keys(&self) -> impl Iterator<Item = &BalanceUpdateKey> {
+        self.keys.iter()
     }
-    pub fn on_timer(&mut self) {
-        self.cache.clear()
+    // an update which has been undone, it may be applied again
+    pub fn forget(&mut self, key: &BalanceUpdateKey) {
+        if self.applied.remove(key) {
+            self.keys.retain(|applied| applied != key);
+        }
     }
-    pub fn timer_interval(&self) -> Duration {
-        Duration::from_secs(60)
+    pub fn remember(&mut self, key: BalanceUpdateKey) {
+        if self.applied.insert(key.clone()) {
+            self.keys.push_back(key);
+        }
+        while self.keys.len() > self.retention {
+            let oldest = self.keys.pop_front().unwrap();
+            self.applied.remove(&oldest);
+        }
     }
     // return false if duplicate
     pub fn update_user_balance(
@@ -83,7 +114,7 @@ impl BalanceUpdateController {
         let business_type = params.business_type;
         let business_id = params.business_id;
         let user_id = params.user_id;
-        let cache_key = BalanceUpdateKey {
+        let update_key = BalanceUpdateKey {
             balance_type,
             business_type,
             user_id,
@@ -91,7 +122,10 @@ impl BalanceUpdateController {
             business: business.clone(),
             business_id,
         };
-        if self.cache.contains_key(&cache_key) {
+        // trade ids come from the sequencer and never repeat, so trades are left out of the
+        // retention window, which is kept for the requests from outside
+        let deduplicated = business_type != BusinessType::Trade;
+        if deduplicated && self.is_applied(&update_key) {
             bail!("duplicate request");
         }
         let old_balance = balance_manager.get(user_id, balance_type, &asset);
@@ -105,8 +139,15 @@ impl BalanceUpdateController {
             }
             balance_manager.sub(user_id, balance_type, &asset, &abs_change);
         }
+        match business_type {
+            BusinessType::Deposit => balance_manager.flow_deposit(&asset, &abs_change),
+            BusinessType::Withdraw => balance_manager.flow_withdraw(&asset, &abs_change),
+            _ => {}
+        }
         log::debug!("change user balance: {} {} {}", user_id, asset, change);
-        self.cache.insert(cache_key, true, Duration::from_secs(3600));
+        if deduplicated {
+            self.remember(update_key);
+        }
         if persistor.real_persist() && (PERSIST_ZERO_BALANCE_UPDATE || !change.is_zero()) {
             params.detail["id"] = serde_json::Value::from(business_id);
             let balance_available = balance_manager.get(user_id, BalanceType::AVAILABLE, &asset);
@@ -141,3 +182,100 @@ impl Default for BalanceUpdateController {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use fluidex_common::rust_decimal_macros::*;
+
+    fn deposit(business_id: u64) -> BalanceUpdateParams {
+        BalanceUpdateParams {
+            balance_type: BalanceType::AVAILABLE,
+            business_type: BusinessType::Deposit,
+            user_id: 1801,
+            business_id,
+            asset: MockAsset::USDT.id(),
+            business: "deposit".to_owned(),
+            market_price: dec!(0),
+            change: dec!(10),
+            detail: serde_json::Value::default(),
+            signature: vec![],
+        }
+    }
+
+    #[test]
+    fn test_update_retention() {
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let persistor = &mut DummyPersistor::default();
+        let mut update_controller = BalanceUpdateController::with_retention(2);
+        for business_id in 1..=3 {
+            update_controller
+                .update_user_balance(balance_manager, persistor, deposit(business_id))
+                .unwrap();
+        }
+        assert!(update_controller
+            .update_user_balance(balance_manager, persistor, deposit(3))
+            .is_err());
+        assert_eq!(balance_manager.get(1801, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(30));
+
+        // what a slice keeps and a restart restores, the first deposit is beyond the retention
+        let keys: Vec<BalanceUpdateKey> = update_controller.applied_keys().cloned().collect();
+        assert_eq!(keys.iter().map(|key| key.business_id).collect::<Vec<_>>(), vec![2, 3]);
+        let mut restored = BalanceUpdateController::with_retention(2);
+        for key in keys {
+            restored.remember(key);
+        }
+        assert!(restored.update_user_balance(balance_manager, persistor, deposit(2)).is_err());
+        restored.update_user_balance(balance_manager, persistor, deposit(1)).unwrap();
+        assert_eq!(balance_manager.get(1801, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(40));
+    }
+
+    #[test]
+    fn test_update_key() {
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let persistor = &mut DummyPersistor::default();
+        let mut update_controller = BalanceUpdateController::new();
+        update_controller
+            .update_user_balance(balance_manager, persistor, deposit(1))
+            .unwrap();
+        assert!(update_controller
+            .update_user_balance(balance_manager, persistor, deposit(1))
+            .is_err());
+
+        // the same business id of another balance type or business type is another update
+        update_controller
+            .update_user_balance(
+                balance_manager,
+                persistor,
+                BalanceUpdateParams {
+                    balance_type: BalanceType::FREEZE,
+                    ..deposit(1)
+                },
+            )
+            .unwrap();
+        update_controller
+            .update_user_balance(
+                balance_manager,
+                persistor,
+                BalanceUpdateParams {
+                    business_type: BusinessType::Transfer,
+                    ..deposit(1)
+                },
+            )
+            .unwrap();
+        assert_eq!(balance_manager.get(1801, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(20));
+        assert_eq!(balance_manager.get(1801, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(10));
+
+        // trades are not remembered, both sides of a self trade share the trade id
+        let trade = || BalanceUpdateParams {
+            business_type: BusinessType::Trade,
+            business: "trade".to_owned(),
+            ..deposit(2)
+        };
+        update_controller.update_user_balance(balance_manager, persistor, trade()).unwrap();
+        update_controller.update_user_balance(balance_manager, persistor, trade()).unwrap();
+        assert_eq!(update_controller.applied_keys().count(), 3);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::asset::{BalanceManager, BalanceType};
+use crate::market::Market;
+
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum MismatchKind {
+    // a balance below zero
+    NEGATIVE_BALANCE,
+    // the frozen balance of a user is not what its orders and its collateral pool hold
+    FROZEN_MISMATCH,
+    // the balances of an asset do not add up to its deposits less its withdrawals,
+    // the trading fees are credited to the fee receivers so they are part of the balances
+    SUPPLY_MISMATCH,
+}
+
+// A broken invariant, of a user and asset or of an asset. A hard mismatch means balances have been
+// made up or lost, or the orders of a user hold more than its frozen balance.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Mismatch {
+    pub kind: MismatchKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<u32>,
+    pub asset: String,
+    pub expected: Decimal,
+    pub actual: Decimal,
+    pub hard: bool,
+}
+
+// check the balances against the orders of the markets and against the deposits and withdrawals
+pub fn audit<'a>(balance_manager: &BalanceManager, markets: impl Iterator<Item = &'a Market>) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+
+    // what the frozen balances should be, by user and asset
+    let mut frozen: BTreeMap<(u32, String), Decimal> = balance_manager.pools.clone();
+    for market in markets {
+        for order_rc in market.orders.values() {
+            let order = order_rc.borrow();
+            let asset = if order.is_ask() { market.base } else { market.quote };
+            *frozen.entry((order.user, asset.to_owned())).or_insert_with(Decimal::zero) += order.frozen;
+        }
+    }
+
+    let mut supply: BTreeMap<&str, Decimal> = BTreeMap::new();
+    for (key, amount) in &balance_manager.balances {
+        if amount.is_sign_negative() && !amount.is_zero() {
+            mismatches.push(Mismatch {
+                kind: MismatchKind::NEGATIVE_BALANCE,
+                user_id: Some(key.user_id),
+                asset: key.asset.clone(),
+                expected: Decimal::zero(),
+                actual: *amount,
+                hard: true,
+            });
+        }
+        *supply.entry(&key.asset).or_insert_with(Decimal::zero) += amount;
+        if key.balance_type == BalanceType::FREEZE {
+            let expected = frozen.remove(&(key.user_id, key.asset.clone())).unwrap_or_else(Decimal::zero);
+            if *amount != expected {
+                mismatches.push(Mismatch {
+                    kind: MismatchKind::FROZEN_MISMATCH,
+                    user_id: Some(key.user_id),
+                    asset: key.asset.clone(),
+                    expected,
+                    actual: *amount,
+                    hard: *amount < expected,
+                });
+            }
+        }
+    }
+    // the orders of users without any frozen balance
+    for ((user_id, asset), expected) in frozen {
+        if !expected.is_zero() {
+            mismatches.push(Mismatch {
+                kind: MismatchKind::FROZEN_MISMATCH,
+                user_id: Some(user_id),
+                asset,
+                expected,
+                actual: Decimal::zero(),
+                hard: true,
+            });
+        }
+    }
+
+    for asset in balance_manager.flows.keys() {
+        supply.entry(asset).or_insert_with(Decimal::zero);
+    }
+    for (asset, actual) in supply {
+        if balance_manager.unknown_flows.contains(asset) {
+            continue;
+        }
+        let expected = balance_manager
+            .flows
+            .get(asset)
+            .map_or_else(Decimal::zero, |flow| flow.deposit - flow.withdraw);
+        if actual != expected {
+            mismatches.push(Mismatch {
+                kind: MismatchKind::SUPPLY_MISMATCH,
+                user_id: None,
+                asset: asset.to_owned(),
+                expected,
+                actual,
+                hard: true,
+            });
+        }
+    }
+
+    // the balances are kept in a hash map
+    mismatches.sort_by(|a, b| (&a.asset, a.user_id).cmp(&(&b.asset, b.user_id)));
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::update_controller::{BalanceUpdateController, BalanceUpdateParams, BusinessType};
+    use crate::config::Settings;
+    use crate::market::OrderSide;
+    use crate::matchengine::mock::*;
+    use crate::sequencer::Sequencer;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_audit() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let sequencer = &mut Sequencer::default();
+        let persistor = &mut crate::persist::DummyPersistor::default();
+        let mut deposit = |balance_manager: &mut BalanceManager, business_id, change| {
+            update_controller
+                .update_user_balance(
+                    balance_manager,
+                    persistor,
+                    BalanceUpdateParams {
+                        balance_type: BalanceType::AVAILABLE,
+                        business_type: if change > Decimal::zero() {
+                            BusinessType::Deposit
+                        } else {
+                            BusinessType::Withdraw
+                        },
+                        user_id: 1701,
+                        asset: MockAsset::USDT.id(),
+                        business: "deposit".to_owned(),
+                        business_id,
+                        market_price: dec!(0),
+                        change,
+                        detail: serde_json::Value::default(),
+                        signature: vec![],
+                    },
+                )
+                .unwrap();
+        };
+        deposit(balance_manager, 1, dec!(100));
+        deposit(balance_manager, 2, dec!(-30));
+
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                get_simple_order_input(1701, OrderSide::BID, dec!(2), dec!(10)),
+            )
+            .unwrap();
+        assert!(audit(balance_manager, std::iter::once(&market)).is_empty());
+
+        // a balance made up out of nothing, and a frozen balance which does not cover the order
+        balance_manager.add(1702, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(5));
+        balance_manager.set(1701, BalanceType::FREEZE, &MockAsset::USDT.id(), &dec!(18));
+        let mismatches = audit(balance_manager, std::iter::once(&market));
+        assert_eq!(
+            mismatches,
+            vec![
+                Mismatch {
+                    kind: MismatchKind::SUPPLY_MISMATCH,
+                    user_id: None,
+                    asset: MockAsset::USDT.id(),
+                    expected: dec!(70),
+                    actual: dec!(73),
+                    hard: true,
+                },
+                Mismatch {
+                    kind: MismatchKind::FROZEN_MISMATCH,
+                    user_id: Some(1701),
+                    asset: MockAsset::USDT.id(),
+                    expected: dec!(20),
+                    actual: dec!(18),
+                    hard: true,
+                },
+            ]
+        );
+
+        // the supply is not checked while the flows are unknown
+        balance_manager.unknown_flows.insert(MockAsset::USDT.id());
+        assert_eq!(audit(balance_manager, std::iter::once(&market)).len(), 1);
+    }
+}
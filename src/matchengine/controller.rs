@@ -1,16 +1,27 @@
-use crate::asset::update_controller::{BalanceUpdateParams, BusinessType};
-use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController};
+use crate::asset::update_controller::{BalanceUpdateKey, BalanceUpdateParams, BusinessType};
+use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController, Withdrawal};
+use crate::audit::{self, Mismatch};
 use crate::config::{self};
 use crate::database::{DatabaseWriterConfig, OperationLogSender};
-use crate::eth_guard::{EthLogGuard, EthLogMetadata};
+use crate::dto::{
+    str_to_decimal, BalanceAuditRequest, BalanceAuditResponse, BatchOrderPutRequestExt, BookCursor, BookOrder, CancelAllAfterRequest,
+    CancelAllAfterResponse, CollateralPoolUpdateRequest, CollateralPoolUpdateResponse, EthBlockRequest, EthBlockResponse,
+    FeeOverrideUpdateRequest, MarketInfoExt, MarketListResponseExt, MarketPhaseUpdateRequest, MarketPhaseUpdateResponse,
+    MarketStatusUpdateRequest, MarketSummaryExt, MarketSummaryResponseExt, OrderAmendRequest, OrderBookOrdersRequest,
+    OrderBookOrdersResponse, OrderBookSnapshotRequest, OrderBookSnapshotResponse, OrderCancelAllRequestExt, OrderCancelRequestExt,
+    OrderDetailRequestExt, OrderExpireRequest, OrderGroupPutRequest, OrderGroupPutResponse, OrderMassCancelRequest,
+    OrderMassCancelResponse, OrderPutRequestExt, PriceLevel, UserCancelAllRequest, WithdrawFinishRequest, WithdrawRequest,
+    WithdrawResponse,
+};
+use crate::eth_guard::{EthLogEffect, EthLogGuard, EthLogMetadata};
 use crate::history::DatabaseHistoryWriter;
 use crate::market::{self, Order, OrderInput};
-use crate::message::{FullOrderMessageManager, SimpleMessageManager};
+use crate::message::{FullOrderMessageManager, SimpleMessageManager, WithdrawStatusMessage};
 use crate::models::{self};
 use crate::persist::{CompositePersistor, DBBasedPersistor, DummyPersistor, FileBasedPersistor, MessengerBasedPersistor, PersistExector};
 use crate::sequencer::Sequencer;
-use crate::storage::config::MarketConfigs;
-use crate::types::{ConnectionType, DbType, SimpleResult};
+use crate::storage::{self, config::MarketConfigs};
+use crate::types::{ConnectionType, DbType, MarketPhase, SimpleResult, TradingStatus, WithdrawStatus};
 use crate::user_manager::{self, UserManager};
 
 use anyhow::{anyhow, bail};
@@ -25,7 +36,7 @@ use sqlx::Connection;
 use sqlx::Executor;
 use tonic::{self, Status};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::str::FromStr;
 
@@ -103,6 +114,11 @@ pub struct Controller {
     pub persistor: Box<dyn PersistExector>,
     // TODO: is this needed?
     pub dummy_persistor: Box<dyn PersistExector>,
+    // the time each user has its orders cancelled at unless it calls `cancel_all_after` again.
+    // A timer which lapses while the engine is down fires on the first tick after the restart
+    pub cancel_all_timers: BTreeMap<u32, f64>,
+    // what the last balance audit found, it runs before each slice and on demand
+    pub audit_mismatches: Vec<Mismatch>,
     db_pool: sqlx::Pool<DbType>,
     market_load_cfg: MarketConfigs,
 }
@@ -112,9 +128,24 @@ const OPERATION_REGISTER_USER: &str = "register_user";
 const OPERATION_BALANCE_UPDATE: &str = "balance_update";
 const OPERATION_ORDER_CANCEL: &str = "order_cancel";
 const OPERATION_ORDER_CANCEL_ALL: &str = "order_cancel_all";
+const OPERATION_USER_CANCEL_ALL: &str = "user_cancel_all";
+const OPERATION_ORDER_MASS_CANCEL: &str = "order_mass_cancel";
+const OPERATION_CANCEL_ALL_AFTER: &str = "cancel_all_after";
+const OPERATION_ORDER_AMEND: &str = "order_amend";
+const OPERATION_ORDER_EXPIRE: &str = "order_expire";
 const OPERATION_ORDER_PUT: &str = "order_put";
 const OPERATION_BATCH_ORDER_PUT: &str = "batch_order_put";
+const OPERATION_ORDER_GROUP_PUT: &str = "order_group_put";
 const OPERATION_TRANSFER: &str = "transfer";
+const OPERATION_MARKET_PHASE_UPDATE: &str = "market_phase_update";
+const OPERATION_MARKET_STATUS_UPDATE: &str = "market_status_update";
+const OPERATION_FEE_OVERRIDE_UPDATE: &str = "fee_override_update";
+const OPERATION_COLLATERAL_POOL_UPDATE: &str = "collateral_pool_update";
+const OPERATION_WITHDRAW_REQUEST: &str = "withdraw_request";
+const OPERATION_WITHDRAW_CONFIRM: &str = "withdraw_confirm";
+const OPERATION_WITHDRAW_REJECT: &str = "withdraw_reject";
+const OPERATION_ETH_BLOCK_UPDATE: &str = "eth_block_update";
+const OPERATION_ETH_BLOCK_REVERT: &str = "eth_block_revert";
 
 pub fn create_controller(cfgs: (config::Settings, MarketConfigs)) -> Controller {
     let settings = cfgs.0;
@@ -122,7 +153,7 @@ pub fn create_controller(cfgs: (config::Settings, MarketConfigs)) -> Controller
     let user_manager = UserManager::new(); // load from db later
     let balance_manager = BalanceManager::new(&settings.assets).unwrap();
 
-    let update_controller = BalanceUpdateController::new();
+    let update_controller = BalanceUpdateController::with_retention(settings.balance_update_retention);
     //        let asset_manager = AssetManager::new(&settings.assets).unwrap();
     let sequencer = Sequencer::default();
     let mut markets = HashMap::new();
@@ -154,6 +185,8 @@ pub fn create_controller(cfgs: (config::Settings, MarketConfigs)) -> Controller
         log_handler: Box::<OperationLogSender>::new(log_handler),
         persistor,
         dummy_persistor: DummyPersistor::new_box(),
+        cancel_all_timers: BTreeMap::new(),
+        audit_mismatches: Vec::new(),
         db_pool: main_pool,
         market_load_cfg: cfgs.1,
     }
@@ -292,35 +325,120 @@ impl Controller {
         })
     }
 
-    pub fn order_detail(&self, req: OrderDetailRequest) -> Result<OrderInfo, Status> {
+    // the levels are not grouped, so that the deltas can be applied to them
+    pub fn order_book_snapshot(&self, req: OrderBookSnapshotRequest) -> Result<OrderBookSnapshotResponse, Status> {
         let market = self
             .markets
             .get(&req.market)
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
-        let order = market
-            .get(req.order_id)
-            .ok_or_else(|| Status::invalid_argument("invalid order_id"))?;
+        let depth = market.depth(req.limit as usize, &Decimal::zero());
+        let convert = |price_info: &Vec<market::PriceInfo>| {
+            price_info
+                .iter()
+                .map(|price_info| PriceLevel {
+                    price: price_info.price.to_string(),
+                    amount: price_info.amount.to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
+        Ok(OrderBookSnapshotResponse {
+            market: req.market,
+            sequence: market.book_sequence,
+            asks: convert(&depth.asks),
+            bids: convert(&depth.bids),
+        })
+    }
+
+    // the users of the orders are only given when `with_users` is set, which is for the admin
+    pub fn order_book_orders(&self, req: OrderBookOrdersRequest, with_users: bool) -> Result<OrderBookOrdersResponse, Status> {
+        let market = self
+            .markets
+            .get(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let limit = if req.limit == 0 {
+            ORDER_LIST_MAX_LEN
+        } else {
+            std::cmp::min(req.limit as usize, ORDER_LIST_MAX_LEN)
+        };
+        let after = match &req.after {
+            Some(cursor) => {
+                let price = Decimal::from_str(&cursor.price).map_err(|_| Status::invalid_argument("invalid cursor"))?;
+                Some((price, cursor.priority, cursor.order_id))
+            }
+            None => None,
+        };
+        let orders = market.book_orders(req.side, after, limit);
+        let next = if orders.len() == limit {
+            orders.last().map(|order| BookCursor {
+                price: order.price.to_string(),
+                priority: order.priority,
+                order_id: order.id,
+            })
+        } else {
+            None
+        };
+        let orders = orders
+            .iter()
+            .map(|order| {
+                let order = if with_users { *order } else { order.displayed() };
+                BookOrder {
+                    id: order.id,
+                    user_id: if with_users { Some(order.user) } else { None },
+                    side: order.side,
+                    price: order.price.to_string(),
+                    remain: order.remain.to_string(),
+                    create_time: order.create_time,
+                    priority: order.priority,
+                }
+            })
+            .collect();
+        Ok(OrderBookOrdersResponse {
+            market: req.market,
+            side: req.side,
+            orders,
+            next,
+        })
+    }
+
+    pub fn order_detail(&self, req: OrderDetailRequestExt) -> Result<OrderInfo, Status> {
+        let market = self
+            .markets
+            .get(&req.base.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let order = if req.base.order_id == 0 && req.client_order_id != 0 {
+            market
+                .get_by_client_order_id(req.user_id, req.client_order_id)
+                .ok_or_else(|| Status::invalid_argument("invalid client_order_id"))?
+        } else {
+            market
+                .get(req.base.order_id)
+                .ok_or_else(|| Status::invalid_argument("invalid order_id"))?
+        };
         Ok(OrderInfo::from(order))
     }
 
-    pub fn market_list(&self, _req: MarketListRequest) -> Result<MarketListResponse, Status> {
+    pub fn market_list(&self, _req: MarketListRequest) -> Result<MarketListResponseExt, Status> {
         let markets = self
             .markets
             .values()
-            .map(|market| market_list_response::MarketInfo {
-                name: String::from(market.name),
-                base: market.base.into(),
-                quote: market.quote.into(),
-                fee_precision: market.fee_prec,
-                amount_precision: market.amount_prec,
-                price_precision: market.price_prec,
-                min_amount: market.min_amount.to_string(),
+            .map(|market| MarketInfoExt {
+                base: market_list_response::MarketInfo {
+                    name: String::from(market.name),
+                    base: market.base.into(),
+                    quote: market.quote.into(),
+                    fee_precision: market.fee_prec,
+                    amount_precision: market.amount_prec,
+                    price_precision: market.price_prec,
+                    min_amount: market.min_amount.to_string(),
+                },
+                trading_status: market.trading_status,
+                phase: market.phase,
             })
             .collect();
-        Ok(MarketListResponse { markets })
+        Ok(MarketListResponseExt { markets })
     }
 
-    pub fn market_summary(&self, req: MarketSummaryRequest) -> Result<MarketSummaryResponse, Status> {
+    pub fn market_summary(&self, req: MarketSummaryRequest) -> Result<MarketSummaryResponseExt, Status> {
         let markets: Vec<String> = if req.markets.is_empty() {
             self.markets.keys().cloned().collect()
         } else {
@@ -335,17 +453,21 @@ impl Controller {
             .iter()
             .map(|market| {
                 let status = self.markets.get(market).unwrap().status();
-                market_summary_response::MarketSummary {
-                    name: status.name,
-                    ask_count: status.ask_count as i32,
-                    ask_amount: status.ask_amount.to_string(),
-                    bid_count: status.bid_count as i32,
-                    bid_amount: status.bid_amount.to_string(),
-                    trade_count: status.trade_count,
+                MarketSummaryExt {
+                    base: market_summary_response::MarketSummary {
+                        name: status.name,
+                        ask_count: status.ask_count as i32,
+                        ask_amount: status.ask_amount.to_string(),
+                        bid_count: status.bid_count as i32,
+                        bid_amount: status.bid_amount.to_string(),
+                        trade_count: status.trade_count,
+                    },
+                    trading_status: status.trading_status,
+                    phase: status.phase,
                 }
             })
             .collect();
-        Ok(MarketSummaryResponse { market_summaries })
+        Ok(MarketSummaryResponseExt { market_summaries })
     }
 
     fn check_service_available(&self) -> bool {
@@ -399,7 +521,8 @@ impl Controller {
             self.append_operation_log(OPERATION_REGISTER_USER, &req);
         }
 
-        self.eth_guard.update_optional(meta);
+        self.eth_guard
+            .update_optional(meta, EthLogEffect::RegisterUser { user_id: req.user_id });
 
         Ok(UserInfo {
             user_id: req.user_id,
@@ -469,15 +592,29 @@ impl Controller {
             self.append_operation_log(OPERATION_BALANCE_UPDATE, &req);
         }
 
-        self.eth_guard.update_optional(meta);
+        self.eth_guard.update_optional(
+            meta,
+            EthLogEffect::BalanceUpdate {
+                user_id: req.user_id,
+                asset: asset.to_owned(),
+                business: req.business.clone(),
+                business_id: req.business_id,
+                change,
+            },
+        );
 
         Ok(BalanceUpdateResponse::default())
     }
 
-    pub fn order_put(&mut self, real: bool, req: OrderPutRequest) -> Result<OrderInfo, Status> {
+    pub fn order_put(&mut self, real: bool, mut req: OrderPutRequestExt) -> Result<OrderInfo, Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        req.time = Self::operation_time(real, req.time);
+        // nothing changes on a resubmission, so it is not logged
+        if let Some(order) = self.resubmitted_order(&req) {
+            return Ok(OrderInfo::from(order));
+        }
         let order = self.put_order(real, &req)?;
         if real {
             self.append_operation_log(OPERATION_ORDER_PUT, &req);
@@ -485,23 +622,37 @@ impl Controller {
         Ok(OrderInfo::from(order))
     }
 
-    pub fn batch_order_put(&mut self, real: bool, req: BatchOrderPutRequest) -> Result<BatchOrderPutResponse, Status> {
+    pub fn batch_order_put(&mut self, real: bool, mut req: BatchOrderPutRequestExt) -> Result<BatchOrderPutResponse, Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
-        let market_name = &req.market;
+        req.time = Self::operation_time(real, req.time);
+        let market_name = &req.base.market;
         if !self.markets.contains_key(market_name) {
             return Err(Status::invalid_argument("invalid market"));
         }
-        let orders = &req.orders;
-        if req.reset {
+        let orders = &req.base.orders;
+        // reject the whole batch before anything is cancelled
+        let market = self.markets.get(market_name).unwrap();
+        for order_req in orders {
+            market
+                .check_order_accepted(order_req.post_only)
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        }
+        if req.base.reset {
             for order_req in orders {
                 if market_name != &order_req.market {
                     return Err(Status::invalid_argument("inconsistent order markets"));
                 }
                 let market = self.markets.get_mut(market_name).unwrap();
                 let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-                market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, order_req.user_id);
+                market.cancel_all_for_user(
+                    &mut self.sequencer,
+                    (&mut self.balance_manager).into(),
+                    persistor,
+                    order_req.user_id,
+                    req.time,
+                );
             }
         }
         let mut result_code = ResultCode::Success;
@@ -512,7 +663,11 @@ impl Controller {
                 return Err(Status::invalid_argument("inconsistent order markets"));
             }
 
-            match self.put_order(real, order_req) {
+            let order_req = OrderPutRequestExt {
+                time: req.time,
+                ..OrderPutRequestExt::from(order_req.clone())
+            };
+            match self.put_order(real, &order_req) {
                 Ok(order) => order_ids.push(order.id),
                 Err(error) => {
                     result_code = ResultCode::InternalError;
@@ -531,10 +686,111 @@ impl Controller {
         })
     }
 
-    pub fn order_cancel(&mut self, real: bool, req: OrderCancelRequest) -> Result<OrderInfo, tonic::Status> {
+    pub fn order_group_put(&mut self, real: bool, mut req: OrderGroupPutRequest) -> Result<OrderGroupPutResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let market_name = match req.orders.first() {
+            Some(order_req) => order_req.base.market.clone(),
+            None => return Err(Status::invalid_argument("empty order group")),
+        };
+        if req.orders.iter().any(|order_req| order_req.base.market != market_name) {
+            return Err(Status::invalid_argument("inconsistent order markets"));
+        }
+        let time = Self::operation_time(real, req.orders[0].time);
+        for order_req in &mut req.orders {
+            order_req.time = time;
+        }
+        self.check_audit(real)?;
+        for order_req in &req.orders {
+            Self::check_expire_time(real, order_req)?;
+        }
+        let market = self
+            .markets
+            .get(&market_name)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        for order_req in &req.orders {
+            market
+                .check_order_accepted(order_req.base.post_only)
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        }
+        // the orders belong to one user, which is checked by the market
+        let user_id = req.orders[0].base.user_id;
+        let total_order_num: usize = self.markets.values().map(|market| market.get_order_num_of_user(user_id)).sum();
+        if total_order_num + req.orders.len() > self.settings.user_order_num_limit {
+            return Err(Status::unavailable("too many active orders for user"));
+        }
+        let order_inputs = req
+            .orders
+            .iter()
+            .cloned()
+            .map(OrderInput::try_from)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
+        let market = self.markets.get_mut(&market_name).unwrap();
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let orders = market
+            .put_order_group(
+                &mut self.sequencer,
+                (&mut self.balance_manager).into(),
+                &mut self.update_controller,
+                persistor,
+                order_inputs,
+                req.oco_trigger,
+            )
+            .map_err(|e| Status::unknown(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_ORDER_GROUP_PUT, &req);
+        }
+        Ok(OrderGroupPutResponse {
+            group_id: orders[0].group_id,
+            order_ids: orders.iter().map(|order| order.id).collect(),
+        })
+    }
+
+    pub fn order_cancel(&mut self, real: bool, mut req: OrderCancelRequestExt) -> Result<OrderInfo, tonic::Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        req.time = Self::operation_time(real, req.time);
+        let market = self
+            .markets
+            .get_mut(&req.base.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let order = if req.base.order_id == 0 && req.client_order_id != 0 {
+            let order = market
+                .get_by_client_order_id(req.base.user_id, req.client_order_id)
+                .ok_or_else(|| Status::invalid_argument("invalid client_order_id"))?;
+            if market.get(order.id).is_none() {
+                return Err(Status::failed_precondition(format!("order {} is finished", order.id)));
+            }
+            order
+        } else {
+            market
+                .get(req.base.order_id)
+                .ok_or_else(|| Status::invalid_argument("invalid order_id"))?
+        };
+        if order.user != req.base.user_id {
+            return Err(Status::invalid_argument("invalid user"));
+        }
+        market
+            .check_cancel_accepted()
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let balance_manager = &mut self.balance_manager;
+        //let persistor = self.get_persistor(real);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        market.cancel(&mut self.sequencer, balance_manager.into(), persistor, order.id, req.time);
+        if real {
+            self.append_operation_log(OPERATION_ORDER_CANCEL, &req);
+        }
+        Ok(OrderInfo::from(order))
+    }
+
+    pub fn order_amend(&mut self, real: bool, mut req: OrderAmendRequest) -> Result<OrderInfo, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        req.time = Self::operation_time(real, req.time);
         let market = self
             .markets
             .get_mut(&req.market)
@@ -545,33 +801,656 @@ impl Controller {
         if order.user != req.user_id {
             return Err(Status::invalid_argument("invalid user"));
         }
-        let balance_manager = &mut self.balance_manager;
-        //let persistor = self.get_persistor(real);
+        market
+            .check_order_accepted(true)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let amount = str_to_decimal(&req.amount, true).map_err(|_| Status::invalid_argument("invalid amount"))?;
+        let price = str_to_decimal(&req.price, true).map_err(|_| Status::invalid_argument("invalid price"))?;
+        if amount.is_zero() && price.is_zero() {
+            return Err(Status::invalid_argument("nothing to amend"));
+        }
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-        market.cancel(balance_manager.into(), persistor, order.id);
+        let order = market
+            .amend(
+                &mut self.sequencer,
+                (&mut self.balance_manager).into(),
+                persistor,
+                order.id,
+                Some(amount).filter(|a| !a.is_zero()),
+                Some(price).filter(|p| !p.is_zero()),
+                req.time,
+            )
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
         if real {
-            self.append_operation_log(OPERATION_ORDER_CANCEL, &req);
+            self.append_operation_log(OPERATION_ORDER_AMEND, &req);
         }
         Ok(OrderInfo::from(order))
     }
 
-    pub fn order_cancel_all(&mut self, real: bool, req: OrderCancelAllRequest) -> Result<OrderCancelAllResponse, tonic::Status> {
+    pub fn order_cancel_all(&mut self, real: bool, mut req: OrderCancelAllRequestExt) -> Result<OrderCancelAllResponse, tonic::Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        req.time = Self::operation_time(real, req.time);
         let market = self
             .markets
-            .get_mut(&req.market)
+            .get_mut(&req.base.market)
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        market
+            .check_cancel_accepted()
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
         //let persistor = self.get_persistor(real);
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-        let total = market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, req.user_id) as u32;
+        let total = market.cancel_all_for_user(
+            &mut self.sequencer,
+            (&mut self.balance_manager).into(),
+            persistor,
+            req.base.user_id,
+            req.time,
+        ) as u32;
         if real {
             self.append_operation_log(OPERATION_ORDER_CANCEL_ALL, &req);
         }
         Ok(OrderCancelAllResponse { total })
     }
 
+    pub fn order_mass_cancel(&mut self, real: bool, mut req: OrderMassCancelRequest) -> Result<OrderMassCancelResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        req.time = Self::operation_time(real, req.time);
+        let market_names: Vec<String> = if req.all_markets {
+            if !req.market.is_empty() {
+                return Err(Status::invalid_argument("market must be empty for all markets"));
+            }
+            // halted markets are skipped, they do not accept cancellations
+            self.markets
+                .values()
+                .filter(|market| market.check_cancel_accepted().is_ok())
+                .map(|market| market.name.to_string())
+                .collect()
+        } else {
+            let market = self
+                .markets
+                .get(&req.market)
+                .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+            market
+                .check_cancel_accepted()
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+            vec![req.market.clone()]
+        };
+        let min_price = str_to_decimal(&req.min_price, true).map_err(|_| Status::invalid_argument("invalid min price"))?;
+        let max_price = str_to_decimal(&req.max_price, true).map_err(|_| Status::invalid_argument("invalid max price"))?;
+        if min_price.is_sign_negative() || max_price.is_sign_negative() {
+            return Err(Status::invalid_argument("invalid price"));
+        }
+        let filter = market::MassCancelFilter {
+            side: req.side,
+            min_price: Some(min_price).filter(|price| !price.is_zero()),
+            max_price: Some(max_price).filter(|price| !price.is_zero()),
+            before: Some(req.before).filter(|before| *before > 0.0),
+        };
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let mut order_ids = Vec::new();
+        for market_name in market_names {
+            let market = self.markets.get_mut(&market_name).unwrap();
+            order_ids.extend(market.mass_cancel(
+                &mut self.sequencer,
+                (&mut self.balance_manager).into(),
+                persistor,
+                Some(req.user_id),
+                &filter,
+                req.time,
+            ));
+        }
+        if real {
+            self.append_operation_log(OPERATION_ORDER_MASS_CANCEL, &req);
+        }
+        Ok(OrderMassCancelResponse { order_ids })
+    }
+
+    pub fn cancel_all_after(&mut self, real: bool, mut req: CancelAllAfterRequest) -> Result<CancelAllAfterResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        if !self.user_manager.users.contains_key(&req.user_id) {
+            return Err(Status::invalid_argument("invalid user_id"));
+        }
+        req.time = Self::operation_time(real, req.time);
+        let trigger_time = if req.timeout == 0 {
+            self.cancel_all_timers.remove(&req.user_id);
+            0.0
+        } else {
+            let trigger_time = req.time + req.timeout as f64;
+            self.cancel_all_timers.insert(req.user_id, trigger_time);
+            trigger_time
+        };
+        if real {
+            self.append_operation_log(OPERATION_CANCEL_ALL_AFTER, &req);
+        }
+        Ok(CancelAllAfterResponse { trigger_time })
+    }
+
+    // Cancel the orders of the user in every market. Halted markets are included,
+    // the orders must not be live when the markets resume.
+    pub fn user_cancel_all(&mut self, real: bool, mut req: UserCancelAllRequest) -> Result<OrderCancelAllResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        req.time = Self::operation_time(real, req.time);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let mut total = 0;
+        for market in self.markets.values_mut() {
+            total += market.cancel_all_for_user(
+                &mut self.sequencer,
+                (&mut self.balance_manager).into(),
+                persistor,
+                req.user_id,
+                req.time,
+            ) as u32;
+        }
+        // disarmed here rather than by the tick, so that it is disarmed on replay as well
+        self.cancel_all_timers.remove(&req.user_id);
+        if real {
+            self.append_operation_log(OPERATION_USER_CANCEL_ALL, &req);
+        }
+        Ok(OrderCancelAllResponse { total })
+    }
+
+    pub fn order_expire(&mut self, real: bool, req: OrderExpireRequest) -> Result<usize, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let orders = market.expire_orders(&mut self.sequencer, (&mut self.balance_manager).into(), persistor, req.time);
+        if real {
+            log::info!("{} orders expired in market {}", orders.len(), req.market);
+            self.append_operation_log(OPERATION_ORDER_EXPIRE, &req);
+        }
+        Ok(orders.len())
+    }
+
+    pub fn market_phase_update(&mut self, real: bool, mut req: MarketPhaseUpdateRequest) -> Result<MarketPhaseUpdateResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        req.time = Self::operation_time(real, req.time);
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let auction_price = match req.phase {
+            MarketPhase::AUCTION => {
+                market.start_auction().map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+                None
+            }
+            MarketPhase::CONTINUOUS => {
+                // the uncross trades
+                market
+                    .check_order_accepted(false)
+                    .map_err(|e| Status::failed_precondition(e.to_string()))?;
+                let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+                market
+                    .end_auction(
+                        &mut self.sequencer,
+                        (&mut self.balance_manager).into(),
+                        &mut self.update_controller,
+                        persistor,
+                        req.time,
+                    )
+                    .map_err(|e| Status::invalid_argument(format!("{}", e)))?
+            }
+        };
+        self.fit_pooled_orders(real, req.time);
+        if real {
+            self.append_operation_log(OPERATION_MARKET_PHASE_UPDATE, &req);
+        }
+        Ok(MarketPhaseUpdateResponse {
+            market: req.market,
+            phase: req.phase,
+            auction_price: auction_price.map(|price| price.to_string()).unwrap_or_default(),
+        })
+    }
+
+    pub fn market_status_update(&mut self, real: bool, req: MarketStatusUpdateRequest) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        log::info!("market {} status {:?} -> {:?}", req.market, market.trading_status, req.status);
+        market.set_trading_status(req.status);
+        if real {
+            self.append_operation_log(OPERATION_MARKET_STATUS_UPDATE, &req);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    pub fn fee_override_update(&mut self, real: bool, req: FeeOverrideUpdateRequest) -> Result<SimpleSuccessResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        match req.rate {
+            Some(rate) => {
+                if !rate.is_valid() || market.fee_prec == 0 && (!rate.taker_fee.is_zero() || !rate.maker_fee.is_zero()) {
+                    return Err(Status::invalid_argument("invalid fee rate"));
+                }
+                market.fee_schedule.overrides.insert(req.user_id, rate);
+            }
+            None => {
+                market.fee_schedule.overrides.remove(&req.user_id);
+            }
+        }
+        if real {
+            self.append_operation_log(OPERATION_FEE_OVERRIDE_UPDATE, &req);
+        }
+        Ok(SimpleSuccessResponse {})
+    }
+
+    pub fn collateral_pool_update(
+        &mut self,
+        real: bool,
+        mut req: CollateralPoolUpdateRequest,
+    ) -> Result<CollateralPoolUpdateResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        req.time = Self::operation_time(real, req.time);
+        let asset = &req.asset;
+        if !self.balance_manager.asset_manager.asset_exist(asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let prec = self.balance_manager.asset_manager.asset_prec(asset);
+        let delta = Decimal::from_str(&req.delta).map_err(|_| Status::invalid_argument("invalid amount"))?;
+        if delta.is_zero() || delta.round_dp(prec) != delta {
+            return Err(Status::invalid_argument("invalid amount"));
+        }
+        if delta.is_sign_positive() {
+            if self.balance_manager.get(req.user_id, BalanceType::AVAILABLE, asset) < delta {
+                return Err(Status::failed_precondition("balance not enough"));
+            }
+            self.balance_manager.pool_add(req.user_id, asset, &delta);
+        } else {
+            let amount = -delta;
+            if self.balance_manager.pool_get(req.user_id, asset) < amount {
+                return Err(Status::failed_precondition("collateral pool not enough"));
+            }
+            self.balance_manager.pool_sub(req.user_id, asset, &amount);
+            // what is left may not be enough for the orders on the pool
+            self.fit_pooled_orders(real, req.time);
+        }
+        if real {
+            self.append_operation_log(OPERATION_COLLATERAL_POOL_UPDATE, &req);
+        }
+        Ok(CollateralPoolUpdateResponse {
+            amount: self.balance_manager.pool_get(req.user_id, asset).to_string(),
+        })
+    }
+
+    pub fn balance_audit(&mut self, _req: BalanceAuditRequest) -> Result<BalanceAuditResponse, Status> {
+        let mismatches = audit::audit(&self.balance_manager, self.markets.values());
+        for mismatch in &mismatches {
+            log::error!("balance audit mismatch {:?}", mismatch);
+        }
+        log::info!("balance audit done, {} mismatches", mismatches.len());
+        self.audit_mismatches = mismatches.clone();
+        Ok(BalanceAuditResponse { mismatches })
+    }
+
+    // The first step of a two-phase withdrawal, the amount is moved into the pending balance.
+    // Only the steps which change anything are logged, so retries are not replayed.
+    pub fn withdraw_request(&mut self, real: bool, req: WithdrawRequest) -> Result<WithdrawResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let asset = &req.asset;
+        if !self.balance_manager.asset_manager.asset_exist(asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let prec = self.balance_manager.asset_manager.asset_prec_show(asset);
+        let amount = Decimal::from_str(&req.amount).map_err(|_| Status::invalid_argument("invalid amount"))?;
+        if amount <= Decimal::zero() || amount.round_dp(prec) != amount {
+            return Err(Status::invalid_argument("invalid amount"));
+        }
+        let detail: serde_json::Value = if req.detail.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(req.detail.as_str()).map_err(|_| Status::invalid_argument("invalid detail"))?
+        };
+        if let Some(withdrawal) = self.balance_manager.withdrawals.get(&req.business_id) {
+            if withdrawal.user_id != req.user_id || withdrawal.asset != *asset || withdrawal.amount != amount {
+                return Err(Status::already_exists("duplicate business_id"));
+            }
+            return Ok(withdraw_response(req.business_id, withdrawal));
+        }
+        if self.balance_manager.get(req.user_id, BalanceType::AVAILABLE, asset) < amount {
+            return Err(Status::failed_precondition("balance not enough"));
+        }
+
+        self.balance_manager.withdraw_hold(req.user_id, asset, &amount);
+        let withdrawal = Withdrawal {
+            user_id: req.user_id,
+            asset: asset.to_owned(),
+            business: req.business.clone(),
+            amount,
+            status: WithdrawStatus::REQUESTED,
+        };
+        if real {
+            let balance_history = self.withdraw_balance_history(req.business_id, &withdrawal, -amount, detail.clone());
+            self.persistor.put_balance(&balance_history);
+        }
+        self.put_withdraw_status(real, req.business_id, &withdrawal, detail);
+        let response = withdraw_response(req.business_id, &withdrawal);
+        self.balance_manager.withdrawals.insert(req.business_id, withdrawal);
+        if real {
+            self.append_operation_log(OPERATION_WITHDRAW_REQUEST, &req);
+        }
+        Ok(response)
+    }
+
+    // the withdrawal went through, the pending amount is taken
+    pub fn withdraw_confirm(&mut self, real: bool, req: WithdrawFinishRequest) -> Result<WithdrawResponse, Status> {
+        self.finish_withdrawal(real, req, WithdrawStatus::CONFIRMED)
+    }
+
+    // the withdrawal failed, the pending amount is given back
+    pub fn withdraw_reject(&mut self, real: bool, req: WithdrawFinishRequest) -> Result<WithdrawResponse, Status> {
+        self.finish_withdrawal(real, req, WithdrawStatus::REJECTED)
+    }
+
+    fn finish_withdrawal(&mut self, real: bool, req: WithdrawFinishRequest, status: WithdrawStatus) -> Result<WithdrawResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let mut withdrawal = match self.balance_manager.withdrawals.get(&req.business_id) {
+            Some(withdrawal) => withdrawal.clone(),
+            None => return Err(Status::not_found("withdrawal not found")),
+        };
+        if withdrawal.status == status {
+            return Ok(withdraw_response(req.business_id, &withdrawal));
+        }
+        if withdrawal.status != WithdrawStatus::REQUESTED {
+            return Err(Status::failed_precondition("withdrawal already finished"));
+        }
+        let detail: serde_json::Value = if req.detail.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(req.detail.as_str()).map_err(|_| Status::invalid_argument("invalid detail"))?
+        };
+
+        let user_id = withdrawal.user_id;
+        let asset = withdrawal.asset.clone();
+        let amount = withdrawal.amount;
+        if status == WithdrawStatus::CONFIRMED {
+            self.balance_manager.sub(user_id, BalanceType::WITHDRAW_PENDING, &asset, &amount);
+            self.balance_manager.flow_withdraw(&asset, &amount);
+        } else {
+            self.balance_manager.withdraw_release(user_id, &asset, &amount);
+        }
+        withdrawal.status = status;
+        self.put_withdraw_status(real, req.business_id, &withdrawal, detail.clone());
+        if real && status == WithdrawStatus::CONFIRMED {
+            // published the same as a withdrawal by `update_balance`, the available balance was
+            // taken by the request already so there is no balance history for it
+            let balance_history = self.withdraw_balance_history(req.business_id, &withdrawal, -amount, detail.clone());
+            self.persistor.put_withdraw(&balance_history);
+        } else if real {
+            let balance_history = self.withdraw_balance_history(req.business_id, &withdrawal, amount, detail.clone());
+            self.persistor.put_balance(&balance_history);
+        }
+        let response = withdraw_response(req.business_id, &withdrawal);
+        self.balance_manager.withdrawals.insert(req.business_id, withdrawal);
+        if real {
+            let method = if status == WithdrawStatus::CONFIRMED {
+                OPERATION_WITHDRAW_CONFIRM
+            } else {
+                OPERATION_WITHDRAW_REJECT
+            };
+            self.append_operation_log(method, &req);
+        }
+        Ok(response)
+    }
+
+    // the L1 watcher reports the hash of each block it has read the logs of
+    pub fn eth_block_update(&mut self, real: bool, req: EthBlockRequest) -> Result<EthBlockResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        self.eth_guard
+            .set_block_hash(req.block_number, &req.block_hash)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        if real {
+            self.append_operation_log(OPERATION_ETH_BLOCK_UPDATE, &req);
+        }
+        Ok(EthBlockResponse {
+            block_number: req.block_number,
+            reverted_logs: 0,
+        })
+    }
+
+    // A reorg has orphaned the block, the registrations and balance updates read from it and from
+    // the blocks after it are undone, latest first.
+    pub fn eth_block_revert(&mut self, real: bool, req: EthBlockRequest) -> Result<EthBlockResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let logs = self
+            .eth_guard
+            .revert(req.block_number, &req.block_hash)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        for log in &logs {
+            log::warn!("revert {:?} of orphaned block {}", log.effect, log.meta.block_number);
+            match &log.effect {
+                EthLogEffect::RegisterUser { user_id } => self.revert_registration(*user_id),
+                EthLogEffect::BalanceUpdate {
+                    user_id,
+                    asset,
+                    business,
+                    business_id,
+                    change,
+                } => {
+                    self.update_controller.forget(&BalanceUpdateKey {
+                        balance_type: BalanceType::AVAILABLE,
+                        business_type: if change.is_sign_positive() {
+                            BusinessType::Deposit
+                        } else {
+                            BusinessType::Withdraw
+                        },
+                        user_id: *user_id,
+                        asset: asset.clone(),
+                        business: business.clone(),
+                        business_id: *business_id,
+                    });
+                    self.revert_balance_update(real, *user_id, asset, business, *business_id, *change);
+                }
+            }
+        }
+        if real {
+            self.append_operation_log(OPERATION_ETH_BLOCK_REVERT, &req);
+        }
+        Ok(EthBlockResponse {
+            block_number: req.block_number,
+            reverted_logs: logs.len() as u32,
+        })
+    }
+
+    // user ids are given in sequence, so only the latest user can be taken back
+    fn revert_registration(&mut self, user_id: u32) {
+        let holds_nothing = !self
+            .balance_manager
+            .balances
+            .iter()
+            .any(|(key, amount)| key.user_id == user_id && !amount.is_zero());
+        if user_id as usize == self.user_manager.users.len() && holds_nothing {
+            self.user_manager.users.remove(&user_id);
+        } else {
+            log::error!("the registration of user {} can not be reverted", user_id);
+        }
+    }
+
+    // A deposit is taken back from the available balance. If part of it has been spent already,
+    // what is available is moved into the reorg hold instead and the rest is left for settling by hand.
+    fn revert_balance_update(&mut self, real: bool, user_id: u32, asset: &str, business: &str, business_id: u64, change: Decimal) {
+        let available = self.balance_manager.get(user_id, BalanceType::AVAILABLE, asset);
+        let mut detail = json!({ "business": business, "change": change.to_string() });
+        let reverted = if change.is_sign_negative() {
+            // a withdrawal, the amount is given back
+            self.balance_manager.add(user_id, BalanceType::AVAILABLE, asset, &-change);
+            self.balance_manager.flow_withdraw(asset, &change);
+            -change
+        } else if available >= change {
+            self.balance_manager.sub(user_id, BalanceType::AVAILABLE, asset, &change);
+            self.balance_manager.flow_deposit(asset, &-change);
+            -change
+        } else {
+            log::error!(
+                "deposit {} of user {} reverted after being spent, {} {} held",
+                business_id,
+                user_id,
+                available,
+                asset
+            );
+            self.balance_manager.sub(user_id, BalanceType::AVAILABLE, asset, &available);
+            self.balance_manager.add(user_id, BalanceType::REORG_HOLD, asset, &available);
+            detail["held"] = serde_json::Value::from(available.to_string());
+            detail["shortfall"] = serde_json::Value::from((change - available).to_string());
+            -available
+        };
+        if real {
+            detail["id"] = serde_json::Value::from(business_id);
+            let balance_available = self.balance_manager.get(user_id, BalanceType::AVAILABLE, asset);
+            let balance_frozen = self.balance_manager.get(user_id, BalanceType::FREEZE, asset);
+            let balance_history = models::BalanceHistory {
+                time: FTimestamp(current_timestamp()).into(),
+                user_id: user_id as i32,
+                business_id: business_id as i64,
+                asset: asset.to_owned(),
+                business: "reorg".to_owned(),
+                market_price: Decimal::zero(),
+                change: reverted,
+                balance: balance_available + balance_frozen,
+                balance_available,
+                balance_frozen,
+                detail: detail.to_string(),
+                signature: vec![],
+            };
+            self.persistor.put_balance(&balance_history);
+        }
+    }
+
+    // the change of the available balance by a step of a withdrawal, in the form `update_balance` persists it
+    fn withdraw_balance_history(
+        &self,
+        business_id: u64,
+        withdrawal: &Withdrawal,
+        change: Decimal,
+        mut detail: serde_json::Value,
+    ) -> models::BalanceHistory {
+        let market_price = self
+            .asset_market_names
+            .get(&(withdrawal.asset.clone(), "USDT".to_owned()))
+            .map_or(Decimal::zero(), |market_name| self.markets.get(market_name).unwrap().price);
+        detail["id"] = serde_json::Value::from(business_id);
+        let balance_available = self
+            .balance_manager
+            .get(withdrawal.user_id, BalanceType::AVAILABLE, &withdrawal.asset);
+        let balance_frozen = self.balance_manager.get(withdrawal.user_id, BalanceType::FREEZE, &withdrawal.asset);
+        models::BalanceHistory {
+            time: FTimestamp(current_timestamp()).into(),
+            user_id: withdrawal.user_id as i32,
+            business_id: business_id as i64,
+            asset: withdrawal.asset.clone(),
+            business: withdrawal.business.clone(),
+            market_price,
+            change,
+            balance: balance_available + balance_frozen,
+            balance_available,
+            balance_frozen,
+            detail: detail.to_string(),
+            signature: vec![],
+        }
+    }
+
+    fn put_withdraw_status(&mut self, real: bool, business_id: u64, withdrawal: &Withdrawal, detail: serde_json::Value) {
+        let status = WithdrawStatusMessage {
+            timestamp: current_timestamp(),
+            user_id: withdrawal.user_id,
+            business_id,
+            asset: withdrawal.asset.clone(),
+            business: withdrawal.business.clone(),
+            amount: withdrawal.amount.to_string(),
+            status: withdrawal.status,
+            balance_available: self
+                .balance_manager
+                .get(withdrawal.user_id, BalanceType::AVAILABLE, &withdrawal.asset)
+                .to_string(),
+            balance_withdraw_pending: self
+                .balance_manager
+                .get(withdrawal.user_id, BalanceType::WITHDRAW_PENDING, &withdrawal.asset)
+                .to_string(),
+            detail: detail.to_string(),
+        };
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        persistor.put_withdraw_status(&status);
+    }
+
+    // called periodically by the server, everything it changes goes through logged operations
+    pub fn on_timer(&mut self) {
+        let now = current_timestamp();
+        let expiring_markets: Vec<String> = self
+            .markets
+            .values()
+            .filter(|market| market.has_expired_orders(now))
+            .map(|market| market.name.to_string())
+            .collect();
+        for market in expiring_markets {
+            if let Err(e) = self.order_expire(true, OrderExpireRequest { market, time: now }) {
+                log::error!("expire orders failed: {}", e);
+            }
+        }
+        let lapsed_users: Vec<u32> = self
+            .cancel_all_timers
+            .iter()
+            .filter(|(_, trigger_time)| **trigger_time <= now)
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        for user_id in lapsed_users {
+            match self.user_cancel_all(true, UserCancelAllRequest { user_id, time: now }) {
+                Ok(resp) => log::info!("cancel all after timer of user {} lapsed, {} orders cancelled", user_id, resp.total),
+                // tried again on the next tick
+                Err(e) => log::error!("cancel all orders of user {} failed: {}", user_id, e),
+            }
+        }
+        let resumed_markets: Vec<String> = self
+            .markets
+            .values()
+            .filter(|market| market.is_halt_expired(now))
+            .map(|market| market.name.to_string())
+            .collect();
+        for market in resumed_markets {
+            log::info!("market {} resumes after the halt", market);
+            if let Err(e) = self.market_status_update(
+                true,
+                MarketStatusUpdateRequest {
+                    market,
+                    status: TradingStatus::OPEN,
+                },
+            ) {
+                log::error!("resume market failed: {}", e);
+            }
+        }
+    }
+
     pub async fn debug_dump(&self, _req: DebugDumpRequest) -> Result<DebugDumpResponse, Status> {
         async {
             let mut connection = ConnectionType::connect(&self.settings.db_log).await?;
@@ -588,6 +1467,8 @@ impl Controller {
             market.reset();
         }
         //self.log_handler.reset();
+        self.cancel_all_timers.clear();
+        self.audit_mismatches.clear();
         self.update_controller.reset();
         self.balance_manager.reset();
         self.user_manager.reset();
@@ -630,6 +1511,17 @@ impl Controller {
             }
         }
 
+        // the trading status is requested through the market table, but applied as an operation
+        let market_status = storage::config::load_market_status_from_db(&self.db_pool)
+            .await
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        for (market, status) in market_status {
+            if self.markets.get(&market).map_or(true, |m| m.trading_status == status) {
+                continue;
+            }
+            self.market_status_update(true, MarketStatusUpdateRequest { market, status })?;
+        }
+
         Ok(())
     }
 
@@ -831,15 +1723,60 @@ impl Controller {
             OPERATION_ORDER_CANCEL_ALL => {
                 self.order_cancel_all(false, serde_json::from_str(params)?)?;
             }
+            OPERATION_ORDER_MASS_CANCEL => {
+                self.order_mass_cancel(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_USER_CANCEL_ALL => {
+                self.user_cancel_all(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_CANCEL_ALL_AFTER => {
+                self.cancel_all_after(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_ORDER_AMEND => {
+                self.order_amend(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_ORDER_EXPIRE => {
+                self.order_expire(false, serde_json::from_str(params)?)?;
+            }
             OPERATION_ORDER_PUT => {
                 self.order_put(false, serde_json::from_str(params)?)?;
             }
             OPERATION_BATCH_ORDER_PUT => {
                 self.batch_order_put(false, serde_json::from_str(params)?)?;
             }
+            OPERATION_ORDER_GROUP_PUT => {
+                self.order_group_put(false, serde_json::from_str(params)?)?;
+            }
             OPERATION_TRANSFER => {
                 self.transfer(false, serde_json::from_str(params)?)?;
             }
+            OPERATION_MARKET_PHASE_UPDATE => {
+                self.market_phase_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_MARKET_STATUS_UPDATE => {
+                self.market_status_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_FEE_OVERRIDE_UPDATE => {
+                self.fee_override_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_COLLATERAL_POOL_UPDATE => {
+                self.collateral_pool_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_WITHDRAW_REQUEST => {
+                self.withdraw_request(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_WITHDRAW_CONFIRM => {
+                self.withdraw_confirm(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_WITHDRAW_REJECT => {
+                self.withdraw_reject(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_ETH_BLOCK_UPDATE => {
+                self.eth_block_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_ETH_BLOCK_REVERT => {
+                self.eth_block_revert(false, serde_json::from_str(params)?)?;
+            }
             OPERATION_REGISTER_USER => {
                 self.register_user(false, serde_json::from_str(params)?)?;
             }
@@ -847,25 +1784,34 @@ impl Controller {
         }
         Ok(())
     }
-    fn put_order(&mut self, real: bool, req: &OrderPutRequest) -> Result<Order, Status> {
-        if !self.markets.contains_key(&req.market) {
+    fn put_order(&mut self, real: bool, req: &OrderPutRequestExt) -> Result<Order, Status> {
+        if !self.markets.contains_key(&req.base.market) {
             return Err(Status::invalid_argument("invalid market"));
         }
+        self.check_audit(real)?;
+        if let Some(order) = self.resubmitted_order(req) {
+            return Ok(order);
+        }
+        Self::check_expire_time(real, req)?;
+        let market = self.markets.get(&req.base.market).unwrap();
+        market
+            .check_order_accepted(req.base.post_only)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
         let total_order_num: usize = self
             .markets
             .iter()
-            .map(|(_, market)| market.get_order_num_of_user(req.user_id))
+            .map(|(_, market)| market.get_order_num_of_user(req.base.user_id))
             .sum();
         debug_assert!(total_order_num <= self.settings.user_order_num_limit);
         if total_order_num == self.settings.user_order_num_limit {
             return Err(Status::unavailable("too many active orders for user"));
         }
-        let market = self.markets.get_mut(&req.market).unwrap();
+        let market = self.markets.get_mut(&req.base.market).unwrap();
         let balance_manager = &mut self.balance_manager;
         let update_controller = &mut self.update_controller;
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
         let order_input = OrderInput::try_from(req.clone()).map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
-        market
+        let order = market
             .put_order(
                 &mut self.sequencer,
                 balance_manager.into(),
@@ -873,7 +1819,47 @@ impl Controller {
                 persistor,
                 order_input,
             )
-            .map_err(|e| Status::unknown(format!("{}", e)))
+            .map_err(|e| Status::unknown(format!("{}", e)))?;
+        self.fit_pooled_orders(real, req.time);
+        Ok(order)
+    }
+    // the order placed before with the same client order id, which may have finished already
+    fn resubmitted_order(&self, req: &OrderPutRequestExt) -> Option<Order> {
+        self.markets
+            .get(&req.base.market)
+            .and_then(|market| market.get_by_client_order_id(req.base.user_id, req.client_order_id))
+    }
+    // The time of an operation which changes the books. It is logged with the operation so that the
+    // fee tiers, the traded volumes, the halts and the times of the orders come out the same when it
+    // is replayed. Logs written before the time was logged have 0.
+    fn operation_time(real: bool, logged: f64) -> f64 {
+        if real || logged == 0.0 {
+            current_timestamp()
+        } else {
+            logged
+        }
+    }
+    // An order which has already expired is rejected. This is skipped when replaying, the order
+    // was accepted back then and is expired by the logged `order_expire` which follows it.
+    fn check_expire_time(real: bool, req: &OrderPutRequestExt) -> Result<(), Status> {
+        if real && req.expire_time > 0.0 && req.expire_time <= current_timestamp() {
+            return Err(Status::invalid_argument("order has already expired"));
+        }
+        Ok(())
+    }
+    // like `check_expire_time`, the orders replayed were accepted back then
+    fn check_audit(&self, real: bool) -> Result<(), Status> {
+        if real && self.settings.audit_refuse_orders && self.audit_mismatches.iter().any(|mismatch| mismatch.hard) {
+            return Err(Status::failed_precondition("new orders are refused after a failed balance audit"));
+        }
+        Ok(())
+    }
+    // the trades may have drawn on pools which orders in other markets share, see `Market::fit_pooled_orders`
+    fn fit_pooled_orders(&mut self, real: bool, time: f64) {
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        for market in self.markets.values_mut() {
+            market.fit_pooled_orders(&mut self.sequencer, (&mut self.balance_manager).into(), persistor, time);
+        }
     }
     fn append_operation_log<Operation>(&mut self, method: &str, req: &Operation)
     where
@@ -890,6 +1876,16 @@ impl Controller {
     }
 }
 
+fn withdraw_response(business_id: u64, withdrawal: &Withdrawal) -> WithdrawResponse {
+    WithdrawResponse {
+        business_id,
+        user_id: withdrawal.user_id,
+        asset: withdrawal.asset.clone(),
+        amount: withdrawal.amount.to_string(),
+        status: withdrawal.status,
+    }
+}
+
 #[cfg(sqlxverf)]
 fn sqlverf_clear_slice() -> impl std::any::Any {
     sqlx::query!("drop table if exists balance_history, balance_slice")
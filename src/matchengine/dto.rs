@@ -1,10 +1,14 @@
+use crate::audit;
 use crate::market;
+use crate::rpc_ext as ext;
+use crate::types::{MarketPhase, OcoTrigger, PegReference, SelfTradePrevention, TimeInForce, TradingStatus, WithdrawStatus};
 
 use anyhow::{anyhow, bail, Result};
 use arrayref::array_ref;
 use fluidex_common::rust_decimal::{self, prelude::Zero, Decimal};
 use fluidex_common::utils::timeutil::FTimestamp;
 use orchestra::rpc::exchange::*;
+use serde::{Deserialize, Serialize};
 
 use std::convert::TryFrom;
 use std::str::FromStr;
@@ -22,10 +26,9 @@ impl From<market::Order> for OrderInfo {
         OrderInfo {
             id: o.id,
             market: String::from(&*o.market),
-            order_type: if o.type_ == market::OrderType::LIMIT {
-                OrderType::Limit as i32
-            } else {
-                OrderType::Market as i32
+            order_type: match o.type_ {
+                market::OrderType::LIMIT | market::OrderType::STOP_LIMIT => OrderType::Limit as i32,
+                market::OrderType::MARKET | market::OrderType::STOP_MARKET => OrderType::Market as i32,
             },
             order_side: if o.side == market::OrderSide::ASK {
                 OrderSide::Ask as i32
@@ -66,11 +69,21 @@ impl TryFrom<OrderPutRequest> for market::OrderInput {
             },
             amount: str_to_decimal(&req.amount, false).map_err(|_| anyhow!("invalid amount"))?,
             price: str_to_decimal(&req.price, req.order_type == OrderType::Market as i32).map_err(|_| anyhow!("invalid price"))?,
+            trigger_price: Decimal::zero(),
             quote_limit: str_to_decimal(&req.quote_limit, true).map_err(|_| anyhow!("invalid quote limit"))?,
             taker_fee: str_to_decimal(&req.taker_fee, true).map_err(|_| anyhow!("invalid taker fee"))?,
             maker_fee: str_to_decimal(&req.maker_fee, true).map_err(|_| anyhow!("invalid maker fee"))?,
             market: req.market.clone(),
             post_only: req.post_only,
+            time_in_force: TimeInForce::GTC,
+            self_trade_prevention: None,
+            client_order_id: 0,
+            display_amount: Decimal::zero(),
+            shared_collateral: false,
+            expire_time: 0.0,
+            peg: PegReference::NONE,
+            peg_offset: Decimal::zero(),
+            time: 0.0,
             signature: if req.signature.is_empty() {
                 log::warn!("empty signature. should only happen in tests");
                 [0; 64]
@@ -85,3 +98,1081 @@ impl TryFrom<OrderPutRequest> for market::OrderInput {
         })
     }
 }
+
+// `OrderPutRequest` with the order options which only the extension service carries.
+// This is also what is written into the operation log, every extra field has a default
+// so that old `order_put` logs can still be replayed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OrderPutRequestExt {
+    #[serde(flatten)]
+    pub base: OrderPutRequest,
+    // non-empty for stop orders: a LIMIT / MARKET order becomes STOP_LIMIT / STOP_MARKET
+    #[serde(default)]
+    pub trigger_price: String,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    // an order with the same client order id, active or recently finished, is returned instead of placing a new one
+    #[serde(default)]
+    pub client_order_id: u64,
+    // non-empty for iceberg orders, only this much of the order is shown at a time
+    #[serde(default)]
+    pub display_amount: String,
+    // draw on the collateral pool of the user in the asset it pays, instead of freezing its own balance
+    #[serde(default)]
+    pub shared_collateral: bool,
+    // unix timestamp in seconds after which the order is cancelled by the engine, 0 means never
+    #[serde(default)]
+    pub expire_time: f64,
+    // a pegged order is placed without a price, it follows the reference with the offset
+    #[serde(default)]
+    pub peg: PegReference,
+    #[serde(default)]
+    pub peg_offset: String,
+    // when the order is placed, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+impl From<OrderPutRequest> for OrderPutRequestExt {
+    fn from(base: OrderPutRequest) -> Self {
+        OrderPutRequestExt {
+            base,
+            ..Default::default()
+        }
+    }
+}
+
+impl TryFrom<OrderPutRequestExt> for market::OrderInput {
+    type Error = anyhow::Error;
+
+    fn try_from(req: OrderPutRequestExt) -> std::result::Result<Self, Self::Error> {
+        let mut base = req.base;
+        // a pegged order has no price of its own
+        if req.peg != PegReference::NONE && base.price.is_empty() {
+            base.price = String::from("0");
+        }
+        let mut input = market::OrderInput::try_from(base)?;
+        input.time_in_force = req.time_in_force;
+        input.self_trade_prevention = req.self_trade_prevention;
+        input.client_order_id = req.client_order_id;
+        input.shared_collateral = req.shared_collateral;
+        input.expire_time = req.expire_time;
+        input.peg = req.peg;
+        input.peg_offset = str_to_decimal(&req.peg_offset, true).map_err(|_| anyhow!("invalid peg offset"))?;
+        input.time = req.time;
+        input.display_amount = str_to_decimal(&req.display_amount, true).map_err(|_| anyhow!("invalid display amount"))?;
+        input.trigger_price = str_to_decimal(&req.trigger_price, true).map_err(|_| anyhow!("invalid trigger price"))?;
+        if !input.trigger_price.is_zero() {
+            input.type_ = if input.type_ == market::OrderType::LIMIT {
+                market::OrderType::STOP_LIMIT
+            } else {
+                market::OrderType::STOP_MARKET
+            };
+        }
+        Ok(input)
+    }
+}
+
+// `BatchOrderPutRequest` as it is written into the operation log, with the time the orders are placed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BatchOrderPutRequestExt {
+    #[serde(flatten)]
+    pub base: BatchOrderPutRequest,
+    #[serde(default)]
+    pub time: f64,
+}
+
+impl From<BatchOrderPutRequest> for BatchOrderPutRequestExt {
+    fn from(base: BatchOrderPutRequest) -> Self {
+        BatchOrderPutRequestExt {
+            base,
+            ..Default::default()
+        }
+    }
+}
+
+// `OrderCancelRequest` / `OrderDetailRequest` which can also find the order by its client order id.
+// The client order id is only used when `order_id` is 0.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OrderCancelRequestExt {
+    #[serde(flatten)]
+    pub base: OrderCancelRequest,
+    #[serde(default)]
+    pub client_order_id: u64,
+    // when the order is cancelled, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+impl From<OrderCancelRequest> for OrderCancelRequestExt {
+    fn from(base: OrderCancelRequest) -> Self {
+        OrderCancelRequestExt {
+            base,
+            ..Default::default()
+        }
+    }
+}
+
+// `OrderCancelAllRequest` with the time it is logged at.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OrderCancelAllRequestExt {
+    #[serde(flatten)]
+    pub base: OrderCancelAllRequest,
+    // when the orders are cancelled, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+impl From<OrderCancelAllRequest> for OrderCancelAllRequestExt {
+    fn from(base: OrderCancelAllRequest) -> Self {
+        OrderCancelAllRequestExt { base, time: 0.0 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OrderDetailRequestExt {
+    #[serde(flatten)]
+    pub base: OrderDetailRequest,
+    #[serde(default)]
+    pub user_id: u32,
+    #[serde(default)]
+    pub client_order_id: u64,
+}
+
+impl From<OrderDetailRequest> for OrderDetailRequestExt {
+    fn from(base: OrderDetailRequest) -> Self {
+        OrderDetailRequestExt {
+            base,
+            ..Default::default()
+        }
+    }
+}
+
+// Orders of one user in one market which are cancelled together once one of them is filled.
+// The orders must be good till cancelled limit or stop orders which do not trade when placed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderGroupPutRequest {
+    pub orders: Vec<OrderPutRequestExt>,
+    #[serde(default)]
+    pub oco_trigger: OcoTrigger,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderGroupPutResponse {
+    // the id of the first order
+    pub group_id: u64,
+    pub order_ids: Vec<u64>,
+}
+
+// Change the price and / or the amount of a resting order. An empty field is left unchanged.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OrderAmendRequest {
+    pub user_id: u32,
+    pub market: String,
+    pub order_id: u64,
+    #[serde(default)]
+    pub amount: String,
+    #[serde(default)]
+    pub price: String,
+    // when the order is amended, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+// Start the auction of a market, or end it by uncrossing the collected orders.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarketPhaseUpdateRequest {
+    pub market: String,
+    pub phase: MarketPhase,
+    // when the phase changes, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarketPhaseUpdateResponse {
+    pub market: String,
+    pub phase: MarketPhase,
+    // the price the auction is uncrossed at, empty if nothing is traded
+    pub auction_price: String,
+}
+
+// The orderbook by price levels, together with the sequence of the last orderbook delta it includes.
+// Deltas with a larger sequence are to be applied on top of it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderBookSnapshotRequest {
+    pub market: String,
+    pub limit: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderBookSnapshotResponse {
+    pub market: String,
+    pub sequence: u64,
+    pub asks: Vec<PriceLevel>,
+    pub bids: Vec<PriceLevel>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PriceLevel {
+    pub price: String,
+    pub amount: String,
+}
+
+// A page of the resting orders of one side of the orderbook, order by order in priority order.
+// The first page is returned when `after` is not given, and `next` is given for the page after.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderBookOrdersRequest {
+    pub market: String,
+    pub side: market::OrderSide,
+    #[serde(default)]
+    pub after: Option<BookCursor>,
+    // 0 means the largest page
+    #[serde(default)]
+    pub limit: u32,
+}
+
+// the position of an order in the orderbook
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BookCursor {
+    pub price: String,
+    pub priority: u64,
+    pub order_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderBookOrdersResponse {
+    pub market: String,
+    pub side: market::OrderSide,
+    pub orders: Vec<BookOrder>,
+    // none on the last page
+    pub next: Option<BookCursor>,
+}
+
+// An order as it rests in the orderbook. The user is only given to the admin, and others only see
+// the shown part of an iceberg order.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BookOrder {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<u32>,
+    pub side: market::OrderSide,
+    pub price: String,
+    pub remain: String,
+    pub create_time: f64,
+    pub priority: u64,
+}
+
+// Cancel the orders of a user which meet all the given conditions, in one market or in all of them.
+// An empty or missing field is not checked.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OrderMassCancelRequest {
+    pub user_id: u32,
+    // empty when `all_markets` is set
+    #[serde(default)]
+    pub market: String,
+    // every market instead of the given one
+    #[serde(default)]
+    pub all_markets: bool,
+    #[serde(default)]
+    pub side: Option<market::OrderSide>,
+    #[serde(default)]
+    pub min_price: String,
+    #[serde(default)]
+    pub max_price: String,
+    // unix timestamp in seconds, only orders created before it are cancelled
+    #[serde(default)]
+    pub before: f64,
+    // when the orders are cancelled, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderMassCancelResponse {
+    pub order_ids: Vec<u64>,
+}
+
+// Arm the cancel-all-after timer of a user, or disarm it with a zero timeout. Calling it again
+// before the timer lapses pushes the deadline back, so it serves as the heartbeat as well.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CancelAllAfterRequest {
+    pub user_id: u32,
+    // in seconds
+    pub timeout: u64,
+    // when the timer is armed, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CancelAllAfterResponse {
+    // unix timestamp in seconds when the orders will be cancelled, 0 when disarmed
+    pub trigger_time: f64,
+}
+
+// Cancel the orders of a user in all markets, issued when its cancel-all-after timer lapses.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserCancelAllRequest {
+    pub user_id: u32,
+    // when the orders are cancelled, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+// Cancel the orders of a market which have expired at `time`. It is issued by the engine
+// itself, and the time is logged so that the same orders expire when it is replayed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderExpireRequest {
+    pub market: String,
+    pub time: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarketStatusUpdateRequest {
+    pub market: String,
+    pub status: TradingStatus,
+}
+
+// `MarketListResponse` / `MarketSummaryResponse` with the trading status and the phase of the markets
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarketInfoExt {
+    #[serde(flatten)]
+    pub base: market_list_response::MarketInfo,
+    pub trading_status: TradingStatus,
+    pub phase: MarketPhase,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarketListResponseExt {
+    pub markets: Vec<MarketInfoExt>,
+}
+
+impl From<MarketListResponseExt> for MarketListResponse {
+    fn from(resp: MarketListResponseExt) -> Self {
+        MarketListResponse {
+            markets: resp.markets.into_iter().map(|market| market.base).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarketSummaryExt {
+    #[serde(flatten)]
+    pub base: market_summary_response::MarketSummary,
+    pub trading_status: TradingStatus,
+    pub phase: MarketPhase,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarketSummaryResponseExt {
+    pub market_summaries: Vec<MarketSummaryExt>,
+}
+
+impl From<MarketSummaryResponseExt> for MarketSummaryResponse {
+    fn from(resp: MarketSummaryResponseExt) -> Self {
+        MarketSummaryResponse {
+            market_summaries: resp.market_summaries.into_iter().map(|summary| summary.base).collect(),
+        }
+    }
+}
+
+// Move available balance of the user into its collateral pool of the asset,
+// or back out of the pool when `delta` is negative.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CollateralPoolUpdateRequest {
+    pub user_id: u32,
+    pub asset: String,
+    pub delta: String,
+    // when the pool is updated, set by the engine
+    #[serde(default)]
+    pub time: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CollateralPoolUpdateResponse {
+    // what is in the pool after the update
+    pub amount: String,
+}
+
+// the override is removed when `rate` is not given
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FeeOverrideUpdateRequest {
+    pub market: String,
+    pub user_id: u32,
+    pub rate: Option<market::FeeRate>,
+}
+
+// Hold `amount` of the available balance of the user for a withdrawal, until it is confirmed
+// or rejected by its `business_id`. A retried request with the same business id is answered
+// with the withdrawal as it is.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WithdrawRequest {
+    pub user_id: u32,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+    pub amount: String,
+    #[serde(default)]
+    pub detail: String,
+}
+
+// confirm or reject a requested withdrawal
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WithdrawFinishRequest {
+    pub business_id: u64,
+    #[serde(default)]
+    pub detail: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WithdrawResponse {
+    pub business_id: u64,
+    pub user_id: u32,
+    pub asset: String,
+    pub amount: String,
+    pub status: WithdrawStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BalanceAuditRequest {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BalanceAuditResponse {
+    pub mismatches: Vec<audit::Mismatch>,
+}
+
+// a block the logs of L1 events have been read from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EthBlockRequest {
+    pub block_number: u64,
+    pub block_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EthBlockResponse {
+    pub block_number: u64,
+    // how many logs have been undone by a revert
+    pub reverted_logs: u32,
+}
+
+// Conversions of the messages of the extension service, see proto/matchengine_ext.proto.
+// Requests are turned into the structs above, which is what the controller takes and logs.
+
+fn ext_enum<T>(from_i32: fn(i32) -> Option<T>, value: i32, name: &str) -> Result<T> {
+    from_i32(value).ok_or_else(|| anyhow!("invalid {}", name))
+}
+
+fn side_from_ext(side: i32) -> Result<market::OrderSide> {
+    Ok(match ext_enum(ext::OrderSide::from_i32, side, "order side")? {
+        ext::OrderSide::Ask => market::OrderSide::ASK,
+        ext::OrderSide::Bid => market::OrderSide::BID,
+    })
+}
+
+fn side_to_ext(side: market::OrderSide) -> i32 {
+    match side {
+        market::OrderSide::ASK => ext::OrderSide::Ask as i32,
+        market::OrderSide::BID => ext::OrderSide::Bid as i32,
+    }
+}
+
+impl TryFrom<ext::OrderPutRequest> for OrderPutRequestExt {
+    type Error = anyhow::Error;
+
+    fn try_from(req: ext::OrderPutRequest) -> std::result::Result<Self, Self::Error> {
+        let order_side = match side_from_ext(req.order_side)? {
+            market::OrderSide::ASK => OrderSide::Ask,
+            market::OrderSide::BID => OrderSide::Bid,
+        };
+        let order_type = match ext_enum(ext::OrderType::from_i32, req.order_type, "order type")? {
+            ext::OrderType::Limit => OrderType::Limit,
+            ext::OrderType::Market => OrderType::Market,
+        };
+        let time_in_force = match ext_enum(ext::TimeInForce::from_i32, req.time_in_force, "time in force")? {
+            ext::TimeInForce::Gtc => TimeInForce::GTC,
+            ext::TimeInForce::Ioc => TimeInForce::IOC,
+            ext::TimeInForce::Fok => TimeInForce::FOK,
+        };
+        let self_trade_prevention = match ext_enum(
+            ext::SelfTradePrevention::from_i32,
+            req.self_trade_prevention,
+            "self trade prevention",
+        )? {
+            ext::SelfTradePrevention::MarketDefault => None,
+            ext::SelfTradePrevention::None => Some(SelfTradePrevention::NONE),
+            ext::SelfTradePrevention::CancelNewest => Some(SelfTradePrevention::CANCEL_NEWEST),
+            ext::SelfTradePrevention::CancelOldest => Some(SelfTradePrevention::CANCEL_OLDEST),
+            ext::SelfTradePrevention::CancelBoth => Some(SelfTradePrevention::CANCEL_BOTH),
+            ext::SelfTradePrevention::DecrementAndCancel => Some(SelfTradePrevention::DECREMENT_AND_CANCEL),
+        };
+        let peg = match ext_enum(ext::PegReference::from_i32, req.peg, "peg reference")? {
+            ext::PegReference::None => PegReference::NONE,
+            ext::PegReference::BestBid => PegReference::BEST_BID,
+            ext::PegReference::BestAsk => PegReference::BEST_ASK,
+            ext::PegReference::Mid => PegReference::MID,
+        };
+        Ok(OrderPutRequestExt {
+            base: OrderPutRequest {
+                user_id: req.user_id,
+                market: req.market,
+                order_side: order_side as i32,
+                order_type: order_type as i32,
+                amount: req.amount,
+                price: req.price,
+                quote_limit: req.quote_limit,
+                taker_fee: req.taker_fee,
+                maker_fee: req.maker_fee,
+                post_only: req.post_only,
+                signature: req.signature,
+            },
+            trigger_price: req.trigger_price,
+            time_in_force,
+            self_trade_prevention,
+            client_order_id: req.client_order_id,
+            display_amount: req.display_amount,
+            shared_collateral: req.shared_collateral,
+            expire_time: req.expire_time,
+            peg,
+            peg_offset: req.peg_offset,
+            time: 0.0,
+        })
+    }
+}
+
+impl From<OrderInfo> for ext::OrderInfo {
+    fn from(o: OrderInfo) -> Self {
+        ext::OrderInfo {
+            id: o.id,
+            market: o.market,
+            order_type: if o.order_type == OrderType::Limit as i32 {
+                ext::OrderType::Limit as i32
+            } else {
+                ext::OrderType::Market as i32
+            },
+            order_side: if o.order_side == OrderSide::Ask as i32 {
+                ext::OrderSide::Ask as i32
+            } else {
+                ext::OrderSide::Bid as i32
+            },
+            user_id: o.user_id,
+            create_time: o.create_time,
+            update_time: o.update_time,
+            price: o.price,
+            amount: o.amount,
+            taker_fee: o.taker_fee,
+            maker_fee: o.maker_fee,
+            remain: o.remain,
+            finished_base: o.finished_base,
+            finished_quote: o.finished_quote,
+            finished_fee: o.finished_fee,
+            post_only: o.post_only,
+        }
+    }
+}
+
+impl From<ext::OrderCancelRequest> for OrderCancelRequestExt {
+    fn from(req: ext::OrderCancelRequest) -> Self {
+        OrderCancelRequestExt {
+            base: OrderCancelRequest {
+                user_id: req.user_id,
+                market: req.market,
+                order_id: req.order_id,
+            },
+            client_order_id: req.client_order_id,
+            time: 0.0,
+        }
+    }
+}
+
+impl From<ext::OrderDetailRequest> for OrderDetailRequestExt {
+    fn from(req: ext::OrderDetailRequest) -> Self {
+        OrderDetailRequestExt {
+            base: OrderDetailRequest {
+                market: req.market,
+                order_id: req.order_id,
+            },
+            user_id: req.user_id,
+            client_order_id: req.client_order_id,
+        }
+    }
+}
+
+impl From<ext::OrderAmendRequest> for OrderAmendRequest {
+    fn from(req: ext::OrderAmendRequest) -> Self {
+        OrderAmendRequest {
+            user_id: req.user_id,
+            market: req.market,
+            order_id: req.order_id,
+            amount: req.amount,
+            price: req.price,
+            time: 0.0,
+        }
+    }
+}
+
+impl TryFrom<ext::OrderGroupPutRequest> for OrderGroupPutRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(req: ext::OrderGroupPutRequest) -> std::result::Result<Self, Self::Error> {
+        Ok(OrderGroupPutRequest {
+            orders: req.orders.into_iter().map(OrderPutRequestExt::try_from).collect::<Result<_>>()?,
+            oco_trigger: match ext_enum(ext::OcoTrigger::from_i32, req.oco_trigger, "oco trigger")? {
+                ext::OcoTrigger::PartialFill => OcoTrigger::PARTIAL_FILL,
+                ext::OcoTrigger::FullFill => OcoTrigger::FULL_FILL,
+            },
+        })
+    }
+}
+
+impl From<OrderGroupPutResponse> for ext::OrderGroupPutResponse {
+    fn from(resp: OrderGroupPutResponse) -> Self {
+        ext::OrderGroupPutResponse {
+            group_id: resp.group_id,
+            order_ids: resp.order_ids,
+        }
+    }
+}
+
+impl TryFrom<ext::OrderMassCancelRequest> for OrderMassCancelRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(req: ext::OrderMassCancelRequest) -> std::result::Result<Self, Self::Error> {
+        Ok(OrderMassCancelRequest {
+            user_id: req.user_id,
+            market: req.market,
+            all_markets: req.all_markets,
+            side: match ext_enum(ext::SideFilter::from_i32, req.side, "side")? {
+                ext::SideFilter::Both => None,
+                ext::SideFilter::Ask => Some(market::OrderSide::ASK),
+                ext::SideFilter::Bid => Some(market::OrderSide::BID),
+            },
+            min_price: req.min_price,
+            max_price: req.max_price,
+            before: req.before,
+            time: 0.0,
+        })
+    }
+}
+
+impl From<OrderMassCancelResponse> for ext::OrderMassCancelResponse {
+    fn from(resp: OrderMassCancelResponse) -> Self {
+        ext::OrderMassCancelResponse { order_ids: resp.order_ids }
+    }
+}
+
+impl From<ext::CancelAllAfterRequest> for CancelAllAfterRequest {
+    fn from(req: ext::CancelAllAfterRequest) -> Self {
+        CancelAllAfterRequest {
+            user_id: req.user_id,
+            timeout: req.timeout,
+            time: 0.0,
+        }
+    }
+}
+
+impl From<CancelAllAfterResponse> for ext::CancelAllAfterResponse {
+    fn from(resp: CancelAllAfterResponse) -> Self {
+        ext::CancelAllAfterResponse {
+            trigger_time: resp.trigger_time,
+        }
+    }
+}
+
+impl From<ext::OrderBookSnapshotRequest> for OrderBookSnapshotRequest {
+    fn from(req: ext::OrderBookSnapshotRequest) -> Self {
+        OrderBookSnapshotRequest {
+            market: req.market,
+            limit: req.limit,
+        }
+    }
+}
+
+impl From<PriceLevel> for ext::PriceLevel {
+    fn from(level: PriceLevel) -> Self {
+        ext::PriceLevel {
+            price: level.price,
+            amount: level.amount,
+        }
+    }
+}
+
+impl From<OrderBookSnapshotResponse> for ext::OrderBookSnapshotResponse {
+    fn from(resp: OrderBookSnapshotResponse) -> Self {
+        ext::OrderBookSnapshotResponse {
+            market: resp.market,
+            sequence: resp.sequence,
+            asks: resp.asks.into_iter().map(Into::into).collect(),
+            bids: resp.bids.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<ext::BookCursor> for BookCursor {
+    fn from(cursor: ext::BookCursor) -> Self {
+        BookCursor {
+            price: cursor.price,
+            priority: cursor.priority,
+            order_id: cursor.order_id,
+        }
+    }
+}
+
+impl From<BookCursor> for ext::BookCursor {
+    fn from(cursor: BookCursor) -> Self {
+        ext::BookCursor {
+            price: cursor.price,
+            priority: cursor.priority,
+            order_id: cursor.order_id,
+        }
+    }
+}
+
+impl TryFrom<ext::OrderBookOrdersRequest> for OrderBookOrdersRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(req: ext::OrderBookOrdersRequest) -> std::result::Result<Self, Self::Error> {
+        Ok(OrderBookOrdersRequest {
+            market: req.market,
+            side: side_from_ext(req.side)?,
+            after: req.after.map(Into::into),
+            limit: req.limit,
+        })
+    }
+}
+
+impl From<OrderBookOrdersResponse> for ext::OrderBookOrdersResponse {
+    fn from(resp: OrderBookOrdersResponse) -> Self {
+        ext::OrderBookOrdersResponse {
+            market: resp.market,
+            side: side_to_ext(resp.side),
+            orders: resp
+                .orders
+                .into_iter()
+                .map(|order| ext::BookOrder {
+                    id: order.id,
+                    user_id: order.user_id.unwrap_or(0),
+                    side: side_to_ext(order.side),
+                    price: order.price,
+                    remain: order.remain,
+                    create_time: order.create_time,
+                    priority: order.priority,
+                })
+                .collect(),
+            next: resp.next.map(Into::into),
+        }
+    }
+}
+
+fn phase_from_ext(phase: i32) -> Result<MarketPhase> {
+    Ok(match ext_enum(ext::MarketPhase::from_i32, phase, "market phase")? {
+        ext::MarketPhase::Continuous => MarketPhase::CONTINUOUS,
+        ext::MarketPhase::Auction => MarketPhase::AUCTION,
+    })
+}
+
+fn phase_to_ext(phase: MarketPhase) -> i32 {
+    match phase {
+        MarketPhase::CONTINUOUS => ext::MarketPhase::Continuous as i32,
+        MarketPhase::AUCTION => ext::MarketPhase::Auction as i32,
+    }
+}
+
+fn status_to_ext(status: TradingStatus) -> i32 {
+    match status {
+        TradingStatus::OPEN => ext::TradingStatus::Open as i32,
+        TradingStatus::HALTED => ext::TradingStatus::Halted as i32,
+        TradingStatus::CANCEL_ONLY => ext::TradingStatus::CancelOnly as i32,
+        TradingStatus::POST_ONLY => ext::TradingStatus::PostOnly as i32,
+    }
+}
+
+impl From<MarketListResponseExt> for ext::MarketListResponse {
+    fn from(resp: MarketListResponseExt) -> Self {
+        ext::MarketListResponse {
+            markets: resp
+                .markets
+                .into_iter()
+                .map(|market| ext::MarketInfo {
+                    name: market.base.name,
+                    base: market.base.base,
+                    quote: market.base.quote,
+                    fee_precision: market.base.fee_precision,
+                    amount_precision: market.base.amount_precision,
+                    price_precision: market.base.price_precision,
+                    min_amount: market.base.min_amount,
+                    trading_status: status_to_ext(market.trading_status),
+                    phase: phase_to_ext(market.phase),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<ext::MarketSummaryRequest> for MarketSummaryRequest {
+    fn from(req: ext::MarketSummaryRequest) -> Self {
+        MarketSummaryRequest { markets: req.markets }
+    }
+}
+
+impl From<MarketSummaryResponseExt> for ext::MarketSummaryResponse {
+    fn from(resp: MarketSummaryResponseExt) -> Self {
+        ext::MarketSummaryResponse {
+            market_summaries: resp
+                .market_summaries
+                .into_iter()
+                .map(|summary| ext::MarketSummary {
+                    name: summary.base.name,
+                    ask_count: summary.base.ask_count,
+                    ask_amount: summary.base.ask_amount,
+                    bid_count: summary.base.bid_count,
+                    bid_amount: summary.base.bid_amount,
+                    trade_count: summary.base.trade_count,
+                    trading_status: status_to_ext(summary.trading_status),
+                    phase: phase_to_ext(summary.phase),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<ext::MarketPhaseUpdateRequest> for MarketPhaseUpdateRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(req: ext::MarketPhaseUpdateRequest) -> std::result::Result<Self, Self::Error> {
+        Ok(MarketPhaseUpdateRequest {
+            market: req.market,
+            phase: phase_from_ext(req.phase)?,
+            time: 0.0,
+        })
+    }
+}
+
+impl From<MarketPhaseUpdateResponse> for ext::MarketPhaseUpdateResponse {
+    fn from(resp: MarketPhaseUpdateResponse) -> Self {
+        ext::MarketPhaseUpdateResponse {
+            market: resp.market,
+            phase: phase_to_ext(resp.phase),
+            auction_price: resp.auction_price,
+        }
+    }
+}
+
+impl TryFrom<ext::MarketStatusUpdateRequest> for MarketStatusUpdateRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(req: ext::MarketStatusUpdateRequest) -> std::result::Result<Self, Self::Error> {
+        Ok(MarketStatusUpdateRequest {
+            market: req.market,
+            status: match ext_enum(ext::TradingStatus::from_i32, req.status, "trading status")? {
+                ext::TradingStatus::Open => TradingStatus::OPEN,
+                ext::TradingStatus::Halted => TradingStatus::HALTED,
+                ext::TradingStatus::CancelOnly => TradingStatus::CANCEL_ONLY,
+                ext::TradingStatus::PostOnly => TradingStatus::POST_ONLY,
+            },
+        })
+    }
+}
+
+impl TryFrom<ext::FeeOverrideUpdateRequest> for FeeOverrideUpdateRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(req: ext::FeeOverrideUpdateRequest) -> std::result::Result<Self, Self::Error> {
+        let rate = match req.rate {
+            Some(rate) => Some(market::FeeRate {
+                taker_fee: str_to_decimal(&rate.taker_fee, true).map_err(|_| anyhow!("invalid taker fee"))?,
+                maker_fee: str_to_decimal(&rate.maker_fee, true).map_err(|_| anyhow!("invalid maker fee"))?,
+            }),
+            None => None,
+        };
+        Ok(FeeOverrideUpdateRequest {
+            market: req.market,
+            user_id: req.user_id,
+            rate,
+        })
+    }
+}
+
+impl From<ext::CollateralPoolUpdateRequest> for CollateralPoolUpdateRequest {
+    fn from(req: ext::CollateralPoolUpdateRequest) -> Self {
+        CollateralPoolUpdateRequest {
+            user_id: req.user_id,
+            asset: req.asset,
+            delta: req.delta,
+            time: 0.0,
+        }
+    }
+}
+
+impl From<CollateralPoolUpdateResponse> for ext::CollateralPoolUpdateResponse {
+    fn from(resp: CollateralPoolUpdateResponse) -> Self {
+        ext::CollateralPoolUpdateResponse { amount: resp.amount }
+    }
+}
+
+impl From<BalanceAuditResponse> for ext::BalanceAuditResponse {
+    fn from(resp: BalanceAuditResponse) -> Self {
+        ext::BalanceAuditResponse {
+            mismatches: resp
+                .mismatches
+                .into_iter()
+                .map(|mismatch| ext::Mismatch {
+                    kind: match mismatch.kind {
+                        audit::MismatchKind::NEGATIVE_BALANCE => ext::MismatchKind::NegativeBalance as i32,
+                        audit::MismatchKind::FROZEN_MISMATCH => ext::MismatchKind::FrozenMismatch as i32,
+                        audit::MismatchKind::SUPPLY_MISMATCH => ext::MismatchKind::SupplyMismatch as i32,
+                    },
+                    user_id: mismatch.user_id.unwrap_or(0),
+                    asset: mismatch.asset,
+                    expected: mismatch.expected.to_string(),
+                    actual: mismatch.actual.to_string(),
+                    hard: mismatch.hard,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<ext::EthBlockRequest> for EthBlockRequest {
+    fn from(req: ext::EthBlockRequest) -> Self {
+        EthBlockRequest {
+            block_number: req.block_number,
+            block_hash: req.block_hash,
+        }
+    }
+}
+
+impl From<EthBlockResponse> for ext::EthBlockResponse {
+    fn from(resp: EthBlockResponse) -> Self {
+        ext::EthBlockResponse {
+            block_number: resp.block_number,
+            reverted_logs: resp.reverted_logs,
+        }
+    }
+}
+
+impl From<ext::WithdrawRequest> for WithdrawRequest {
+    fn from(req: ext::WithdrawRequest) -> Self {
+        WithdrawRequest {
+            user_id: req.user_id,
+            asset: req.asset,
+            business: req.business,
+            business_id: req.business_id,
+            amount: req.amount,
+            detail: req.detail,
+        }
+    }
+}
+
+impl From<ext::WithdrawFinishRequest> for WithdrawFinishRequest {
+    fn from(req: ext::WithdrawFinishRequest) -> Self {
+        WithdrawFinishRequest {
+            business_id: req.business_id,
+            detail: req.detail,
+        }
+    }
+}
+
+impl From<WithdrawResponse> for ext::WithdrawResponse {
+    fn from(resp: WithdrawResponse) -> Self {
+        ext::WithdrawResponse {
+            business_id: resp.business_id,
+            user_id: resp.user_id,
+            asset: resp.asset,
+            amount: resp.amount,
+            status: match resp.status {
+                WithdrawStatus::REQUESTED => ext::WithdrawStatus::Requested as i32,
+                WithdrawStatus::CONFIRMED => ext::WithdrawStatus::Confirmed as i32,
+                WithdrawStatus::REJECTED => ext::WithdrawStatus::Rejected as i32,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_ext_order_put() {
+        let req = ext::OrderPutRequest {
+            user_id: 1,
+            market: "ETH_USDT".to_owned(),
+            order_side: ext::OrderSide::Bid as i32,
+            order_type: ext::OrderType::Limit as i32,
+            amount: "1".to_owned(),
+            price: "100".to_owned(),
+            trigger_price: "90".to_owned(),
+            time_in_force: ext::TimeInForce::Fok as i32,
+            self_trade_prevention: ext::SelfTradePrevention::CancelOldest as i32,
+            client_order_id: 7,
+            expire_time: 1000.0,
+            ..Default::default()
+        };
+        let input = market::OrderInput::try_from(OrderPutRequestExt::try_from(req.clone()).unwrap()).unwrap();
+        assert_eq!(input.side, market::OrderSide::BID);
+        assert_eq!(input.type_, market::OrderType::STOP_LIMIT);
+        assert_eq!(input.trigger_price, dec!(90));
+        assert_eq!(input.time_in_force, TimeInForce::FOK);
+        assert_eq!(input.self_trade_prevention, Some(SelfTradePrevention::CANCEL_OLDEST));
+        assert_eq!(input.client_order_id, 7);
+        assert_eq!(input.expire_time, 1000.0);
+
+        // the market mode when it is not given
+        let req = ext::OrderPutRequest {
+            self_trade_prevention: ext::SelfTradePrevention::MarketDefault as i32,
+            ..req
+        };
+        assert_eq!(OrderPutRequestExt::try_from(req.clone()).unwrap().self_trade_prevention, None);
+        let req = ext::OrderPutRequest { time_in_force: 9, ..req };
+        assert!(OrderPutRequestExt::try_from(req).is_err());
+    }
+
+    #[test]
+    fn test_ext_market_list() {
+        let resp = MarketListResponseExt {
+            markets: vec![MarketInfoExt {
+                base: market_list_response::MarketInfo {
+                    name: "ETH_USDT".to_owned(),
+                    base: "ETH".to_owned(),
+                    quote: "USDT".to_owned(),
+                    fee_precision: 4,
+                    amount_precision: 4,
+                    price_precision: 2,
+                    min_amount: "0.001".to_owned(),
+                },
+                trading_status: TradingStatus::CANCEL_ONLY,
+                phase: MarketPhase::AUCTION,
+            }],
+        };
+        let ext_resp = ext::MarketListResponse::from(resp.clone());
+        assert_eq!(ext_resp.markets[0].name, "ETH_USDT");
+        assert_eq!(ext_resp.markets[0].trading_status, ext::TradingStatus::CancelOnly as i32);
+        assert_eq!(ext_resp.markets[0].phase, ext::MarketPhase::Auction as i32);
+        // the base response keeps the rest
+        assert_eq!(MarketListResponse::from(resp).markets[0].min_amount, "0.001");
+
+        let resp = MarketSummaryResponseExt {
+            market_summaries: vec![MarketSummaryExt {
+                base: market_summary_response::MarketSummary {
+                    name: "ETH_USDT".to_owned(),
+                    ask_count: 1,
+                    ask_amount: "2".to_owned(),
+                    bid_count: 3,
+                    bid_amount: "4".to_owned(),
+                    trade_count: 5,
+                },
+                trading_status: TradingStatus::HALTED,
+                phase: MarketPhase::CONTINUOUS,
+            }],
+        };
+        let ext_resp = ext::MarketSummaryResponse::from(resp.clone());
+        assert_eq!(ext_resp.market_summaries[0].bid_count, 3);
+        assert_eq!(ext_resp.market_summaries[0].trading_status, ext::TradingStatus::Halted as i32);
+        assert_eq!(ext_resp.market_summaries[0].phase, ext::MarketPhase::Continuous as i32);
+        assert_eq!(MarketSummaryResponse::from(resp).market_summaries[0].trade_count, 5);
+    }
+}
@@ -1,10 +1,18 @@
+use anyhow::{bail, Result};
+use fluidex_common::rust_decimal::Decimal;
 use orchestra::rpc::exchange;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+// how many blocks below the highest one can still be reverted by a reorg
+pub const REORG_WINDOW: u64 = 64;
 
 #[derive(Debug, Clone)]
 pub struct EthLogGuard {
     block_number: u64,
     history: HashSet<EthLogMetadata>,
+    // the recent blocks, with what their logs did so that a reorg can undo it
+    blocks: BTreeMap<u64, EthBlock>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -14,14 +22,63 @@ pub struct EthLogMetadata {
     pub log_index: String,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct EthBlock {
+    // unknown until the block is reported by `set_block_hash`
+    pub hash: Option<String>,
+    pub logs: Vec<EthLog>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EthLog {
+    pub meta: EthLogMetadata,
+    pub effect: EthLogEffect,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum EthLogEffect {
+    RegisterUser {
+        user_id: u32,
+    },
+    BalanceUpdate {
+        user_id: u32,
+        asset: String,
+        business: String,
+        business_id: u64,
+        change: Decimal,
+    },
+}
+
 impl EthLogGuard {
     pub fn new(block_number: u64) -> Self {
         Self {
             block_number,
             history: HashSet::new(),
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    // from a slice, the history is what has been logged in the current block
+    pub fn restore(block_number: u64, blocks: BTreeMap<u64, EthBlock>) -> Self {
+        let history = blocks
+            .get(&block_number)
+            .map(|block| block.logs.iter().map(|log| log.meta.clone()).collect())
+            .unwrap_or_default();
+        Self {
+            block_number,
+            history,
+            blocks,
         }
     }
 
+    pub fn block_number(&self) -> u64 {
+        self.block_number
+    }
+
+    pub fn blocks(&self) -> &BTreeMap<u64, EthBlock> {
+        &self.blocks
+    }
+
     pub fn accept(&self, log_meta: &EthLogMetadata) -> bool {
         !(log_meta.block_number < self.block_number || (log_meta.block_number == self.block_number && self.history.contains(log_meta)))
     }
@@ -33,7 +90,7 @@ impl EthLogGuard {
         }
     }
 
-    pub fn update(&mut self, log_meta: EthLogMetadata) {
+    pub fn update(&mut self, log_meta: EthLogMetadata, effect: EthLogEffect) {
         assert!(self.accept(&log_meta));
 
         if log_meta.block_number > self.block_number {
@@ -41,12 +98,65 @@ impl EthLogGuard {
             self.history.clear();
         }
 
-        assert!(self.history.insert(log_meta));
+        assert!(self.history.insert(log_meta.clone()));
+        self.blocks
+            .entry(log_meta.block_number)
+            .or_default()
+            .logs
+            .push(EthLog { meta: log_meta, effect });
+        self.prune();
     }
 
-    pub fn update_optional(&mut self, log_meta: Option<EthLogMetadata>) {
+    pub fn update_optional(&mut self, log_meta: Option<EthLogMetadata>, effect: EthLogEffect) {
         if let Some(meta) = log_meta {
-            self.update(meta)
+            self.update(meta, effect)
+        }
+    }
+
+    // the hash of a block the logs have been read from
+    pub fn set_block_hash(&mut self, block_number: u64, hash: &str) -> Result<()> {
+        if block_number + REORG_WINDOW < self.block_number {
+            bail!("block is below the reorg window");
+        }
+        let block = self.blocks.entry(block_number).or_default();
+        match &block.hash {
+            Some(known) if known != hash => bail!("block hash mismatch, the block should be reverted first"),
+            _ => block.hash = Some(hash.to_owned()),
+        }
+        self.prune();
+        Ok(())
+    }
+
+    // A reorg has orphaned the block and so all the blocks after it. Their logs are returned
+    // latest first for the controller to undo, and will be accepted again from the new chain.
+    pub fn revert(&mut self, block_number: u64, hash: &str) -> Result<Vec<EthLog>> {
+        if block_number + REORG_WINDOW < self.block_number {
+            bail!("block is below the reorg window");
+        }
+        if let Some(known) = self.blocks.get(&block_number).and_then(|block| block.hash.as_ref()) {
+            if known != hash {
+                bail!("block hash mismatch");
+            }
+        }
+        let orphaned = self.blocks.split_off(&block_number);
+        let mut logs: Vec<EthLog> = orphaned.into_iter().flat_map(|(_, block)| block.logs).collect();
+        logs.reverse();
+        if block_number <= self.block_number {
+            self.block_number = block_number;
+            self.history.clear();
+        }
+        Ok(logs)
+    }
+
+    // the current block is always kept, its logs are the history
+    fn prune(&mut self) {
+        let highest = self.blocks.keys().next_back().copied().unwrap_or(0).max(self.block_number);
+        if let Some(lowest) = highest.checked_sub(REORG_WINDOW) {
+            let current = self.blocks.remove(&self.block_number);
+            self.blocks = self.blocks.split_off(&lowest);
+            if let Some(current) = current {
+                self.blocks.insert(self.block_number, current);
+            }
         }
     }
 }
@@ -60,3 +170,51 @@ impl From<&exchange::EthLogMetadata> for EthLogMetadata {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fluidex_common::rust_decimal_macros::*;
+
+    fn meta(block_number: u64, log_index: &str) -> EthLogMetadata {
+        EthLogMetadata {
+            block_number,
+            tx_hash: format!("0x{}", block_number),
+            log_index: log_index.to_owned(),
+        }
+    }
+
+    fn deposit(business_id: u64) -> EthLogEffect {
+        EthLogEffect::BalanceUpdate {
+            user_id: 1,
+            asset: "ETH".to_owned(),
+            business: "deposit".to_owned(),
+            business_id,
+            change: dec!(1),
+        }
+    }
+
+    #[test]
+    fn test_revert() {
+        let mut guard = EthLogGuard::new(0);
+        guard.update(meta(10, "0"), EthLogEffect::RegisterUser { user_id: 1 });
+        guard.set_block_hash(10, "0xa").unwrap();
+        guard.update(meta(11, "0"), deposit(1));
+        guard.update(meta(11, "1"), deposit(2));
+        guard.set_block_hash(11, "0xb").unwrap();
+        guard.update(meta(12, "0"), deposit(3));
+        assert!(guard.set_block_hash(11, "0xc").is_err());
+        assert!(guard.revert(11, "0xc").is_err());
+
+        // block 11 and everything after it, latest first
+        let reverted: Vec<EthLogEffect> = guard.revert(11, "0xb").unwrap().into_iter().map(|log| log.effect).collect();
+        assert_eq!(reverted, vec![deposit(3), deposit(2), deposit(1)]);
+        assert!(!guard.accept(&meta(10, "0")));
+        assert!(guard.accept(&meta(11, "0")));
+        assert!(guard.revert(11, "0xb").unwrap().is_empty());
+
+        // blocks below the window are final
+        guard.update(meta(11 + REORG_WINDOW + 1, "0"), deposit(4));
+        assert!(guard.revert(10, "0xa").is_err());
+    }
+}
@@ -11,12 +11,14 @@ type TransferWriter = DatabaseWriter<models::InternalTx>;
 type UserWriter = DatabaseWriter<models::AccountDesc>;
 type OrderWriter = DatabaseWriter<models::OrderHistory>;
 type TradeWriter = DatabaseWriter<models::UserTrade>;
+type WithdrawWriter = DatabaseWriter<models::WithdrawHistory>;
 
 pub trait HistoryWriter: Sync + Send {
     fn is_block(&self) -> bool;
     //TODO: don't take the ownership?
     fn append_balance_history(&mut self, data: models::BalanceHistory);
     fn append_internal_transfer(&mut self, data: models::InternalTx);
+    fn append_withdraw_history(&mut self, data: models::WithdrawHistory);
     fn append_user(&mut self, user: models::AccountDesc);
     fn append_order_history(&mut self, order: &market::Order);
     fn append_expired_order_history(&mut self, _order: &market::Order);
@@ -27,6 +29,7 @@ pub struct DummyHistoryWriter;
 impl HistoryWriter for DummyHistoryWriter {
     fn append_balance_history(&mut self, _data: models::BalanceHistory) {}
     fn append_internal_transfer(&mut self, _data: models::InternalTx) {}
+    fn append_withdraw_history(&mut self, _data: models::WithdrawHistory) {}
     fn append_user(&mut self, _user: models::AccountDesc) {}
     fn append_order_history(&mut self, _order: &market::Order) {}
     fn append_expired_order_history(&mut self, _order: &market::Order) {}
@@ -42,6 +45,7 @@ pub struct DatabaseHistoryWriter {
     pub user_writer: UserWriter,
     pub trade_writer: TradeWriter,
     pub order_writer: OrderWriter,
+    pub withdraw_writer: WithdrawWriter,
 }
 
 impl DatabaseHistoryWriter {
@@ -52,6 +56,7 @@ impl DatabaseHistoryWriter {
             user_writer: UserWriter::new(config).start_schedule(pool)?,
             trade_writer: TradeWriter::new(config).start_schedule(pool)?,
             order_writer: OrderWriter::new(config).start_schedule(pool)?,
+            withdraw_writer: WithdrawWriter::new(config).start_schedule(pool)?,
         })
     }
 }
@@ -96,6 +101,9 @@ impl HistoryWriter for DatabaseHistoryWriter {
     fn append_internal_transfer(&mut self, data: models::InternalTx) {
         self.transfer_writer.append(data).ok();
     }
+    fn append_withdraw_history(&mut self, data: models::WithdrawHistory) {
+        self.withdraw_writer.append(data).ok();
+    }
     fn append_user(&mut self, user: models::AccountDesc) {
         self.user_writer.append(user).ok();
     }
@@ -0,0 +1,95 @@
+use crate::config::FeeTier;
+
+use std::collections::BTreeMap;
+
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+// the traded volume of this many days decides the tier
+pub const VOLUME_WINDOW_DAYS: u32 = 30;
+
+pub fn day_of(timestamp: f64) -> u32 {
+    (timestamp / 86400.0) as u32
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FeeRate {
+    pub taker_fee: Decimal,
+    pub maker_fee: Decimal,
+}
+
+impl FeeRate {
+    pub fn is_valid(&self) -> bool {
+        self.taker_fee.is_sign_positive() && self.maker_fee.is_sign_positive()
+    }
+}
+
+impl From<&FeeTier> for FeeRate {
+    fn from(tier: &FeeTier) -> Self {
+        FeeRate {
+            taker_fee: tier.taker_fee,
+            maker_fee: tier.maker_fee,
+        }
+    }
+}
+
+// The rate of a user is its override if there is one, else the highest tier
+// its traded quote volume in the window has reached, else the default.
+pub struct FeeSchedule {
+    pub default_rate: FeeRate,
+    // ascending by volume
+    pub tiers: Vec<FeeTier>,
+    pub overrides: BTreeMap<u32, FeeRate>,
+    // traded quote volume by user and day
+    pub volumes: BTreeMap<u32, BTreeMap<u32, Decimal>>,
+}
+
+impl FeeSchedule {
+    pub fn new(default_rate: FeeRate, mut tiers: Vec<FeeTier>) -> Self {
+        tiers.sort_by(|a, b| a.volume.cmp(&b.volume));
+        FeeSchedule {
+            default_rate,
+            tiers,
+            overrides: BTreeMap::new(),
+            volumes: BTreeMap::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.overrides.clear();
+        self.volumes.clear();
+    }
+
+    pub fn volume(&self, user_id: u32, now: f64) -> Decimal {
+        let first_day = (day_of(now) + 1).saturating_sub(VOLUME_WINDOW_DAYS);
+        self.volumes
+            .get(&user_id)
+            .map(|days| days.range(first_day..).fold(Decimal::zero(), |sum, (_, volume)| sum + volume))
+            .unwrap_or_else(Decimal::zero)
+    }
+
+    pub fn rate(&self, user_id: u32, now: f64) -> FeeRate {
+        if let Some(rate) = self.overrides.get(&user_id) {
+            return *rate;
+        }
+        let volume = self.volume(user_id, now);
+        self.tiers
+            .iter()
+            .rev()
+            .find(|tier| volume >= tier.volume)
+            .map(FeeRate::from)
+            .unwrap_or(self.default_rate)
+    }
+
+    pub fn add_volume(&mut self, user_id: u32, quote_amount: &Decimal, now: f64) {
+        let today = day_of(now);
+        let days = self.volumes.entry(user_id).or_insert_with(BTreeMap::new);
+        *days.entry(today).or_insert_with(Decimal::zero) += quote_amount;
+        // days out of the window are never counted again
+        let first_day = (today + 1).saturating_sub(VOLUME_WINDOW_DAYS);
+        if days.keys().next().map_or(false, |day| *day < first_day) {
+            *days = days.split_off(&first_day);
+        }
+    }
+}
@@ -1,16 +1,21 @@
 #![allow(clippy::if_same_then_else)]
 use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController, BalanceUpdateParams, BusinessType};
 use crate::config::{self, OrderSignatrueCheck};
+use crate::message::OrderBookDeltaMessage;
 use crate::persist::PersistExector;
 use crate::sequencer::Sequencer;
-use crate::types::{self, MarketRole, OrderEventType};
+use crate::types::{
+    self, CancelReason, MarketPhase, MarketRole, MatchingMode, OcoTrigger, OrderEventType, PegReference, SelfTradePrevention, TimeInForce,
+    TradingStatus,
+};
 
-use std::cmp::min;
-use std::collections::BTreeMap;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::iter::Iterator;
+use std::ops::Bound;
 
 use anyhow::{bail, Result};
-use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::prelude::{One, Zero};
 use fluidex_common::rust_decimal::{Decimal, RoundingStrategy};
 use fluidex_common::utils::timeutil::current_timestamp;
 use itertools::Itertools;
@@ -18,6 +23,8 @@ use serde::{Deserialize, Serialize};
 
 pub use types::{OrderSide, OrderType};
 
+mod fee;
+pub use fee::*;
 mod order;
 pub use order::*;
 mod trade;
@@ -37,13 +44,56 @@ pub struct Market {
 
     pub orders: BTreeMap<u64, OrderRc>,
     pub users: BTreeMap<u32, BTreeMap<u64, OrderRc>>,
+    // active orders which have a client order id, by user and client order id
+    pub client_orders: BTreeMap<u32, BTreeMap<u64, OrderRc>>,
+    // finished orders which have a client order id, by user and client order id, so that the id
+    // still finds the order and is not taken by another one
+    pub finished_client_orders: BTreeMap<u32, BTreeMap<u64, Order>>,
+    // the same orders by order id, the oldest are forgotten beyond `client_order_retention`
+    finished_client_order_ids: BTreeMap<u64, (u32, u64)>,
+    pub client_order_retention: usize,
+    // active orders which are in a group, by group id
+    pub groups: BTreeMap<u64, BTreeMap<u64, OrderRc>>,
+    // groups fired by the trades of the current execution, with the orders which fired them
+    triggered_groups: BTreeMap<u64, BTreeSet<u64>>,
+    // active orders which draw on the collateral pools
+    pub pooled_orders: BTreeMap<u64, OrderRc>,
+    // active orders which have an expire time
+    pub expiring_orders: BTreeMap<u64, OrderRc>,
+    // active pegged orders
+    pub pegged_orders: BTreeMap<u64, OrderRc>,
 
     pub asks: BTreeMap<MarketKeyAsk, OrderRc>,
     pub bids: BTreeMap<MarketKeyBid, OrderRc>,
 
+    // untriggered stop orders, keyed by trigger price in the order they fire
+    pub stop_asks: BTreeMap<MarketKeyBid, OrderRc>,
+    pub stop_bids: BTreeMap<MarketKeyAsk, OrderRc>,
+
+    // the sequence of the last orderbook delta, see `OrderBookDeltaMessage`
+    pub book_sequence: u64,
+    // price levels changed by the current operation, published once it is done
+    changed_asks: BTreeSet<Decimal>,
+    changed_bids: BTreeSet<Decimal>,
+
     pub trade_count: u64,
+    pub phase: MarketPhase,
+    pub trading_status: TradingStatus,
+    // set when the market is halted by a price band breach, it resumes after this time
+    pub halted_until: Option<f64>,
 
-    pub disable_self_trade: bool,
+    // see `config::Market`
+    pub price_band: Decimal,
+    pub halt_band: Decimal,
+    pub halt_cooldown: f64,
+    pub fee_schedule: FeeSchedule,
+    pub base_fee_receiver: u32,
+    pub quote_fee_receiver: u32,
+    // the default for orders which do not choose a mode
+    pub self_trade_prevention: SelfTradePrevention,
+    pub matching: MatchingMode,
+    pub pro_rata_top_order_share: Decimal,
+    pub pro_rata_min_allocation: Decimal,
     pub disable_market_order: bool,
     pub check_eddsa_signatue: OrderSignatrueCheck,
 }
@@ -80,6 +130,12 @@ impl BalanceManagerWrapper<'_> {
     pub fn asset_prec(&mut self, asset: &str) -> u32 {
         self.inner.asset_manager.asset_prec(asset)
     }
+    pub fn pool_get(&self, user_id: u32, asset: &str) -> Decimal {
+        self.inner.pool_get(user_id, asset)
+    }
+    pub fn pool_draw(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+        self.inner.pool_draw(user_id, asset, amount)
+    }
 }
 
 const MAP_INIT_CAPACITY: usize = 1024;
@@ -106,6 +162,28 @@ impl Market {
                 bail!("invalid fee precision");
             }
         }
+        let fee_schedule = FeeSchedule::new(
+            FeeRate {
+                taker_fee: market_conf.taker_fee,
+                maker_fee: market_conf.maker_fee,
+            },
+            market_conf.fee_tiers.clone(),
+        );
+        for rate in std::iter::once(fee_schedule.default_rate).chain(fee_schedule.tiers.iter().map(FeeRate::from)) {
+            if !rate.is_valid() {
+                bail!("invalid fee schedule");
+            }
+            // fee_prec == 0 means no fee allowed
+            if market_conf.fee_prec == 0 && (!rate.taker_fee.is_zero() || !rate.maker_fee.is_zero()) {
+                bail!("only 0 fee is supported now");
+            }
+        }
+        if market_conf.pro_rata_top_order_share < Decimal::zero()
+            || market_conf.pro_rata_top_order_share > Decimal::one()
+            || market_conf.pro_rata_min_allocation < Decimal::zero()
+        {
+            bail!("invalid pro-rata parameters");
+        }
         let leak_fn = |x: &str| -> &'static str { Box::leak(x.to_string().into_boxed_str()) };
         let market = Market {
             name: leak_fn(&market_conf.name),
@@ -120,10 +198,40 @@ impl Market {
             price: Decimal::zero(),
             orders: BTreeMap::new(),
             users: BTreeMap::new(),
+            client_orders: BTreeMap::new(),
+            finished_client_orders: BTreeMap::new(),
+            finished_client_order_ids: BTreeMap::new(),
+            client_order_retention: global_settings.client_order_retention,
+            groups: BTreeMap::new(),
+            triggered_groups: BTreeMap::new(),
+            pooled_orders: BTreeMap::new(),
+            expiring_orders: BTreeMap::new(),
+            pegged_orders: BTreeMap::new(),
             asks: BTreeMap::new(),
             bids: BTreeMap::new(),
+            stop_asks: BTreeMap::new(),
+            stop_bids: BTreeMap::new(),
+            book_sequence: 0,
+            changed_asks: BTreeSet::new(),
+            changed_bids: BTreeSet::new(),
             trade_count: 0,
-            disable_self_trade: global_settings.disable_self_trade,
+            phase: MarketPhase::CONTINUOUS,
+            trading_status: TradingStatus::OPEN,
+            halted_until: None,
+            price_band: market_conf.price_band,
+            halt_band: market_conf.halt_band,
+            halt_cooldown: market_conf.halt_cooldown as f64,
+            fee_schedule,
+            base_fee_receiver: global_settings.fee_receiver(&market_conf.base),
+            quote_fee_receiver: global_settings.fee_receiver(&market_conf.quote),
+            self_trade_prevention: market_conf.self_trade_prevention.unwrap_or(if global_settings.disable_self_trade {
+                SelfTradePrevention::CANCEL_NEWEST
+            } else {
+                SelfTradePrevention::NONE
+            }),
+            matching: market_conf.matching,
+            pro_rata_top_order_share: market_conf.pro_rata_top_order_share,
+            pro_rata_min_allocation: market_conf.pro_rata_min_allocation,
             disable_market_order: global_settings.disable_market_order,
             check_eddsa_signatue: global_settings.check_eddsa_signatue,
         };
@@ -134,9 +242,64 @@ impl Market {
         log::debug!("market {} reset", self.name);
         self.bids.clear();
         self.asks.clear();
+        self.stop_bids.clear();
+        self.stop_asks.clear();
+        self.changed_asks.clear();
+        self.changed_bids.clear();
+        self.book_sequence = 0;
         self.users.clear();
+        self.client_orders.clear();
+        self.finished_client_orders.clear();
+        self.finished_client_order_ids.clear();
+        self.groups.clear();
+        self.triggered_groups.clear();
+        self.pooled_orders.clear();
+        self.expiring_orders.clear();
+        self.pegged_orders.clear();
+        self.phase = MarketPhase::CONTINUOUS;
+        self.trading_status = TradingStatus::OPEN;
+        self.halted_until = None;
+        self.fee_schedule.reset();
         self.orders.clear();
     }
+
+    // whether a new order (or an amended one, which never trades) is accepted in the current status
+    pub fn check_order_accepted(&self, post_only: bool) -> Result<()> {
+        match self.trading_status {
+            TradingStatus::OPEN => Ok(()),
+            TradingStatus::POST_ONLY if post_only => Ok(()),
+            TradingStatus::POST_ONLY => bail!("market only accepts post only orders"),
+            TradingStatus::CANCEL_ONLY => bail!("market only accepts cancellations"),
+            TradingStatus::HALTED => bail!("market is halted"),
+        }
+    }
+    pub fn set_trading_status(&mut self, status: TradingStatus) {
+        self.trading_status = status;
+        self.halted_until = None;
+    }
+    // whether an automatical halt has cooled down
+    pub fn is_halt_expired(&self, now: f64) -> bool {
+        matches!(self.halted_until, Some(halted_until) if halted_until <= now)
+    }
+    fn auto_halt(&mut self, time: f64) {
+        log::warn!(
+            "market {} is halted for {} seconds by a price band breach",
+            self.name,
+            self.halt_cooldown
+        );
+        self.trading_status = TradingStatus::HALTED;
+        self.halted_until = Some(time + self.halt_cooldown);
+    }
+    // the band is a ratio around the reference price, no check before the first trade
+    fn is_within_band(reference: &Decimal, band: &Decimal, price: &Decimal) -> bool {
+        band.is_zero() || reference.is_zero() || (*price - *reference).abs() <= *reference * *band
+    }
+    pub fn check_cancel_accepted(&self) -> Result<()> {
+        if self.trading_status == TradingStatus::HALTED {
+            bail!("market is halted");
+        }
+        Ok(())
+    }
     pub fn frozen_balance(&self, balance_manager: &mut BalanceManagerWrapper<'_>, order: &Order) {
         let asset = if order.is_ask() { &self.base } else { &self.quote };
 
@@ -159,7 +322,91 @@ impl Market {
         persistor: &mut impl PersistExector,
         order_input: OrderInput,
     ) -> Result<Order> {
-        if order_input.type_ == OrderType::MARKET && self.disable_market_order {
+        let time = order_input.time;
+        let result = self.place_order(
+            sequencer,
+            &mut balance_manager,
+            balance_update_controller,
+            persistor,
+            order_input,
+            None,
+        );
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
+        result
+    }
+
+    // Place orders which are cancelled together once one of them is filled, see `Order::fires_group`.
+    // The orders can only rest in the books when they are placed, so none of them trades before
+    // the group is complete. Either all the orders are placed or none of them.
+    pub fn put_order_group(
+        &mut self,
+        sequencer: &mut Sequencer,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+        order_inputs: Vec<OrderInput>,
+        oco_trigger: OcoTrigger,
+    ) -> Result<Vec<Order>> {
+        if order_inputs.len() < 2 {
+            bail!("an order group needs at least two orders");
+        }
+        if order_inputs.iter().any(|input| input.user_id != order_inputs[0].user_id) {
+            bail!("orders of a group must belong to the same user");
+        }
+        // the id the first order is going to take
+        let group_id = sequencer.get_order_id() + 1;
+        let time = order_inputs[0].time;
+        let mut orders = Vec::with_capacity(order_inputs.len());
+        for order_input in order_inputs {
+            match self.place_order(
+                sequencer,
+                &mut balance_manager,
+                balance_update_controller,
+                persistor,
+                order_input,
+                Some((group_id, oco_trigger)),
+            ) {
+                Ok(order) => orders.push(order),
+                Err(e) => {
+                    // nothing has traded, so the placed orders are simply taken back,
+                    // and their client order ids can be used again
+                    for order in orders {
+                        let order = Order {
+                            cancel_reason: Some(CancelReason::ORDER_GROUP),
+                            ..order
+                        };
+                        self.detach_order(&mut balance_manager, &order);
+                        persistor.put_order(&order, OrderEventType::FINISH);
+                    }
+                    self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
+                    return Err(e);
+                }
+            }
+        }
+        debug_assert_eq!(orders[0].id, group_id);
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
+        Ok(orders)
+    }
+
+    fn place_order(
+        &mut self,
+        sequencer: &mut Sequencer,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+        mut order_input: OrderInput,
+        group: Option<(u64, OcoTrigger)>,
+    ) -> Result<Order> {
+        // a resubmitted order is not placed again, even if it has finished
+        if let Some(order) = self.get_by_client_order_id(order_input.user_id, order_input.client_order_id) {
+            if group.is_some() {
+                bail!("client order id {} is in use", order_input.client_order_id);
+            }
+            return Ok(order);
+        }
+        let is_stop_order = order_input.type_ == OrderType::STOP_LIMIT || order_input.type_ == OrderType::STOP_MARKET;
+        let is_market_order = order_input.type_ == OrderType::MARKET || order_input.type_ == OrderType::STOP_MARKET;
+        if is_market_order && self.disable_market_order {
             bail!("market orders disabled");
         }
         if order_input.amount.lt(&self.min_amount) {
@@ -175,25 +422,144 @@ impl Market {
         if amount != order_input.amount {
             bail!("invalid amount precision");
         }
+        if order_input.peg != PegReference::NONE {
+            if order_input.type_ != OrderType::LIMIT || order_input.time_in_force != TimeInForce::GTC {
+                bail!("pegged orders must be good till cancelled limit orders");
+            }
+            if self.phase == MarketPhase::AUCTION {
+                bail!("pegged orders are not accepted during the auction");
+            }
+            if !order_input.price.is_zero() {
+                bail!("pegged order should not have a price");
+            }
+            if order_input.peg_offset.round_dp(self.price_prec) != order_input.peg_offset {
+                bail!("invalid peg offset precision");
+            }
+            order_input.price = match self.peg_price(order_input.side, order_input.peg, &order_input.peg_offset) {
+                Some(price) => price,
+                None => bail!("no price to peg to"),
+            };
+        } else if !order_input.peg_offset.is_zero() {
+            bail!("only pegged orders can have a peg offset");
+        }
         let price = order_input.price.round_dp(self.price_prec);
         if price != order_input.price {
             bail!("invalid price precision");
         }
-        if order_input.type_ == OrderType::MARKET {
+        if is_market_order {
             if !order_input.price.is_zero() {
                 bail!("market order should not have a price");
             }
             if order_input.post_only {
                 bail!("market order cannot be post only");
             }
-            if order_input.side == OrderSide::ASK && self.bids.is_empty() || order_input.side == OrderSide::BID && self.asks.is_empty() {
+            // a stop market order checks the counter orders when it is triggered
+            if !is_stop_order
+                && (order_input.side == OrderSide::ASK && self.bids.is_empty()
+                    || order_input.side == OrderSide::BID && self.asks.is_empty())
+            {
                 bail!("no counter orders");
             }
         } else if order_input.price.is_zero() {
             bail!("invalid price for limit order");
+        } else if !is_stop_order && !Self::is_within_band(&self.price, &self.price_band, &order_input.price) {
+            bail!("price is out of the band around {}", self.price);
+        }
+        if is_stop_order {
+            if !order_input.trigger_price.is_sign_positive() || order_input.trigger_price.is_zero() {
+                bail!("invalid trigger price");
+            }
+            if order_input.trigger_price.round_dp(self.price_prec) != order_input.trigger_price {
+                bail!("invalid trigger price precision");
+            }
+            if self.is_stop_triggered(order_input.side, &order_input.trigger_price) {
+                bail!("stop order would be triggered immediately");
+            }
+        } else if !order_input.trigger_price.is_zero() {
+            bail!("only stop orders can have a trigger price");
+        }
+        if self.phase == MarketPhase::AUCTION {
+            if is_market_order {
+                bail!("market orders are not accepted during the auction");
+            }
+            if order_input.time_in_force != TimeInForce::GTC {
+                bail!("orders must be good till cancelled during the auction");
+            }
+        }
+        if order_input.time_in_force != TimeInForce::GTC {
+            if is_market_order {
+                bail!("time in force is only supported for limit orders");
+            }
+            if order_input.post_only {
+                bail!("post only order must be good till cancelled");
+            }
+        }
+        if !order_input.display_amount.is_zero() {
+            if order_input.type_ != OrderType::LIMIT || order_input.time_in_force != TimeInForce::GTC {
+                bail!("iceberg orders must be good till cancelled limit orders");
+            }
+            if order_input.display_amount < self.min_amount
+                || order_input.display_amount >= order_input.amount
+                || order_input
+                    .display_amount
+                    .round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero)
+                    != order_input.display_amount
+            {
+                bail!("invalid display amount");
+            }
+        }
+        if order_input.shared_collateral && (order_input.type_ != OrderType::LIMIT || order_input.time_in_force != TimeInForce::GTC) {
+            bail!("only good till cancelled limit orders can use shared collateral");
+        }
+        if order_input.expire_time != 0.0 {
+            if !order_input.expire_time.is_finite() || order_input.expire_time < 0.0 {
+                bail!("invalid expire time");
+            }
+            if is_market_order && !is_stop_order || order_input.time_in_force != TimeInForce::GTC {
+                bail!("only good till cancelled limit or stop orders can expire");
+            }
+        }
+        if group.is_some() {
+            if is_market_order && !is_stop_order || order_input.time_in_force != TimeInForce::GTC {
+                bail!("orders of a group must be good till cancelled limit or stop orders");
+            }
+            if !is_stop_order && self.would_cross(order_input.side, &order_input.price) {
+                bail!("orders of a group cannot trade when placed");
+            }
+        }
+
+        let self_trade_prevention = match order_input.self_trade_prevention {
+            Some(SelfTradePrevention::NONE) if self.self_trade_prevention != SelfTradePrevention::NONE => {
+                bail!("self trade is not allowed in this market");
+            }
+            Some(mode) => mode,
+            None => self.self_trade_prevention,
+        };
+
+        let t = order_input.time;
+        // the rates come from the schedule, a rate given by the client is the highest one it accepts
+        let fee_rate = self.fee_schedule.rate(order_input.user_id, t);
+        if !order_input.taker_fee.is_zero() && order_input.taker_fee < fee_rate.taker_fee
+            || !order_input.maker_fee.is_zero() && order_input.maker_fee < fee_rate.maker_fee
+        {
+            bail!(
+                "fee rates of the user are taker {} and maker {}",
+                fee_rate.taker_fee,
+                fee_rate.maker_fee
+            );
         }
 
-        if order_input.side == OrderSide::ASK {
+        if order_input.shared_collateral {
+            let (asset, need) = if order_input.side == OrderSide::ASK {
+                (self.base, order_input.amount)
+            } else {
+                (self.quote, order_input.amount * order_input.price)
+            };
+            let pool = balance_manager.pool_get(order_input.user_id, asset);
+            if pool.lt(&need) {
+                bail!("shared collateral not enough: pool({}) < {}", &pool, &need);
+            }
+        } else if order_input.side == OrderSide::ASK {
             if balance_manager
                 .balance_get(order_input.user_id, BalanceType::AVAILABLE, self.base)
                 .lt(&order_input.amount)
@@ -203,7 +569,20 @@ impl Market {
         } else {
             let balance = balance_manager.balance_get(order_input.user_id, BalanceType::AVAILABLE, self.quote);
 
-            if order_input.type_ == OrderType::LIMIT {
+            if order_input.type_ == OrderType::STOP_MARKET {
+                // the quote to be spent is frozen until the order is triggered,
+                // so it has to be given explicitly
+                if order_input.quote_limit.is_zero() {
+                    bail!("stop market bid order needs a quote limit");
+                }
+                if balance.lt(&order_input.quote_limit) {
+                    bail!(
+                        "balance not enough: balance({}) < quote_limit({})",
+                        &balance,
+                        &order_input.quote_limit
+                    );
+                }
+            } else if !is_market_order {
                 if balance.lt(&(order_input.amount * order_input.price)) {
                     bail!(
                         "balance not enough: balance({}) < amount({}) * price({})",
@@ -240,46 +619,420 @@ impl Market {
                         .round_dp_with_strategy(balance_manager.asset_prec(self.quote), RoundingStrategy::ToZero),
                 )
             }
+        } else if order_input.type_ == OrderType::STOP_MARKET && order_input.side == OrderSide::BID {
+            order_input
+                .quote_limit
+                .round_dp_with_strategy(balance_manager.asset_prec(self.quote), RoundingStrategy::ToZero)
         } else {
             // not used
             Decimal::zero()
         };
 
-        let t = current_timestamp();
+        // a stop order is checked when it is triggered
+        if order_input.time_in_force == TimeInForce::FOK
+            && !is_stop_order
+            && !self.can_fill_completely(
+                balance_manager,
+                order_input.user_id,
+                order_input.side,
+                &order_input.price,
+                &order_input.amount,
+                self_trade_prevention,
+            )
+        {
+            bail!("fill or kill order cannot be fully filled");
+        }
+
+        let id = sequencer.next_order_id();
         let order = Order {
-            id: sequencer.next_order_id(),
+            id,
             type_: order_input.type_,
             side: order_input.side,
             create_time: t,
             update_time: t,
+            priority: sequencer.next_priority(),
             market: self.name.into(),
             base: self.base.into(),
             quote: self.quote.into(),
             user: order_input.user_id,
+            client_order_id: order_input.client_order_id,
             price: order_input.price,
             amount: order_input.amount,
-            taker_fee: order_input.taker_fee,
-            maker_fee: order_input.maker_fee,
+            display_amount: order_input.display_amount,
+            trigger_price: order_input.trigger_price,
+            taker_fee: fee_rate.taker_fee,
+            maker_fee: fee_rate.maker_fee,
             remain: order_input.amount,
             frozen: Decimal::zero(),
             finished_base: Decimal::zero(),
             finished_quote: Decimal::zero(),
             finished_fee: Decimal::zero(),
             post_only: order_input.post_only,
+            time_in_force: order_input.time_in_force,
+            self_trade_prevention,
+            group_id: group.map_or(0, |(group_id, _)| group_id),
+            oco_trigger: group.map(|(_, oco_trigger)| oco_trigger).unwrap_or_default(),
+            shared_collateral: order_input.shared_collateral,
+            expire_time: order_input.expire_time,
+            peg: order_input.peg,
+            peg_offset: order_input.peg_offset,
             signature: order_input.signature,
+            cancel_reason: None,
+            // the first part of an iceberg order, it only trades as a maker
+            visible: order_input.display_amount,
         };
+        // the the older version, PUT means being inserted into orderbook
+        // so if an order is matched instantly, only 'FINISH' event will occur, no 'PUT' event
+        // now PUT means being created
+        // we can revisit this decision later
+        persistor.put_order(&order, OrderEventType::PUT);
+        if is_stop_order {
+            // a stop market bid freezes its quote limit, see `insert_order_into_trigger_book`
+            let order = self.insert_order_into_orderbook(Order {
+                frozen: quote_limit,
+                ..order
+            });
+            self.frozen_balance(balance_manager, &order);
+            return Ok(order);
+        }
+        if self.phase == MarketPhase::AUCTION {
+            // matched when the auction ends
+            let order = self.insert_order_into_orderbook(order);
+            self.touch_level(&order);
+            self.frozen_balance(balance_manager, &order);
+            return Ok(order);
+        }
         let order = self.execute_order(
             sequencer,
-            &mut balance_manager,
+            balance_manager,
             balance_update_controller,
             persistor,
             order,
             &quote_limit,
+            t,
         );
+        self.trigger_stop_orders(sequencer, balance_manager, balance_update_controller, persistor, t);
         Ok(order)
     }
 
-    // the last parameter `quote_limit`, is only used for market bid order,
+    // whether a limit order at the price would trade right away
+    fn would_cross(&self, side: OrderSide, price: &Decimal) -> bool {
+        self.phase == MarketPhase::CONTINUOUS
+            && if side == OrderSide::ASK {
+                self.bids.values().next().map_or(false, |bid| bid.borrow().price >= *price)
+            } else {
+                self.asks.values().next().map_or(false, |ask| ask.borrow().price <= *price)
+            }
+    }
+
+    fn is_stop_triggered(&self, side: OrderSide, trigger_price: &Decimal) -> bool {
+        // no trade has happened yet
+        if self.price.is_zero() {
+            return false;
+        }
+        match side {
+            OrderSide::ASK => self.price <= *trigger_price,
+            OrderSide::BID => self.price >= *trigger_price,
+        }
+    }
+
+    fn next_triggered_stop_order(&self) -> Option<Order> {
+        let is_triggered = |order: &Order| self.is_stop_triggered(order.side, &order.trigger_price);
+        self.stop_bids
+            .values()
+            .next()
+            .map(OrderRc::deep)
+            .filter(is_triggered)
+            .or_else(|| self.stop_asks.values().next().map(OrderRc::deep).filter(is_triggered))
+    }
+
+    // Stop orders are checked against `self.price` after each execution. A triggered order
+    // is executed as an ordinary order, which may move the price and trigger more stop orders.
+    fn trigger_stop_orders(
+        &mut self,
+        sequencer: &mut Sequencer,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+        time: f64,
+    ) {
+        while let Some(mut order) = self.next_triggered_stop_order() {
+            // the rest are triggered after the market resumes
+            if self.trading_status == TradingStatus::HALTED {
+                break;
+            }
+            log::debug!("stop order triggered {:?}", order);
+            self.detach_order(balance_manager, &order);
+            let quote_limit = if order.type_ == OrderType::STOP_MARKET && !order.is_ask() {
+                order.frozen
+            } else {
+                Decimal::zero()
+            };
+            order.type_ = if order.type_ == OrderType::STOP_LIMIT {
+                OrderType::LIMIT
+            } else {
+                OrderType::MARKET
+            };
+            order.frozen = Decimal::zero();
+            order.update_time = time;
+            persistor.put_order(&order, OrderEventType::TRIGGERED);
+            if order.time_in_force == TimeInForce::FOK
+                && !self.can_fill_completely(
+                    balance_manager,
+                    order.user,
+                    order.side,
+                    &order.price,
+                    &order.remain,
+                    order.self_trade_prevention,
+                )
+            {
+                order.cancel_reason = Some(CancelReason::FILL_OR_KILL);
+                self.put_finished_order(persistor, &order, OrderEventType::FINISH);
+                continue;
+            }
+            self.execute_order(
+                sequencer,
+                balance_manager,
+                balance_update_controller,
+                persistor,
+                order,
+                &quote_limit,
+                time,
+            );
+        }
+    }
+
+    pub fn start_auction(&mut self) -> Result<()> {
+        if self.phase == MarketPhase::AUCTION {
+            bail!("market is already in the auction");
+        }
+        self.phase = MarketPhase::AUCTION;
+        Ok(())
+    }
+
+    // Uncross the collected orders at the auction price and switch to continuous trading.
+    // Returns the auction price, `None` if no orders crossed.
+    pub fn end_auction(
+        &mut self,
+        sequencer: &mut Sequencer,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+        time: f64,
+    ) -> Result<Option<Decimal>> {
+        if self.phase != MarketPhase::AUCTION {
+            bail!("market is not in the auction");
+        }
+        let auction_price = self.auction_price().map(|(price, _)| price);
+        if let Some(price) = auction_price {
+            self.uncross(sequencer, &mut balance_manager, balance_update_controller, persistor, price, time);
+        }
+        self.phase = MarketPhase::CONTINUOUS;
+        self.trigger_stop_orders(sequencer, &mut balance_manager, balance_update_controller, persistor, time);
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
+        Ok(auction_price)
+    }
+
+    // The price which executes the largest volume of the collected orders, together with that volume.
+    // Ties are broken by the smaller imbalance left at the price, then by the distance to the last price.
+    pub fn auction_price(&self) -> Option<(Decimal, Decimal)> {
+        // total remain of the bids at or above each bid price, and of the asks at or below each ask price
+        let mut bid_volumes = BTreeMap::new();
+        let mut volume = Decimal::zero();
+        for bid in self.bids.values() {
+            let bid = bid.borrow();
+            volume += bid.remain;
+            bid_volumes.insert(bid.price, volume);
+        }
+        let mut ask_volumes = BTreeMap::new();
+        let mut volume = Decimal::zero();
+        for ask in self.asks.values() {
+            let ask = ask.borrow();
+            volume += ask.remain;
+            ask_volumes.insert(ask.price, volume);
+        }
+
+        // (price, volume, imbalance)
+        let mut best: Option<(Decimal, Decimal, Decimal)> = None;
+        for price in bid_volumes.keys().chain(ask_volumes.keys()) {
+            let bid_volume = bid_volumes.range(price..).next().map_or(Decimal::zero(), |(_, volume)| *volume);
+            let ask_volume = ask_volumes
+                .range(..=price)
+                .next_back()
+                .map_or(Decimal::zero(), |(_, volume)| *volume);
+            let volume = min(bid_volume, ask_volume);
+            if volume.is_zero() {
+                continue;
+            }
+            let imbalance = (bid_volume - ask_volume).abs();
+            let is_better = match best {
+                None => true,
+                Some((best_price, best_volume, best_imbalance)) => {
+                    volume > best_volume
+                        || volume == best_volume
+                            && (imbalance < best_imbalance
+                                || imbalance == best_imbalance && (*price - self.price).abs() < (best_price - self.price).abs())
+                }
+            };
+            if is_better {
+                best = Some((*price, volume, imbalance));
+            }
+        }
+        best.map(|(price, volume, _)| (price, volume))
+    }
+
+    // match the best bid and the best ask at the auction price until they no longer cross it
+    fn uncross(
+        &mut self,
+        sequencer: &mut Sequencer,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+        price: Decimal,
+        time: f64,
+    ) {
+        loop {
+            let (mut bid_rc, mut ask_rc) = match (self.bids.values().next(), self.asks.values().next()) {
+                (Some(bid_rc), Some(ask_rc)) => (bid_rc.clone(), ask_rc.clone()),
+                _ => break,
+            };
+            let mut bid = bid_rc.deep();
+            let mut ask = ask_rc.deep();
+            if bid.price < price || ask.price > price {
+                break;
+            }
+            if let Some(mut order) = [bid, ask].into_iter().find(|order| self.is_cancelled_by_group(order)) {
+                order.cancel_reason = Some(CancelReason::ORDER_GROUP);
+                self.order_finish(balance_manager, persistor, &order);
+                continue;
+            }
+            if bid.user == ask.user
+                && (bid.self_trade_prevention != SelfTradePrevention::NONE || ask.self_trade_prevention != SelfTradePrevention::NONE)
+            {
+                // there is no taker in the auction, the later order is cancelled
+                let mut newer = if bid.priority > ask.priority { bid } else { ask };
+                newer.cancel_reason = Some(CancelReason::SELF_TRADE);
+                self.order_finish(balance_manager, persistor, &newer);
+                continue;
+            }
+
+            let exhausted = [bid, ask]
+                .into_iter()
+                .find(|order| order.shared_collateral && self.pool_capacity(balance_manager, order, &price).is_zero());
+            if let Some(mut order) = exhausted {
+                order.cancel_reason = Some(CancelReason::COLLATERAL_EXHAUSTED);
+                self.order_finish(balance_manager, persistor, &order);
+                continue;
+            }
+
+            let mut amount = min(bid.remain, ask.remain);
+            for order in [&bid, &ask] {
+                if order.shared_collateral {
+                    amount = min(amount, self.pool_capacity(balance_manager, order, &price));
+                }
+            }
+            self.settle_trade(
+                sequencer,
+                balance_manager,
+                balance_update_controller,
+                persistor,
+                (&mut ask, MarketRole::MAKER),
+                (&mut bid, MarketRole::MAKER),
+                price,
+                amount,
+                time,
+            );
+            if !ask.shared_collateral {
+                ask.frozen -= amount;
+            }
+            if !bid.shared_collateral {
+                // the bid has frozen quote at its own price, the difference is given back
+                bid.frozen -= amount * bid.price;
+                let refund = amount * (bid.price - price);
+                if !refund.is_zero() {
+                    balance_manager.balance_unfrozen(bid.user, self.quote, &refund);
+                }
+            }
+
+            for (order_rc, mut order) in [(&mut bid_rc, bid), (&mut ask_rc, ask)] {
+                if order.is_iceberg() {
+                    order.visible = min(order.display_amount, order.remain);
+                }
+                if order.remain.is_zero() {
+                    self.order_finish(balance_manager, persistor, &order);
+                } else {
+                    *order_rc.borrow_mut() = order;
+                    self.touch_level(&order);
+                    persistor.put_order(&order, OrderEventType::UPDATE);
+                }
+            }
+        }
+        self.cancel_triggered_groups(balance_manager, persistor);
+    }
+
+    // walk the counter orders like `execute_order` does, without changing anything
+    fn can_fill_completely(
+        &self,
+        balance_manager: &BalanceManagerWrapper<'_>,
+        user_id: u32,
+        side: OrderSide,
+        price: &Decimal,
+        amount: &Decimal,
+        self_trade_prevention: SelfTradePrevention,
+    ) -> bool {
+        let counter_orders: Box<dyn Iterator<Item = &OrderRc>> = if side == OrderSide::ASK {
+            Box::new(self.bids.values())
+        } else {
+            Box::new(self.asks.values())
+        };
+        let maker_asset = if side == OrderSide::ASK { self.quote } else { self.base };
+        // what the makers on shared collateral would have drawn from their pools so far
+        let mut drawn: BTreeMap<u32, Decimal> = BTreeMap::new();
+        let mut remain = *amount;
+        for maker_ref in counter_orders {
+            let maker = maker_ref.borrow();
+            let crossed = if side == OrderSide::ASK {
+                maker.price >= *price
+            } else {
+                maker.price <= *price
+            };
+            if !crossed {
+                break;
+            }
+            if !Self::is_within_band(&self.price, &self.halt_band, &maker.price)
+                || !Self::is_within_band(&self.price, &self.price_band, &maker.price)
+            {
+                break;
+            }
+            if maker.user == user_id {
+                match self_trade_prevention {
+                    SelfTradePrevention::NONE => {}
+                    // the maker would be cancelled
+                    SelfTradePrevention::CANCEL_OLDEST => continue,
+                    _ => break,
+                }
+            }
+            let mut traded = min(remain, maker.remain);
+            if maker.shared_collateral {
+                let drawn = drawn.entry(maker.user).or_insert_with(Decimal::zero);
+                let pool = balance_manager.pool_get(maker.user, maker_asset) - *drawn;
+                let capacity = if maker.is_ask() {
+                    pool
+                } else {
+                    (pool / maker.price).round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero)
+                };
+                traded = min(traded, capacity);
+                *drawn += if maker.is_ask() { traded } else { traded * maker.price };
+            }
+            remain -= traded;
+            if remain.is_zero() {
+                return true;
+            }
+        }
+        false
+    }
+
+    // the parameter `quote_limit`, is only used for market bid order,
     // it indicates the `quote` balance of the user,
     // so the sum of all the trades' quote amount cannot exceed this value
     fn execute_order(
@@ -290,72 +1043,150 @@ impl Market {
         persistor: &mut impl PersistExector,
         mut taker: Order,
         quote_limit: &Decimal,
+        time: f64,
     ) -> Order {
         log::debug!("execute_order {:?}", taker);
-
-        // the the older version, PUT means being inserted into orderbook
-        // so if an order is matched instantly, only 'FINISH' event will occur, no 'PUT' event
-        // now PUT means being created
-        // we can revisit this decision later
-        persistor.put_order(&taker, OrderEventType::PUT);
+        debug_assert!(!taker.is_stop_order());
 
         let taker_is_ask = taker.side == OrderSide::ASK;
         let taker_is_bid = !taker_is_ask;
         let maker_is_bid = taker_is_ask;
-        let maker_is_ask = !maker_is_bid;
         let is_limit_order = taker.type_ == OrderType::LIMIT;
         let is_market_order = !is_limit_order;
         let is_post_only_order = taker.post_only;
+        // the mode of the taker decides
+        let self_trade_prevention = taker.self_trade_prevention;
 
         let mut quote_sum = Decimal::zero();
-
-        let mut finished_orders = Vec::new();
-
-        let counter_orders: Box<dyn Iterator<Item = &mut OrderRc>> = if maker_is_bid {
-            Box::new(self.bids.values_mut())
-        } else {
-            Box::new(self.asks.values_mut())
-        };
+        // the bands are fixed for the whole execution, so an order cannot drag them along
+        let reference_price = self.price;
 
         // TODO: find a more elegant way to handle this
-        let mut need_cancel = false;
-        for maker_ref in counter_orders {
-            // Step1: get ask and bid
-            let mut maker = maker_ref.borrow_mut();
-            if taker.remain.is_zero() {
-                break;
-            }
-            let (ask_fee_rate, bid_fee_rate) = if taker_is_ask {
-                (taker.taker_fee, maker.maker_fee)
-            } else {
-                (maker.maker_fee, taker.taker_fee)
-            };
-            // of course, price should be counter order price
-            let price = maker.price;
-            let (ask_order, bid_order) = if taker_is_ask {
-                (&mut taker, &mut *maker)
-            } else {
-                (&mut *maker, &mut taker)
+        let mut cancel_reason = None;
+        // pro-rata only: what is left of the allocation of the current level, see `allocate_level`
+        let mut allocations = VecDeque::new();
+        while !taker.remain.is_zero() {
+            // Step1: get the best counter order
+            let (mut maker_rc, allocation) = match self.matching {
+                MatchingMode::PRICE_TIME => {
+                    let best_counter_order = if maker_is_bid {
+                        self.bids.values().next()
+                    } else {
+                        self.asks.values().next()
+                    };
+                    match best_counter_order {
+                        Some(maker_rc) => (maker_rc.clone(), None),
+                        None => break,
+                    }
+                }
+                MatchingMode::PRO_RATA => {
+                    if allocations.is_empty() {
+                        let quote_limit = if taker_is_bid && is_market_order {
+                            Some(quote_limit - quote_sum)
+                        } else {
+                            None
+                        };
+                        allocations = self.allocate_level(maker_is_bid, &taker.remain, quote_limit);
+                    }
+                    let (order_id, allocation) = match allocations.pop_front() {
+                        Some(allocation) => allocation,
+                        None => break,
+                    };
+                    match self.orders.get(&order_id) {
+                        Some(maker_rc) => (maker_rc.clone(), Some(allocation)),
+                        // cancelled since the level was allocated
+                        None => continue,
+                    }
+                }
             };
-            //let ask_order_id: u64 = ask_order.id;
-            //let bid_order_id: u64 = bid_order.id;
+            let mut maker = maker_rc.deep();
 
             // Step2: abort if needed
-            if is_limit_order && ask_order.price.gt(&bid_order.price) {
+            if is_limit_order && (taker_is_ask && taker.price.gt(&maker.price) || taker_is_bid && taker.price.lt(&maker.price)) {
                 break;
             }
-            // new trade will be generated
             if is_post_only_order {
-                need_cancel = true;
+                cancel_reason = Some(CancelReason::POST_ONLY);
+                break;
+            }
+            if self.is_cancelled_by_group(&maker) {
+                maker.cancel_reason = Some(CancelReason::ORDER_GROUP);
+                self.order_finish(&mut *balance_manager, persistor, &maker);
+                continue;
+            }
+            if !Self::is_within_band(&reference_price, &self.halt_band, &maker.price) {
+                self.auto_halt(time);
                 break;
             }
-            if ask_order.user == bid_order.user && self.disable_self_trade {
-                need_cancel = true;
+            if !Self::is_within_band(&reference_price, &self.price_band, &maker.price) {
                 break;
             }
+            if maker.user == taker.user && self_trade_prevention != SelfTradePrevention::NONE {
+                let (cancel_taker, cancel_maker) = match self_trade_prevention {
+                    SelfTradePrevention::CANCEL_OLDEST => (false, true),
+                    SelfTradePrevention::CANCEL_BOTH => (true, true),
+                    SelfTradePrevention::DECREMENT_AND_CANCEL => {
+                        let decrement = min(taker.remain, maker.remain);
+                        // `amount` shrinks with `remain`, so `remain + finished_base == amount` still holds
+                        taker.amount -= decrement;
+                        taker.remain -= decrement;
+                        maker.amount -= decrement;
+                        maker.remain -= decrement;
+                        if !maker.shared_collateral {
+                            let unfrozen = if maker_is_bid { decrement * maker.price } else { decrement };
+                            maker.frozen -= unfrozen;
+                            balance_manager.balance_unfrozen(maker.user, if maker_is_bid { self.quote } else { self.base }, &unfrozen);
+                        }
+                        maker.update_time = time;
+                        if maker.is_iceberg() {
+                            maker.visible = min(maker.visible, maker.remain);
+                        }
+                        if !maker.remain.is_zero() {
+                            *maker_rc.borrow_mut() = maker;
+                            self.touch_level(&maker);
+                            persistor.put_order(&maker, OrderEventType::UPDATE);
+                        }
+                        (taker.remain.is_zero(), maker.remain.is_zero())
+                    }
+                    _ => (true, false),
+                };
+                if cancel_maker {
+                    maker.cancel_reason = Some(CancelReason::SELF_TRADE);
+                    self.order_finish(&mut *balance_manager, persistor, &maker);
+                }
+                if cancel_taker {
+                    cancel_reason = Some(CancelReason::SELF_TRADE);
+                    break;
+                }
+                continue;
+            }
+            // of course, price should be counter order price
+            let price = maker.price;
 
             // Step3: get trade amount
-            let mut traded_base_amount = min(ask_order.remain, bid_order.remain);
+            // an iceberg maker trades its visible part at a time
+            let mut traded_base_amount = min(taker.remain, maker.visible_remain());
+            if let Some(allocation) = allocation {
+                traded_base_amount = min(traded_base_amount, allocation);
+            }
+            // an order on shared collateral trades no more than its pool can pay for
+            if maker.shared_collateral {
+                let capacity = self.pool_capacity(balance_manager, &maker, &price);
+                if capacity.is_zero() {
+                    maker.cancel_reason = Some(CancelReason::COLLATERAL_EXHAUSTED);
+                    self.order_finish(&mut *balance_manager, persistor, &maker);
+                    continue;
+                }
+                traded_base_amount = min(traded_base_amount, capacity);
+            }
+            if taker.shared_collateral {
+                let capacity = self.pool_capacity(balance_manager, &taker, &price);
+                if capacity.is_zero() {
+                    cancel_reason = Some(CancelReason::COLLATERAL_EXHAUSTED);
+                    break;
+                }
+                traded_base_amount = min(traded_base_amount, capacity);
+            }
             if taker_is_bid && is_market_order {
                 if (quote_sum + price * traded_base_amount).gt(quote_limit) {
                     // divide remain quote by price to get a base amount to be traded,
@@ -374,221 +1205,489 @@ impl Market {
             if taker_is_bid && is_market_order {
                 debug_assert!(quote_sum <= *quote_limit);
             }
+            if self_trade_prevention != SelfTradePrevention::NONE {
+                debug_assert_ne!(taker.user, maker.user);
+            }
 
-            // Step4: create the trade
-            let bid_fee = (traded_base_amount * bid_fee_rate).round_dp_with_strategy(self.base_prec, RoundingStrategy::ToZero);
-            let ask_fee = (traded_quote_amount * ask_fee_rate).round_dp_with_strategy(self.quote_prec, RoundingStrategy::ToZero);
+            // Step4: trade
+            let (ask_order, bid_order, ask_role, bid_role) = if taker_is_ask {
+                (&mut taker, &mut maker, MarketRole::TAKER, MarketRole::MAKER)
+            } else {
+                (&mut maker, &mut taker, MarketRole::MAKER, MarketRole::TAKER)
+            };
+            self.settle_trade(
+                sequencer,
+                balance_manager,
+                balance_update_controller,
+                persistor,
+                (ask_order, ask_role),
+                (bid_order, bid_role),
+                price,
+                traded_base_amount,
+                time,
+            );
+            if !maker.shared_collateral {
+                maker.frozen -= if maker_is_bid { traded_quote_amount } else { traded_base_amount };
+            }
+            if maker.is_iceberg() {
+                maker.visible -= traded_base_amount;
+            }
 
-            let timestamp = current_timestamp();
-            ask_order.update_time = timestamp;
-            bid_order.update_time = timestamp;
+            if maker.remain.is_zero() {
+                self.order_finish(&mut *balance_manager, persistor, &maker);
+            } else {
+                if maker.is_iceberg() && maker.visible.is_zero() {
+                    self.refresh_iceberg(sequencer, &mut maker);
+                }
+                *maker_rc.borrow_mut() = maker;
+                self.touch_level(&maker);
+                persistor.put_order(&maker, OrderEventType::UPDATE);
+            }
+        }
 
-            // emit the trade
-            let trade_id = sequencer.next_trade_id();
-            let trade = Trade {
-                id: trade_id,
-                timestamp: current_timestamp(),
-                market: self.name.to_string(),
-                base: self.base.into(),
-                quote: self.quote.into(),
-                price,
-                amount: traded_base_amount,
-                quote_amount: traded_quote_amount,
-                ask_user_id: ask_order.user,
-                ask_order_id: ask_order.id,
-                ask_role: if taker_is_ask { MarketRole::TAKER } else { MarketRole::MAKER },
-                ask_fee,
-                bid_user_id: bid_order.user,
-                bid_order_id: bid_order.id,
-                bid_role: if taker_is_ask { MarketRole::MAKER } else { MarketRole::TAKER },
-                bid_fee,
-
-                ask_order: None,
-                bid_order: None,
-                #[cfg(feature = "emit_state_diff")]
-                state_before: Default::default(),
-                #[cfg(feature = "emit_state_diff")]
-                state_after: Default::default(),
-            };
-            #[cfg(feature = "emit_state_diff")]
-            let state_before = Self::get_trade_state(ask_order, bid_order, balance_manager, self.base, self.quote);
-            self.trade_count += 1;
-            if self.disable_self_trade {
-                debug_assert_ne!(trade.ask_user_id, trade.bid_user_id);
-            }
-
-            // Step5: update orders
-            let ask_order_is_new = ask_order.finished_base.is_zero();
-            let ask_order_before = *ask_order;
-            let bid_order_is_new = bid_order.finished_base.is_zero();
-            let bid_order_before = *bid_order;
-            ask_order.remain -= traded_base_amount;
-            debug_assert!(ask_order.remain.is_sign_positive());
-            bid_order.remain -= traded_base_amount;
-            debug_assert!(bid_order.remain.is_sign_positive());
-            ask_order.finished_base += traded_base_amount;
-            bid_order.finished_base += traded_base_amount;
-            ask_order.finished_quote += traded_quote_amount;
-            bid_order.finished_quote += traded_quote_amount;
-            ask_order.finished_fee += ask_fee;
-            bid_order.finished_fee += bid_fee;
-
-            // Step6: update balances
-            balance_update_controller
-                .update_user_balance(
-                    balance_manager.inner,
-                    persistor,
-                    BalanceUpdateParams {
-                        balance_type: BalanceType::AVAILABLE,
-                        business_type: BusinessType::Trade,
-                        user_id: bid_order.user,
-                        asset: self.base.to_string(),
-                        business: "trade".to_string(),
-                        business_id: trade_id,
-                        market_price: self.price,
-                        change: if bid_fee.is_sign_positive() {
-                            traded_base_amount - bid_fee
-                        } else {
-                            traded_base_amount
-                        },
-                        detail: serde_json::Value::default(),
-                        signature: vec![],
+        if cancel_reason.is_some() {
+            // Now both self trade orders and immediately triggered post_only
+            // limit orders will be cancelled here.
+            // TODO: use CANCEL event here
+            taker.cancel_reason = cancel_reason;
+            self.put_finished_order(persistor, &taker, OrderEventType::FINISH);
+        } else if taker.type_ == OrderType::MARKET {
+            // market order can either filled or not
+            // if it is filled, `FINISH` is ok
+            // if it is not filled, `CANCELED` may be a better choice?
+            self.put_finished_order(persistor, &taker, OrderEventType::FINISH);
+        } else {
+            // now the order type is limit
+            if taker.remain.is_zero() {
+                self.put_finished_order(persistor, &taker, OrderEventType::FINISH);
+            } else if taker.time_in_force != TimeInForce::GTC {
+                // a fill or kill order can only get here if the dry run went wrong
+                taker.cancel_reason = Some(if taker.time_in_force == TimeInForce::IOC {
+                    CancelReason::IMMEDIATE_OR_CANCEL
+                } else {
+                    CancelReason::FILL_OR_KILL
+                });
+                self.put_finished_order(persistor, &taker, OrderEventType::FINISH);
+            } else {
+                // `insert_order` will update the order info
+                taker = self.insert_order_into_orderbook(taker);
+                self.touch_level(&taker);
+                self.frozen_balance(balance_manager, &taker);
+            }
+        }
+
+        self.cancel_triggered_groups(balance_manager, persistor);
+
+        log::debug!("execute_order done {:?}", taker);
+        taker
+    }
+
+    // Splits `amount` among the orders at the best counter level for pro-rata matching, capped by what
+    // the level holds and by `quote_limit` for a market bid. The first order by time priority takes
+    // its top order share, the rest is split by visible remain rounded down to the amount precision,
+    // and splits below the minimum allocation are dropped. What is left over goes by time priority.
+    // The allocations are returned by time priority, orders allocated nothing are left out.
+    fn allocate_level(&self, maker_is_bid: bool, amount: &Decimal, quote_limit: Option<Decimal>) -> VecDeque<(u64, Decimal)> {
+        let counter_orders: Box<dyn Iterator<Item = &OrderRc>> = if maker_is_bid {
+            Box::new(self.bids.values())
+        } else {
+            Box::new(self.asks.values())
+        };
+        let mut level_price = None;
+        let mut level: Vec<(u64, Decimal)> = Vec::new();
+        for order_rc in counter_orders {
+            let order = order_rc.borrow();
+            if *level_price.get_or_insert(order.price) != order.price {
+                break;
+            }
+            level.push((order.id, order.visible_remain()));
+        }
+        let price = match level_price {
+            Some(price) => price,
+            None => return VecDeque::new(),
+        };
+        let total: Decimal = level.iter().map(|(_, size)| *size).sum();
+        let mut amount = min(*amount, total);
+        if let Some(quote_limit) = quote_limit {
+            amount = min(
+                amount,
+                (quote_limit / price).round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero),
+            );
+        }
+
+        let mut allocations = vec![Decimal::zero(); level.len()];
+        allocations[0] = min(
+            level[0].1,
+            (amount * self.pro_rata_top_order_share).round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero),
+        );
+        let split_amount = amount - allocations[0];
+        let split_total = total - allocations[0];
+        if !split_total.is_zero() {
+            for ((_, size), allocation) in level.iter().zip(allocations.iter_mut()) {
+                let split =
+                    (split_amount * (size - *allocation) / split_total).round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero);
+                if split >= self.pro_rata_min_allocation {
+                    *allocation += split;
+                }
+            }
+        }
+        let mut leftover = amount - allocations.iter().sum::<Decimal>();
+        for ((_, size), allocation) in level.iter().zip(allocations.iter_mut()) {
+            let extra = min(leftover, size - *allocation);
+            *allocation += extra;
+            leftover -= extra;
+        }
+
+        level
+            .into_iter()
+            .zip(allocations)
+            .filter(|(_, allocation)| !allocation.is_zero())
+            .map(|((order_id, _), allocation)| (order_id, allocation))
+            .collect()
+    }
+
+    // the base amount the pool of an order on shared collateral can pay for at the price
+    fn pool_capacity(&self, balance_manager: &BalanceManagerWrapper<'_>, order: &Order, price: &Decimal) -> Decimal {
+        if order.is_ask() {
+            balance_manager.pool_get(order.user, self.base)
+        } else {
+            (balance_manager.pool_get(order.user, self.quote) / price).round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero)
+        }
+    }
+
+    // Orders on shared collateral are shrunk to what is left in their pools, and cancelled once
+    // nothing is left. The pools are shared by the markets, so this is run for every market after
+    // the pools change. The time is the one of the operation which changed the pools.
+    pub fn fit_pooled_orders(
+        &mut self,
+        sequencer: &mut Sequencer,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        time: f64,
+    ) {
+        let orders: Vec<Order> = self.pooled_orders.values().map(OrderRc::deep).collect();
+        for mut order in orders {
+            let capacity = self.pool_capacity(&balance_manager, &order, &order.price);
+            if order.remain <= capacity {
+                continue;
+            }
+            if capacity.is_zero() {
+                order.cancel_reason = Some(CancelReason::COLLATERAL_EXHAUSTED);
+                self.order_finish(&mut balance_manager, persistor, &order);
+                continue;
+            }
+            // `amount` shrinks with `remain`, so `remain + finished_base == amount` still holds
+            order.amount -= order.remain - capacity;
+            order.remain = capacity;
+            if order.is_iceberg() {
+                order.visible = min(order.visible, order.remain);
+            }
+            order.update_time = time;
+            *self.pooled_orders.get_mut(&order.id).unwrap().borrow_mut() = order;
+            self.touch_level(&order);
+            persistor.put_order(&order, OrderEventType::UPDATE);
+        }
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
+    }
+
+    // whether another order of its group has been filled in the current execution
+    fn is_cancelled_by_group(&self, order: &Order) -> bool {
+        order.group_id != 0
+            && self
+                .triggered_groups
+                .get(&order.group_id)
+                .map_or(false, |fired| !fired.contains(&order.id))
+    }
+
+    // the orders which fired their groups are left alone, the other orders of the groups are cancelled
+    fn cancel_triggered_groups(&mut self, balance_manager: &mut BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector) {
+        let triggered_groups = std::mem::take(&mut self.triggered_groups);
+        for (group_id, fired) in triggered_groups {
+            let others: Vec<Order> = match self.groups.get(&group_id) {
+                Some(group) => group
+                    .values()
+                    .map(OrderRc::deep)
+                    .filter(|order| !fired.contains(&order.id))
+                    .collect(),
+                None => continue,
+            };
+            for order in others {
+                let order = Order {
+                    cancel_reason: Some(CancelReason::ORDER_GROUP),
+                    ..order
+                };
+                self.order_finish(balance_manager, persistor, &order);
+            }
+        }
+    }
+
+    // The next part of an iceberg order is shown with a fresh time priority,
+    // so it queues behind the orders already at its price
+    fn refresh_iceberg(&mut self, sequencer: &mut Sequencer, order: &mut Order) {
+        debug_assert!(order.is_iceberg() && !order.remain.is_zero());
+        if order.is_ask() {
+            let order_rc = self.asks.remove(&order.get_ask_key()).unwrap();
+            order.priority = sequencer.next_priority();
+            self.asks.insert(order.get_ask_key(), order_rc);
+        } else {
+            let order_rc = self.bids.remove(&order.get_bid_key()).unwrap();
+            order.priority = sequencer.next_priority();
+            self.bids.insert(order.get_bid_key(), order_rc);
+        }
+        order.visible = min(order.display_amount, order.remain);
+    }
+
+    // Create a trade between two orders and move the balances, the orders are updated in place.
+    // A maker pays from its frozen balance and a taker from its available balance.
+    fn settle_trade(
+        &mut self,
+        sequencer: &mut Sequencer,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+        (ask_order, ask_role): (&mut Order, MarketRole),
+        (bid_order, bid_role): (&mut Order, MarketRole),
+        price: Decimal,
+        traded_base_amount: Decimal,
+        time: f64,
+    ) {
+        let ask_fee_rate = if ask_role == MarketRole::TAKER {
+            ask_order.taker_fee
+        } else {
+            ask_order.maker_fee
+        };
+        let bid_fee_rate = if bid_role == MarketRole::TAKER {
+            bid_order.taker_fee
+        } else {
+            bid_order.maker_fee
+        };
+        let traded_quote_amount = price * traded_base_amount;
+        let bid_fee = (traded_base_amount * bid_fee_rate).round_dp_with_strategy(self.base_prec, RoundingStrategy::ToZero);
+        let ask_fee = (traded_quote_amount * ask_fee_rate).round_dp_with_strategy(self.quote_prec, RoundingStrategy::ToZero);
+
+        ask_order.update_time = time;
+        bid_order.update_time = time;
+
+        // emit the trade
+        let trade_id = sequencer.next_trade_id();
+        let trade = Trade {
+            id: trade_id,
+            timestamp: time,
+            market: self.name.to_string(),
+            base: self.base.into(),
+            quote: self.quote.into(),
+            price,
+            amount: traded_base_amount,
+            quote_amount: traded_quote_amount,
+            ask_user_id: ask_order.user,
+            ask_order_id: ask_order.id,
+            ask_role,
+            ask_fee,
+            bid_user_id: bid_order.user,
+            bid_order_id: bid_order.id,
+            bid_role,
+            bid_fee,
+
+            ask_order: None,
+            bid_order: None,
+            #[cfg(feature = "emit_state_diff")]
+            state_before: Default::default(),
+            #[cfg(feature = "emit_state_diff")]
+            state_after: Default::default(),
+        };
+        #[cfg(feature = "emit_state_diff")]
+        let state_before = Self::get_trade_state(ask_order, bid_order, balance_manager, self.base, self.quote);
+        self.trade_count += 1;
+
+        // update orders
+        let ask_order_is_new = ask_order.finished_base.is_zero();
+        let ask_order_before = *ask_order;
+        let bid_order_is_new = bid_order.finished_base.is_zero();
+        let bid_order_before = *bid_order;
+        ask_order.remain -= traded_base_amount;
+        debug_assert!(ask_order.remain.is_sign_positive());
+        bid_order.remain -= traded_base_amount;
+        debug_assert!(bid_order.remain.is_sign_positive());
+        ask_order.finished_base += traded_base_amount;
+        bid_order.finished_base += traded_base_amount;
+        ask_order.finished_quote += traded_quote_amount;
+        bid_order.finished_quote += traded_quote_amount;
+        ask_order.finished_fee += ask_fee;
+        bid_order.finished_fee += bid_fee;
+        for order in [&*ask_order, &*bid_order] {
+            if order.fires_group() {
+                self.triggered_groups
+                    .entry(order.group_id)
+                    .or_insert_with(BTreeSet::new)
+                    .insert(order.id);
+            }
+        }
+
+        // update balances
+        balance_update_controller
+            .update_user_balance(
+                balance_manager.inner,
+                persistor,
+                BalanceUpdateParams {
+                    balance_type: BalanceType::AVAILABLE,
+                    business_type: BusinessType::Trade,
+                    user_id: bid_order.user,
+                    asset: self.base.to_string(),
+                    business: "trade".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: if bid_fee.is_sign_positive() {
+                        traded_base_amount - bid_fee
+                    } else {
+                        traded_base_amount
                     },
-                )
-                .unwrap();
-            balance_update_controller
-                .update_user_balance(
-                    balance_manager.inner,
-                    persistor,
-                    BalanceUpdateParams {
-                        balance_type: if maker_is_ask {
-                            BalanceType::FREEZE
-                        } else {
-                            BalanceType::AVAILABLE
-                        },
-                        business_type: BusinessType::Trade,
-                        user_id: ask_order.user,
-                        asset: self.base.to_string(),
-                        business: "trade".to_string(),
-                        business_id: trade_id,
-                        market_price: self.price,
-                        change: -traded_base_amount,
-                        detail: serde_json::Value::default(),
-                        signature: vec![],
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+            )
+            .unwrap();
+        balance_update_controller
+            .update_user_balance(
+                balance_manager.inner,
+                persistor,
+                BalanceUpdateParams {
+                    balance_type: if ask_role == MarketRole::MAKER || ask_order.shared_collateral {
+                        BalanceType::FREEZE
+                    } else {
+                        BalanceType::AVAILABLE
                     },
-                )
-                .unwrap();
-            balance_update_controller
-                .update_user_balance(
-                    balance_manager.inner,
-                    persistor,
-                    BalanceUpdateParams {
-                        balance_type: BalanceType::AVAILABLE,
-                        business_type: BusinessType::Trade,
-                        user_id: ask_order.user,
-                        asset: self.quote.to_string(),
-                        business: "trade".to_string(),
-                        business_id: trade_id,
-                        market_price: self.price,
-                        change: if ask_fee.is_sign_positive() {
-                            traded_quote_amount - ask_fee
-                        } else {
-                            traded_quote_amount
-                        },
-                        detail: serde_json::Value::default(),
-                        signature: vec![],
+                    business_type: BusinessType::Trade,
+                    user_id: ask_order.user,
+                    asset: self.base.to_string(),
+                    business: "trade".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: -traded_base_amount,
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+            )
+            .unwrap();
+        balance_update_controller
+            .update_user_balance(
+                balance_manager.inner,
+                persistor,
+                BalanceUpdateParams {
+                    balance_type: BalanceType::AVAILABLE,
+                    business_type: BusinessType::Trade,
+                    user_id: ask_order.user,
+                    asset: self.quote.to_string(),
+                    business: "trade".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: if ask_fee.is_sign_positive() {
+                        traded_quote_amount - ask_fee
+                    } else {
+                        traded_quote_amount
                     },
-                )
-                .unwrap();
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+            )
+            .unwrap();
+        balance_update_controller
+            .update_user_balance(
+                balance_manager.inner,
+                persistor,
+                BalanceUpdateParams {
+                    balance_type: if bid_role == MarketRole::MAKER || bid_order.shared_collateral {
+                        BalanceType::FREEZE
+                    } else {
+                        BalanceType::AVAILABLE
+                    },
+                    business_type: BusinessType::Trade,
+                    user_id: bid_order.user,
+                    asset: self.quote.to_string(),
+                    business: "trade".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: -traded_quote_amount,
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+            )
+            .unwrap();
+        // orders on shared collateral have paid from their pools
+        if ask_order.shared_collateral {
+            balance_manager.pool_draw(ask_order.user, self.base, &traded_base_amount);
+        }
+        if bid_order.shared_collateral {
+            balance_manager.pool_draw(bid_order.user, self.quote, &traded_quote_amount);
+        }
+        // the fees are moved to the receivers so that the total balances are kept
+        for (asset, fee_receiver, fee) in [
+            (self.base, self.base_fee_receiver, bid_fee),
+            (self.quote, self.quote_fee_receiver, ask_fee),
+        ] {
+            if fee.is_zero() || fee.is_sign_negative() {
+                continue;
+            }
             balance_update_controller
                 .update_user_balance(
                     balance_manager.inner,
                     persistor,
                     BalanceUpdateParams {
-                        balance_type: if maker_is_bid {
-                            BalanceType::FREEZE
-                        } else {
-                            BalanceType::AVAILABLE
-                        },
+                        balance_type: BalanceType::AVAILABLE,
                         business_type: BusinessType::Trade,
-                        user_id: bid_order.user,
-                        asset: self.quote.to_string(),
-                        business: "trade".to_string(),
+                        user_id: fee_receiver,
+                        asset: asset.to_string(),
+                        business: "trade_fee".to_string(),
                         business_id: trade_id,
                         market_price: self.price,
-                        change: -traded_quote_amount,
+                        change: fee,
                         detail: serde_json::Value::default(),
                         signature: vec![],
                     },
                 )
                 .unwrap();
-            #[cfg(feature = "emit_state_diff")]
-            let state_after = Self::get_trade_state(ask_order, bid_order, balance_manager, self.base, self.quote);
-
-            // Step7: persist trade and order
-            //if true persistor.real_persist() {
-            //if true
-            let trade = Trade {
-                #[cfg(feature = "emit_state_diff")]
-                state_after,
-                #[cfg(feature = "emit_state_diff")]
-                state_before,
-                ask_order: if ask_order_is_new { Some(ask_order_before) } else { None },
-                bid_order: if bid_order_is_new { Some(bid_order_before) } else { None },
-                ..trade
-            };
-            persistor.put_trade(&trade);
-            //}
-            maker.frozen -= if maker_is_bid { traded_quote_amount } else { traded_base_amount };
-
-            let maker_finished = maker.remain.is_zero();
-            if maker_finished {
-                finished_orders.push(*maker);
-            } else {
-                // When maker_finished, `order_finish` will send message.
-                // So we don't need to send the finish message here.
-                persistor.put_order(&maker, OrderEventType::UPDATE);
-            }
-
-            // Save this trade price to market.
-            self.price = price;
         }
+        #[cfg(feature = "emit_state_diff")]
+        let state_after = Self::get_trade_state(ask_order, bid_order, balance_manager, self.base, self.quote);
 
-        for item in finished_orders.iter() {
-            self.order_finish(&mut *balance_manager, persistor, item);
-        }
+        // persist the trade
+        //if true persistor.real_persist() {
+        //if true
+        let trade = Trade {
+            #[cfg(feature = "emit_state_diff")]
+            state_after,
+            #[cfg(feature = "emit_state_diff")]
+            state_before,
+            ask_order: if ask_order_is_new { Some(ask_order_before) } else { None },
+            bid_order: if bid_order_is_new { Some(bid_order_before) } else { None },
+            ..trade
+        };
+        persistor.put_trade(&trade);
+        //}
 
-        if need_cancel {
-            // Now both self trade orders and immediately triggered post_only
-            // limit orders will be cancelled here.
-            // TODO: use CANCEL event here
-            persistor.put_order(&taker, OrderEventType::FINISH);
-        } else if taker.type_ == OrderType::MARKET {
-            // market order can either filled or not
-            // if it is filled, `FINISH` is ok
-            // if it is not filled, `CANCELED` may be a better choice?
-            persistor.put_order(&taker, OrderEventType::FINISH);
-        } else {
-            // now the order type is limit
-            if taker.remain.is_zero() {
-                persistor.put_order(&taker, OrderEventType::FINISH);
-            } else {
-                // `insert_order` will update the order info
-                taker = self.insert_order_into_orderbook(taker);
-                self.frozen_balance(balance_manager, &taker);
-            }
+        // self trades do not move a user up the tiers
+        if ask_order.user != bid_order.user {
+            self.fee_schedule.add_volume(ask_order.user, &traded_quote_amount, time);
+            self.fee_schedule.add_volume(bid_order.user, &traded_quote_amount, time);
         }
 
-        log::debug!("execute_order done {:?}", taker);
-        taker
+        // Save this trade price to market.
+        self.price = price;
     }
 
     pub fn insert_order_into_orderbook(&mut self, mut order: Order) -> Order {
-        if order.side == OrderSide::ASK {
+        if order.is_stop_order() {
+            return self.insert_order_into_trigger_book(order);
+        }
+        if order.shared_collateral {
+            order.frozen = Decimal::zero();
+        } else if order.side == OrderSide::ASK {
             order.frozen = order.remain;
         } else {
             order.frozen = order.remain * order.price;
         }
+        if order.is_iceberg() {
+            order.visible = min(order.visible, order.remain);
+        }
         debug_assert_eq!(order.type_, OrderType::LIMIT);
         debug_assert!(!self.orders.contains_key(&order.id));
         // log::debug!("order insert {}", &order.id);
@@ -598,6 +1697,24 @@ impl Market {
         let user_map = self.users.entry(order.user).or_insert_with(BTreeMap::new);
         debug_assert!(!user_map.contains_key(&order.id));
         user_map.insert(order.id, order_rc.clone());
+        if order.client_order_id != 0 {
+            let client_map = self.client_orders.entry(order.user).or_insert_with(BTreeMap::new);
+            debug_assert!(!client_map.contains_key(&order.client_order_id));
+            client_map.insert(order.client_order_id, order_rc.clone());
+        }
+        if order.group_id != 0 {
+            let group = self.groups.entry(order.group_id).or_insert_with(BTreeMap::new);
+            group.insert(order.id, order_rc.clone());
+        }
+        if order.shared_collateral {
+            self.pooled_orders.insert(order.id, order_rc.clone());
+        }
+        if order.expire_time > 0.0 {
+            self.expiring_orders.insert(order.id, order_rc.clone());
+        }
+        if order.is_pegged() {
+            self.pegged_orders.insert(order.id, order_rc.clone());
+        }
         if order.side == OrderSide::ASK {
             let key = order.get_ask_key();
             debug_assert!(!self.asks.contains_key(&key));
@@ -610,60 +1727,324 @@ impl Market {
         order_rc.deep()
     }
 
-    fn order_finish(&mut self, balance_manager: &mut BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector, order: &Order) {
+    fn insert_order_into_trigger_book(&mut self, mut order: Order) -> Order {
+        // a stop market bid keeps the given frozen quote
         if order.side == OrderSide::ASK {
-            let key = &order.get_ask_key();
-            debug_assert!(self.asks.contains_key(key));
-            self.asks.remove(key);
+            order.frozen = order.remain;
+        } else if order.type_ == OrderType::STOP_LIMIT {
+            order.frozen = order.remain * order.price;
+        }
+        debug_assert!(!self.orders.contains_key(&order.id));
+        let order_rc = OrderRc::new(order);
+        self.orders.insert(order.id, order_rc.clone());
+        let user_map = self.users.entry(order.user).or_insert_with(BTreeMap::new);
+        debug_assert!(!user_map.contains_key(&order.id));
+        user_map.insert(order.id, order_rc.clone());
+        if order.client_order_id != 0 {
+            let client_map = self.client_orders.entry(order.user).or_insert_with(BTreeMap::new);
+            debug_assert!(!client_map.contains_key(&order.client_order_id));
+            client_map.insert(order.client_order_id, order_rc.clone());
+        }
+        if order.group_id != 0 {
+            let group = self.groups.entry(order.group_id).or_insert_with(BTreeMap::new);
+            group.insert(order.id, order_rc.clone());
+        }
+        if order.expire_time > 0.0 {
+            self.expiring_orders.insert(order.id, order_rc.clone());
+        }
+        if order.side == OrderSide::ASK {
+            self.stop_asks.insert(order.get_stop_ask_key(), order_rc);
         } else {
-            let key = &order.get_bid_key();
-            debug_assert!(self.bids.contains_key(key));
-            self.bids.remove(key);
+            self.stop_bids.insert(order.get_stop_bid_key(), order_rc);
         }
-        self.unfrozen_balance(balance_manager, order);
-        debug_assert!(self.orders.contains_key(&order.id));
-        // log::debug!("order finish {}", &order.id);
-        self.orders.remove(&order.id);
-        let user_map = self.users.get_mut(&order.user).unwrap();
-        debug_assert!(user_map.contains_key(&order.id));
-        user_map.remove(&order.id);
+        order
+    }
 
-        persistor.put_order(order, OrderEventType::FINISH);
+    // remember the price level of an order in the orderbook, stop orders are not in the book
+    fn touch_level(&mut self, order: &Order) {
+        if order.is_stop_order() {
+            return;
+        }
+        if order.is_ask() {
+            self.changed_asks.insert(order.price);
+        } else {
+            self.changed_bids.insert(order.price);
+        }
     }
 
-    // for debugging
-    fn get_trade_state(
-        ask: &Order,
-        bid: &Order,
+    // the size others can see at a price level
+    fn level_amount(&self, side: OrderSide, price: Decimal) -> Decimal {
+        let level: Box<dyn Iterator<Item = &OrderRc>> = if side == OrderSide::ASK {
+            let start = MarketKeyAsk {
+                order_price: price,
+                priority: 0,
+                order_id: 0,
+            };
+            Box::new(self.asks.range(start..).map(|(_, order_rc)| order_rc))
+        } else {
+            let start = MarketKeyBid {
+                order_price: price,
+                priority: 0,
+                order_id: 0,
+            };
+            Box::new(self.bids.range(start..).map(|(_, order_rc)| order_rc))
+        };
+        level
+            .map(|order_rc| order_rc.borrow())
+            .take_while(|order| order.price == price)
+            .map(|order| order.visible_remain())
+            .sum()
+    }
+
+    // run after each operation which changes the orderbook, with the time of the operation
+    fn finish_book_update(
+        &mut self,
+        sequencer: &mut Sequencer,
         balance_manager: &mut BalanceManagerWrapper<'_>,
-        base: &'static str,
-        quote: &'static str,
-    ) -> VerboseTradeState {
-        let ask_order_state = VerboseOrderState {
-            user_id: ask.user,
-            order_id: ask.id,
-            order_side: ask.side,
-            finished_base: ask.finished_base,
-            finished_quote: ask.finished_quote,
-            finished_fee: ask.finished_fee,
+        persistor: &mut impl PersistExector,
+        time: f64,
+    ) {
+        self.reprice_pegged_orders(sequencer, balance_manager, persistor, time);
+        self.publish_book_changes(persistor);
+    }
+
+    // The price a pegged order rests at: its reference plus the offset, rounded away from the
+    // other side. It is kept behind the best counter order so that it never trades when it moves.
+    // None if there is no reference or no positive price.
+    fn peg_price(&self, side: OrderSide, peg: PegReference, offset: &Decimal) -> Option<Decimal> {
+        let best_bid = || {
+            self.bids
+                .values()
+                .map(OrderRc::deep)
+                .find(|order| !order.is_pegged())
+                .map(|order| order.price)
         };
-        let bid_order_state = VerboseOrderState {
-            user_id: bid.user,
-            order_id: bid.id,
-            order_side: bid.side,
-            finished_base: bid.finished_base,
-            finished_quote: bid.finished_quote,
-            finished_fee: bid.finished_fee,
+        let best_ask = || {
+            self.asks
+                .values()
+                .map(OrderRc::deep)
+                .find(|order| !order.is_pegged())
+                .map(|order| order.price)
         };
-        let ask_user_base = balance_manager.balance_total(ask.user, base);
-        let ask_user_quote = balance_manager.balance_total(ask.user, quote);
-        let bid_user_base = balance_manager.balance_total(bid.user, base);
-        let bid_user_quote = balance_manager.balance_total(bid.user, quote);
-        VerboseTradeState {
-            order_states: vec![ask_order_state, bid_order_state],
-            balance_states: vec![
-                VerboseBalanceState {
-                    user_id: ask.user,
+        let reference = match peg {
+            PegReference::NONE => return None,
+            PegReference::BEST_BID => best_bid()?,
+            PegReference::BEST_ASK => best_ask()?,
+            PegReference::MID => (best_bid()? + best_ask()?) / Decimal::from(2),
+        };
+        let tick = Decimal::new(1, self.price_prec);
+        let price = if side == OrderSide::ASK {
+            let price = (reference + offset).round_dp_with_strategy(self.price_prec, RoundingStrategy::AwayFromZero);
+            match self.bids.values().next() {
+                Some(bid) => max(price, bid.borrow().price + tick),
+                None => price,
+            }
+        } else {
+            let price = (reference + offset).round_dp_with_strategy(self.price_prec, RoundingStrategy::ToZero);
+            match self.asks.values().next() {
+                Some(ask) => min(price, ask.borrow().price - tick),
+                None => price,
+            }
+        };
+        Some(price).filter(|price| price.is_sign_positive() && !price.is_zero())
+    }
+
+    // Move the pegged orders to the prices of their references. A moved order queues behind the
+    // orders at its new price. A bid freezes its quote at the new price, and is cancelled if the
+    // user cannot pay for it. An order keeps its price while its reference is missing.
+    // The moved orders take the time of the operation which moved their references.
+    fn reprice_pegged_orders(
+        &mut self,
+        sequencer: &mut Sequencer,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        time: f64,
+    ) {
+        // the book may cross during the auction, the orders are moved once it ends
+        if self.pegged_orders.is_empty() || self.phase == MarketPhase::AUCTION {
+            return;
+        }
+        let orders: Vec<Order> = self.pegged_orders.values().map(OrderRc::deep).collect();
+        for mut order in orders {
+            let price = match self.peg_price(order.side, order.peg, &order.peg_offset) {
+                Some(price) if price != order.price => price,
+                _ => continue,
+            };
+            if !order.is_ask() && !order.shared_collateral {
+                let frozen = order.remain * price;
+                if frozen > order.frozen {
+                    let delta = frozen - order.frozen;
+                    if balance_manager.balance_get(order.user, BalanceType::AVAILABLE, self.quote) < delta {
+                        order.cancel_reason = Some(CancelReason::PEG_UNFUNDED);
+                        self.order_finish(balance_manager, persistor, &order);
+                        continue;
+                    }
+                    balance_manager.balance_frozen(order.user, self.quote, &delta);
+                } else if frozen < order.frozen {
+                    balance_manager.balance_unfrozen(order.user, self.quote, &(order.frozen - frozen));
+                }
+                order.frozen = frozen;
+            }
+            self.touch_level(&order);
+            let mut order_rc = if order.is_ask() {
+                self.asks.remove(&order.get_ask_key()).unwrap()
+            } else {
+                self.bids.remove(&order.get_bid_key()).unwrap()
+            };
+            order.price = price;
+            order.priority = sequencer.next_priority();
+            order.update_time = time;
+            if order.is_ask() {
+                self.asks.insert(order.get_ask_key(), order_rc.clone());
+            } else {
+                self.bids.insert(order.get_bid_key(), order_rc.clone());
+            }
+            *order_rc.borrow_mut() = order;
+            self.touch_level(&order);
+            persistor.put_order(&order, OrderEventType::UPDATE);
+        }
+    }
+
+    // Emit a delta for each price level the operation has changed. A level may be touched
+    // several times by one operation, only its final size is published.
+    fn publish_book_changes(&mut self, persistor: &mut impl PersistExector) {
+        let changes = std::mem::take(&mut self.changed_asks)
+            .into_iter()
+            .map(|price| (OrderSide::ASK, price))
+            .chain(
+                std::mem::take(&mut self.changed_bids)
+                    .into_iter()
+                    .map(|price| (OrderSide::BID, price)),
+            );
+        for (side, price) in changes {
+            self.book_sequence += 1;
+            persistor.put_orderbook_delta(&OrderBookDeltaMessage {
+                market: self.name.to_string(),
+                sequence: self.book_sequence,
+                side,
+                price,
+                amount: self.level_amount(side, price),
+            });
+        }
+    }
+
+    fn order_finish(&mut self, balance_manager: &mut BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector, order: &Order) {
+        self.detach_order(balance_manager, order);
+        self.put_finished_order(persistor, order, OrderEventType::FINISH);
+    }
+
+    // the last event of an order, an order with a client order id is remembered after it
+    fn put_finished_order(&mut self, persistor: &mut impl PersistExector, order: &Order, event: OrderEventType) {
+        persistor.put_order(order, event);
+        self.remember_client_order(*order);
+    }
+
+    pub fn remember_client_order(&mut self, order: Order) {
+        if order.client_order_id == 0 {
+            return;
+        }
+        let client_map = self.finished_client_orders.entry(order.user).or_insert_with(BTreeMap::new);
+        if let Some(previous) = client_map.insert(order.client_order_id, order) {
+            self.finished_client_order_ids.remove(&previous.id);
+        }
+        self.finished_client_order_ids.insert(order.id, (order.user, order.client_order_id));
+        while self.finished_client_order_ids.len() > self.client_order_retention {
+            let oldest = *self.finished_client_order_ids.keys().next().unwrap();
+            let (user, client_order_id) = self.finished_client_order_ids.remove(&oldest).unwrap();
+            let client_map = self.finished_client_orders.get_mut(&user).unwrap();
+            client_map.remove(&client_order_id);
+            if client_map.is_empty() {
+                self.finished_client_orders.remove(&user);
+            }
+        }
+    }
+
+    // remove the order from all indexes and return its frozen balance
+    fn detach_order(&mut self, balance_manager: &mut BalanceManagerWrapper<'_>, order: &Order) {
+        if order.is_stop_order() {
+            if order.side == OrderSide::ASK {
+                let key = &order.get_stop_ask_key();
+                debug_assert!(self.stop_asks.contains_key(key));
+                self.stop_asks.remove(key);
+            } else {
+                let key = &order.get_stop_bid_key();
+                debug_assert!(self.stop_bids.contains_key(key));
+                self.stop_bids.remove(key);
+            }
+        } else if order.side == OrderSide::ASK {
+            let key = &order.get_ask_key();
+            debug_assert!(self.asks.contains_key(key));
+            self.asks.remove(key);
+        } else {
+            let key = &order.get_bid_key();
+            debug_assert!(self.bids.contains_key(key));
+            self.bids.remove(key);
+        }
+        self.touch_level(order);
+        self.unfrozen_balance(balance_manager, order);
+        debug_assert!(self.orders.contains_key(&order.id));
+        // log::debug!("order finish {}", &order.id);
+        self.orders.remove(&order.id);
+        let user_map = self.users.get_mut(&order.user).unwrap();
+        debug_assert!(user_map.contains_key(&order.id));
+        user_map.remove(&order.id);
+        if order.client_order_id != 0 {
+            let client_map = self.client_orders.get_mut(&order.user).unwrap();
+            debug_assert!(client_map.contains_key(&order.client_order_id));
+            client_map.remove(&order.client_order_id);
+        }
+        if order.group_id != 0 {
+            let group = self.groups.get_mut(&order.group_id).unwrap();
+            debug_assert!(group.contains_key(&order.id));
+            group.remove(&order.id);
+            if group.is_empty() {
+                self.groups.remove(&order.group_id);
+            }
+        }
+        if order.shared_collateral {
+            self.pooled_orders.remove(&order.id);
+        }
+        if order.expire_time > 0.0 {
+            self.expiring_orders.remove(&order.id);
+        }
+        if order.is_pegged() {
+            self.pegged_orders.remove(&order.id);
+        }
+    }
+
+    // for debugging
+    fn get_trade_state(
+        ask: &Order,
+        bid: &Order,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        base: &'static str,
+        quote: &'static str,
+    ) -> VerboseTradeState {
+        let ask_order_state = VerboseOrderState {
+            user_id: ask.user,
+            order_id: ask.id,
+            order_side: ask.side,
+            finished_base: ask.finished_base,
+            finished_quote: ask.finished_quote,
+            finished_fee: ask.finished_fee,
+        };
+        let bid_order_state = VerboseOrderState {
+            user_id: bid.user,
+            order_id: bid.id,
+            order_side: bid.side,
+            finished_base: bid.finished_base,
+            finished_quote: bid.finished_quote,
+            finished_fee: bid.finished_fee,
+        };
+        let ask_user_base = balance_manager.balance_total(ask.user, base);
+        let ask_user_quote = balance_manager.balance_total(ask.user, quote);
+        let bid_user_base = balance_manager.balance_total(bid.user, base);
+        let bid_user_quote = balance_manager.balance_total(bid.user, quote);
+        VerboseTradeState {
+            order_states: vec![ask_order_state, bid_order_state],
+            balance_states: vec![
+                VerboseBalanceState {
+                    user_id: ask.user,
                     asset: base.into(),
                     balance: ask_user_base,
                 },
@@ -685,31 +2066,218 @@ impl Market {
             ],
         }
     }
-    pub fn cancel(&mut self, mut balance_manager: BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector, order_id: u64) -> Order {
+    pub fn cancel(
+        &mut self,
+        sequencer: &mut Sequencer,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        order_id: u64,
+        time: f64,
+    ) -> Order {
         let order = self.orders.get(&order_id).unwrap();
-        let order_struct = order.deep();
+        let order_struct = Order {
+            cancel_reason: Some(CancelReason::USER),
+            ..order.deep()
+        };
         self.order_finish(&mut balance_manager, persistor, &order_struct);
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
         order_struct
     }
+    // Change the price and / or the total amount of a resting limit order in place.
+    // The order keeps its time priority only if the price is unchanged and the amount is not increased.
+    pub fn amend(
+        &mut self,
+        sequencer: &mut Sequencer,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        order_id: u64,
+        amount: Option<Decimal>,
+        price: Option<Decimal>,
+        time: f64,
+    ) -> Result<Order> {
+        let old_order = match self.orders.get(&order_id) {
+            Some(order_rc) => order_rc.deep(),
+            None => bail!("order not found"),
+        };
+        if old_order.is_stop_order() {
+            bail!("stop orders cannot be amended");
+        }
+        if old_order.is_pegged() && price.is_some() {
+            bail!("the price of a pegged order follows its reference");
+        }
+        let amount = amount.unwrap_or(old_order.amount);
+        let price = price.unwrap_or(old_order.price);
+        if amount.lt(&self.min_amount) {
+            bail!("invalid amount");
+        }
+        if amount.round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero) != amount {
+            bail!("invalid amount precision");
+        }
+        if price.is_zero() || price.round_dp(self.price_prec) != price {
+            bail!("invalid price");
+        }
+        if price != old_order.price && !Self::is_within_band(&self.price, &self.price_band, &price) {
+            bail!("price is out of the band around {}", self.price);
+        }
+        if amount <= old_order.finished_base {
+            bail!("amount should be larger than the finished amount");
+        }
+        // matching is not done here
+        if self.would_cross(old_order.side, &price) {
+            bail!("amended order would cross the book");
+        }
+
+        let remain = amount - old_order.finished_base;
+        let need = if old_order.is_ask() { remain } else { remain * price };
+        let asset = if old_order.is_ask() { self.base } else { self.quote };
+        let frozen = if old_order.shared_collateral { Decimal::zero() } else { need };
+        if old_order.shared_collateral {
+            if balance_manager.pool_get(old_order.user, asset) < need {
+                bail!("shared collateral not enough");
+            }
+        } else if frozen > old_order.frozen {
+            let delta = frozen - old_order.frozen;
+            if balance_manager.balance_get(old_order.user, BalanceType::AVAILABLE, asset) < delta {
+                bail!("balance not enough");
+            }
+            balance_manager.balance_frozen(old_order.user, asset, &delta);
+        } else if frozen < old_order.frozen {
+            balance_manager.balance_unfrozen(old_order.user, asset, &(old_order.frozen - frozen));
+        }
+
+        let keep_priority = price == old_order.price && amount <= old_order.amount;
+        let order = Order {
+            amount,
+            price,
+            remain,
+            frozen,
+            visible: min(old_order.visible, remain),
+            update_time: time,
+            priority: if keep_priority {
+                old_order.priority
+            } else {
+                sequencer.next_priority()
+            },
+            ..old_order
+        };
+        let order_rc = self.orders.get_mut(&order_id).unwrap();
+        *order_rc.borrow_mut() = order;
+        if !keep_priority {
+            if order.is_ask() {
+                let order_rc = self.asks.remove(&old_order.get_ask_key()).unwrap();
+                self.asks.insert(order.get_ask_key(), order_rc);
+            } else {
+                let order_rc = self.bids.remove(&old_order.get_bid_key()).unwrap();
+                self.bids.insert(order.get_bid_key(), order_rc);
+            }
+        }
+        self.touch_level(&old_order);
+        self.touch_level(&order);
+        persistor.put_order(&order, OrderEventType::UPDATE);
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
+        Ok(order)
+    }
+    // Cancel the orders which pass the filter and return their ids, the orders of all users if
+    // no user is given. Orders at the same price are cancelled in their priority order.
+    pub fn mass_cancel(
+        &mut self,
+        sequencer: &mut Sequencer,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        user_id: Option<u32>,
+        filter: &MassCancelFilter,
+        time: f64,
+    ) -> Vec<u64> {
+        let orders: Vec<Order> = {
+            let empty = BTreeMap::new();
+            let candidates: Box<dyn Iterator<Item = &OrderRc>> = match (user_id, filter.side) {
+                (Some(user_id), _) => Box::new(self.users.get(&user_id).unwrap_or(&empty).values()),
+                (None, Some(OrderSide::ASK)) => Box::new(self.asks.values().chain(self.stop_asks.values())),
+                (None, Some(OrderSide::BID)) => Box::new(self.bids.values().chain(self.stop_bids.values())),
+                (None, None) => Box::new(self.orders.values()),
+            };
+            candidates.map(OrderRc::deep).filter(|order| filter.matches(order)).collect()
+        };
+        for order in &orders {
+            let order = Order {
+                cancel_reason: Some(CancelReason::USER),
+                ..*order
+            };
+            self.order_finish(&mut balance_manager, persistor, &order);
+        }
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
+        orders.iter().map(|order| order.id).collect()
+    }
+    pub fn has_expired_orders(&self, now: f64) -> bool {
+        self.expiring_orders.values().any(|order_rc| order_rc.borrow().is_expired(now))
+    }
+    // Cancel the orders which have expired at the time and return them. The time is given
+    // by the caller, so that the same orders expire when the operation is replayed.
+    pub fn expire_orders(
+        &mut self,
+        sequencer: &mut Sequencer,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        now: f64,
+    ) -> Vec<Order> {
+        let orders: Vec<Order> = self
+            .expiring_orders
+            .values()
+            .map(OrderRc::deep)
+            .filter(|order| order.is_expired(now))
+            .map(|order| Order {
+                update_time: now,
+                cancel_reason: Some(CancelReason::EXPIRED),
+                ..order
+            })
+            .collect();
+        for order in &orders {
+            self.detach_order(&mut balance_manager, order);
+            self.put_finished_order(persistor, order, OrderEventType::EXPIRED);
+        }
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, now);
+        orders
+    }
     pub fn cancel_all_for_user(
         &mut self,
+        sequencer: &mut Sequencer,
         mut balance_manager: BalanceManagerWrapper<'_>,
         persistor: &mut impl PersistExector,
         user_id: u32,
+        time: f64,
     ) -> usize {
         // TODO: can we mutate while iterate?
         let order_ids: Vec<u64> = self.users.get(&user_id).unwrap_or(&BTreeMap::new()).keys().copied().collect();
         let total = order_ids.len();
         for order_id in order_ids {
             let order = self.orders.get(&order_id).unwrap();
-            let order_struct = order.deep();
+            let order_struct = Order {
+                cancel_reason: Some(CancelReason::USER),
+                ..order.deep()
+            };
             self.order_finish(&mut balance_manager, persistor, &order_struct);
         }
+        self.finish_book_update(sequencer, &mut balance_manager, persistor, time);
         total
     }
     pub fn get(&self, order_id: u64) -> Option<Order> {
         self.orders.get(&order_id).map(OrderRc::deep)
     }
+    pub fn get_by_client_order_id(&self, user_id: u32, client_order_id: u64) -> Option<Order> {
+        if client_order_id == 0 {
+            return None;
+        }
+        self.client_orders
+            .get(&user_id)
+            .and_then(|client_map| client_map.get(&client_order_id))
+            .map(OrderRc::deep)
+            .or_else(|| {
+                self.finished_client_orders
+                    .get(&user_id)
+                    .and_then(|client_map| client_map.get(&client_order_id))
+                    .copied()
+            })
+    }
     pub fn get_order_num_of_user(&self, user_id: u32) -> usize {
         self.users.get(&user_id).map(|m| m.len()).unwrap_or(0)
     }
@@ -735,6 +2303,40 @@ impl Market {
             bid_count: self.bids.len(),
             bid_amount: self.bids.values().map(|item| item.borrow().remain).sum(),
             trade_count: self.trade_count,
+            phase: self.phase,
+            trading_status: self.trading_status,
+        }
+    }
+    // The orders of one side of the orderbook in priority order, a page of at most `limit` orders.
+    // The page starts after the order with the given (price, priority, order id), which need not
+    // be in the book any more.
+    pub fn book_orders(&self, side: OrderSide, after: Option<(Decimal, u64, u64)>, limit: usize) -> Vec<Order> {
+        if side == OrderSide::ASK {
+            let start = after.map_or(Bound::Unbounded, |(order_price, priority, order_id)| {
+                Bound::Excluded(MarketKeyAsk {
+                    order_price,
+                    priority,
+                    order_id,
+                })
+            });
+            self.asks
+                .range((start, Bound::Unbounded))
+                .take(limit)
+                .map(|(_, order_rc)| order_rc.deep())
+                .collect()
+        } else {
+            let start = after.map_or(Bound::Unbounded, |(order_price, priority, order_id)| {
+                Bound::Excluded(MarketKeyBid {
+                    order_price,
+                    priority,
+                    order_id,
+                })
+            });
+            self.bids
+                .range((start, Bound::Unbounded))
+                .take(limit)
+                .map(|(_, order_rc)| order_rc.deep())
+                .collect()
         }
     }
     pub fn depth(&self, limit: usize, interval: &Decimal) -> MarketDepth {
@@ -765,7 +2367,7 @@ impl Market {
             .take(limit)
             .map(|(price, group)| PriceInfo {
                 price,
-                amount: group.map(|order_rc| order_rc.borrow().remain).sum(),
+                amount: group.map(|order_rc| order_rc.borrow().visible_remain()).sum(),
             })
             .collect::<Vec<PriceInfo>>()
     }
@@ -778,6 +2380,30 @@ pub struct MarketStatus {
     pub bid_count: usize,
     pub bid_amount: Decimal,
     pub trade_count: u64,
+    pub phase: MarketPhase,
+    pub trading_status: TradingStatus,
+}
+
+// Which orders `Market::mass_cancel` cancels, a condition which is not given is not checked.
+#[derive(Default)]
+pub struct MassCancelFilter {
+    pub side: Option<OrderSide>,
+    // the price range, both ends included. Stop market orders have no price and are
+    // left alone when either end is given
+    pub min_price: Option<Decimal>,
+    pub max_price: Option<Decimal>,
+    // only orders created before the time
+    pub before: Option<f64>,
+}
+
+impl MassCancelFilter {
+    pub fn matches(&self, order: &Order) -> bool {
+        let has_price = !order.price.is_zero();
+        self.side.map_or(true, |side| order.side == side)
+            && self.min_price.map_or(true, |min_price| has_price && order.price >= min_price)
+            && self.max_price.map_or(true, |max_price| has_price && order.price <= max_price)
+            && self.before.map_or(true, |before| order.create_time < before)
+    }
 }
 
 pub struct PriceInfo {
@@ -821,8 +2447,8 @@ mod tests {
     #[test]
     fn test_multi_orders() {
         use crate::asset::BalanceUpdateController;
-        use crate::matchengine::market::{Market, OrderInput};
-        use crate::types::{OrderSide, OrderType};
+        use crate::matchengine::market::Market;
+        use crate::types::OrderSide;
         use fluidex_common::rust_decimal::prelude::FromPrimitive;
         use rand::Rng;
 
@@ -885,262 +2511,1754 @@ mod tests {
             } else {
                 Decimal::from_f64(rng.gen_range(120.0..140.0)).unwrap()
             };
-            let order = OrderInput {
-                user_id,
-                side,
-                type_: OrderType::LIMIT,
-                // the matchengine will truncate precision
-                // but later we'd better truncate precision outside
-                amount,
-                price,
-                quote_limit: dec!(0),
-                taker_fee: dec!(0),
-                maker_fee: dec!(0),
-                market: market.name.to_string(),
-                post_only: false,
-                signature: [0; 64],
-            };
+            // the matchengine will truncate precision
+            // but later we'd better truncate precision outside
+            let order = get_simple_order_input(user_id, side, amount, price);
             market
                 .put_order(sequencer, balance_manager.into(), &mut update_controller, &mut persistor, order)
                 .unwrap();
         }
     }
     #[test]
-    fn test_market_taker_is_bid() {
+    fn test_market_taker_is_bid() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let ask_user_id = 101;
+        let market_conf = config::Market {
+            taker_fee: dec!(0.001),
+            maker_fee: dec!(0.001),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let ask_order_input = OrderInput {
+            taker_fee: dec!(0.001),
+            maker_fee: dec!(0.001),
+            ..get_simple_order_input(ask_user_id, OrderSide::ASK, dec!(20.0), dec!(0.1))
+        };
+        let ask_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                ask_order_input,
+            )
+            .unwrap();
+        assert_eq!(ask_order.id, 1);
+        assert_eq!(ask_order.remain, dec!(20.0));
+
+        let bid_user_id = 102;
+        let bid_order_input = OrderInput {
+            type_: OrderType::MARKET,
+            taker_fee: dec!(0.001),
+            maker_fee: dec!(0.001),
+            ..get_simple_order_input(bid_user_id, OrderSide::BID, dec!(10.0), dec!(0))
+        };
+        let bid_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                bid_order_input,
+            )
+            .unwrap();
+        // trade: price: 0.10 amount: 10
+        assert_eq!(bid_order.id, 2);
+        assert_eq!(bid_order.remain, dec!(0));
+        assert_eq!(bid_order.finished_quote, dec!(1));
+        assert_eq!(bid_order.finished_base, dec!(10));
+        assert_eq!(bid_order.finished_fee, dec!(0.01));
+
+        //market.print();
+
+        let ask_order = market.get(ask_order.id).unwrap();
+        assert_eq!(ask_order.remain, dec!(10));
+        assert_eq!(ask_order.finished_quote, dec!(1));
+        assert_eq!(ask_order.finished_base, dec!(10));
+        assert_eq!(ask_order.finished_fee, dec!(0.001));
+
+        // original balance: btc 300, eth 1000
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(980)
+        );
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()),
+            dec!(10)
+        );
+
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(300.999)
+        );
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
+            dec!(0)
+        );
+
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(1009.99)
+        );
+        assert_eq!(balance_manager.get(bid_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(299)
+        );
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
+            dec!(0)
+        );
+
+        //assert_eq!(persistor.orders.len(), 3);
+        //assert_eq!(persistor.trades.len(), 1);
+    }
+
+    #[test]
+    fn test_limit_post_only_orders() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(201, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+        balance_manager.add(202, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+        balance_manager.add(201, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(202, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let ask_user_id = 201;
+        let market_conf = config::Market {
+            taker_fee: dec!(0.001),
+            maker_fee: dec!(0.001),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let ask_order_input = OrderInput {
+            taker_fee: dec!(0.001),
+            maker_fee: dec!(0.001),
+            post_only: true,
+            ..get_simple_order_input(ask_user_id, OrderSide::ASK, dec!(20.0), dec!(0.1))
+        };
+        let ask_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                ask_order_input,
+            )
+            .unwrap();
+
+        assert_eq!(ask_order.id, 1);
+        assert_eq!(ask_order.remain, dec!(20));
+
+        let bid_user_id = 202;
+        let bid_order_input = OrderInput {
+            taker_fee: dec!(0.001),
+            maker_fee: dec!(0.001),
+            post_only: true,
+            ..get_simple_order_input(bid_user_id, OrderSide::BID, dec!(10.0), dec!(0.1))
+        };
+        let bid_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                bid_order_input,
+            )
+            .unwrap();
+
+        // No trade occurred since limit and post only. This BID order should be finished.
+        assert_eq!(bid_order.id, 2);
+        assert_eq!(bid_order.remain, dec!(10));
+        assert_eq!(bid_order.finished_quote, dec!(0));
+        assert_eq!(bid_order.finished_base, dec!(0));
+        assert_eq!(bid_order.finished_fee, dec!(0));
+
+        let ask_order = market.get(ask_order.id).unwrap();
+        assert_eq!(ask_order.remain, dec!(20));
+        assert_eq!(ask_order.finished_quote, dec!(0));
+        assert_eq!(ask_order.finished_base, dec!(0));
+        assert_eq!(ask_order.finished_fee, dec!(0));
+
+        let bid_order_message = persistor.messages.last().unwrap();
+        match bid_order_message {
+            Message::OrderMessage(msg) => {
+                assert!(matches!(
+                    **msg,
+                    OrderMessage {
+                        event: OrderEventType::FINISH,
+                        order: Order { id: 2, user: 202, .. },
+                        ..
+                    }
+                ));
+            }
+            _ => panic!("expect OrderMessage only"),
+        }
+
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(980)
+        );
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()),
+            dec!(20)
+        );
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(300)
+        );
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
+            dec!(0)
+        );
+
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(1000)
+        );
+        assert_eq!(balance_manager.get(bid_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(300)
+        );
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
+            dec!(0)
+        );
+    }
+
+    #[test]
+    fn test_stop_limit_order_triggered_by_trade() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        for user_id in 301..=303 {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        }
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let mut put = |market: &mut Market, user_id, side, type_, amount, price, trigger_price| {
+            let order_input = OrderInput {
+                type_,
+                trigger_price,
+                ..get_simple_order_input(user_id, side, amount, price)
+            };
+            market.put_order(
+                sequencer,
+                (&mut *balance_manager).into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input,
+            )
+        };
+
+        // sell 5 at 0.09 once the price falls to 0.1
+        let stop_order = put(
+            &mut market,
+            303,
+            OrderSide::ASK,
+            OrderType::STOP_LIMIT,
+            dec!(5),
+            dec!(0.09),
+            dec!(0.1),
+        )
+        .unwrap();
+        assert_eq!(stop_order.frozen, dec!(5));
+        assert_eq!(market.stop_asks.len(), 1);
+        assert!(market.asks.is_empty());
+
+        let bid_order = put(&mut market, 301, OrderSide::BID, OrderType::LIMIT, dec!(10), dec!(0.1), dec!(0)).unwrap();
+        // this trade sets the price to 0.1 and fires the stop order, which takes 5 more from the bid
+        put(&mut market, 302, OrderSide::ASK, OrderType::LIMIT, dec!(2), dec!(0.1), dec!(0)).unwrap();
+
+        assert!(market.stop_asks.is_empty());
+        assert!(market.get(stop_order.id).is_none());
+        assert_eq!(market.get(bid_order.id).unwrap().remain, dec!(3));
+        assert_eq!(market.price, dec!(0.1));
+
+        // a stop order with a trigger price which is already reached is rejected
+        assert!(put(
+            &mut market,
+            303,
+            OrderSide::BID,
+            OrderType::STOP_LIMIT,
+            dec!(5),
+            dec!(0.2),
+            dec!(0.1)
+        )
+        .is_err());
+
+        let triggered = persistor.messages.iter().any(|msg| match msg {
+            Message::OrderMessage(msg) => msg.event == OrderEventType::TRIGGERED && msg.order.id == stop_order.id,
+            _ => false,
+        });
+        assert!(triggered);
+
+        assert_eq!(balance_manager.get(303, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(995));
+        assert_eq!(balance_manager.get(303, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+        assert_eq!(balance_manager.get(303, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(300.5));
+    }
+
+    #[test]
+    fn test_immediate_or_cancel_and_fill_or_kill() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        for user_id in 401..=402 {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        }
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let mut put = |market: &mut Market, user_id, side, amount, time_in_force| {
+            let order_input = OrderInput {
+                time_in_force,
+                ..get_simple_order_input(user_id, side, amount, dec!(0.1))
+            };
+            market.put_order(
+                sequencer,
+                (&mut *balance_manager).into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input,
+            )
+        };
+
+        let ask_order = put(&mut market, 401, OrderSide::ASK, dec!(10), TimeInForce::GTC).unwrap();
+
+        // only 10 is available, the order is rejected before anything happens
+        assert!(put(&mut market, 402, OrderSide::BID, dec!(20), TimeInForce::FOK).is_err());
+        assert_eq!(market.get(ask_order.id).unwrap().remain, dec!(10));
+
+        let ioc_order = put(&mut market, 402, OrderSide::BID, dec!(15), TimeInForce::IOC).unwrap();
+        assert_eq!(ioc_order.remain, dec!(5));
+        assert_eq!(ioc_order.cancel_reason, Some(CancelReason::IMMEDIATE_OR_CANCEL));
+        assert!(market.get(ioc_order.id).is_none());
+        assert!(market.asks.is_empty());
+        assert!(market.bids.is_empty());
+
+        put(&mut market, 401, OrderSide::ASK, dec!(5), TimeInForce::GTC).unwrap();
+        let fok_order = put(&mut market, 402, OrderSide::BID, dec!(5), TimeInForce::FOK).unwrap();
+        assert_eq!(fok_order.remain, dec!(0));
+        assert_eq!(fok_order.cancel_reason, None);
+
+        let trade_count = persistor
+            .messages
+            .iter()
+            .filter(|msg| matches!(msg, Message::TradeMessage(_)))
+            .count();
+        assert_eq!(trade_count, 2);
+        assert_eq!(balance_manager.get(402, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(1015));
+        assert_eq!(balance_manager.get(402, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(298.5));
+        assert_eq!(balance_manager.get(402, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(0));
+    }
+
+    #[test]
+    fn test_amend_order() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        for user_id in 501..=502 {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        }
+
+        let sequencer = &mut Sequencer::default();
+        let persistor = &mut crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let bid_input = |user_id, amount| get_simple_order_input(user_id, OrderSide::BID, amount, dec!(0.1));
+        let first = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                bid_input(501, dec!(100)),
+            )
+            .unwrap();
+        let second = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                bid_input(502, dec!(100)),
+            )
+            .unwrap();
+        assert_eq!(balance_manager.get(501, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(10));
+
+        // decreasing the amount keeps the place in the queue
+        let amended = market
+            .amend(
+                sequencer,
+                balance_manager.into(),
+                persistor,
+                first.id,
+                Some(dec!(50)),
+                None,
+                first.update_time + 1.0,
+            )
+            .unwrap();
+        // the order is stamped with the time of the operation, not the clock
+        assert_eq!(amended.update_time, first.update_time + 1.0);
+        assert_eq!(amended.priority, first.priority);
+        assert_eq!(amended.remain, dec!(50));
+        assert_eq!(market.bids.values().next().unwrap().borrow().id, first.id);
+        assert_eq!(balance_manager.get(501, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(5));
+        assert_eq!(balance_manager.get(501, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(295));
+
+        // increasing it sends the order to the back of the queue
+        let amended = market
+            .amend(
+                sequencer,
+                balance_manager.into(),
+                persistor,
+                first.id,
+                Some(dec!(200)),
+                None,
+                current_timestamp(),
+            )
+            .unwrap();
+        assert!(amended.priority > second.priority);
+        assert_eq!(market.bids.values().next().unwrap().borrow().id, second.id);
+        // the new priority does not take an order id
+        assert_eq!(sequencer.get_order_id(), second.id);
+        assert_eq!(market.bids.len(), 2);
+        assert_eq!(balance_manager.get(501, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(20));
+
+        // a better price is a new level
+        let amended = market
+            .amend(
+                sequencer,
+                balance_manager.into(),
+                persistor,
+                first.id,
+                None,
+                Some(dec!(0.2)),
+                current_timestamp(),
+            )
+            .unwrap();
+        assert_eq!(amended.price, dec!(0.2));
+        assert_eq!(market.bids.values().next().unwrap().borrow().id, first.id);
+        assert_eq!(market.get(first.id).unwrap().frozen, dec!(40));
+        assert_eq!(balance_manager.get(501, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(40));
+
+        assert!(market
+            .amend(
+                sequencer,
+                balance_manager.into(),
+                persistor,
+                first.id,
+                Some(dec!(5000)),
+                None,
+                current_timestamp()
+            )
+            .is_err());
+        assert_eq!(market.get(first.id).unwrap().amount, dec!(200));
+    }
+
+    #[test]
+    fn test_self_trade_prevention_modes() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        for user_id in 601..=602 {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        }
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        assert_eq!(market.self_trade_prevention, SelfTradePrevention::CANCEL_NEWEST);
+        let mut put = |market: &mut Market, user_id, side, amount, self_trade_prevention| {
+            let order_input = OrderInput {
+                self_trade_prevention,
+                ..get_simple_order_input(user_id, side, amount, dec!(0.1))
+            };
+            market.put_order(
+                sequencer,
+                (&mut *balance_manager).into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input,
+            )
+        };
+
+        // the market does not allow self trades
+        assert!(put(&mut market, 601, OrderSide::BID, dec!(10), Some(SelfTradePrevention::NONE)).is_err());
+
+        // cancel oldest: the own ask is cancelled and the bid trades with the next one
+        let old_ask = put(&mut market, 601, OrderSide::ASK, dec!(10), None).unwrap();
+        put(&mut market, 602, OrderSide::ASK, dec!(10), None).unwrap();
+        let bid = put(&mut market, 601, OrderSide::BID, dec!(10), Some(SelfTradePrevention::CANCEL_OLDEST)).unwrap();
+        assert_eq!(bid.remain, dec!(0));
+        assert_eq!(bid.cancel_reason, None);
+        assert!(market.get(old_ask.id).is_none());
+        assert!(market.asks.is_empty());
+
+        // decrement and cancel: the smaller bid disappears, the ask shrinks and keeps resting
+        let ask = put(&mut market, 601, OrderSide::ASK, dec!(10), None).unwrap();
+        let bid = put(
+            &mut market,
+            601,
+            OrderSide::BID,
+            dec!(4),
+            Some(SelfTradePrevention::DECREMENT_AND_CANCEL),
+        )
+        .unwrap();
+        assert_eq!(bid.remain, dec!(0));
+        assert_eq!(bid.cancel_reason, Some(CancelReason::SELF_TRADE));
+        let ask = market.get(ask.id).unwrap();
+        assert_eq!(ask.amount, dec!(6));
+        assert_eq!(ask.remain, dec!(6));
+        assert_eq!(balance_manager.get(601, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(6));
+
+        // cancel both
+        let bid = put(&mut market, 601, OrderSide::BID, dec!(1), Some(SelfTradePrevention::CANCEL_BOTH)).unwrap();
+        assert_eq!(bid.cancel_reason, Some(CancelReason::SELF_TRADE));
+        assert!(market.get(ask.id).is_none());
+        assert!(market.asks.is_empty());
+        assert!(market.bids.is_empty());
+        assert_eq!(balance_manager.get(601, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+
+        let cancelled_makers = persistor
+            .messages
+            .iter()
+            .filter(|msg| match msg {
+                Message::OrderMessage(msg) => {
+                    msg.event == OrderEventType::FINISH && msg.order.side == OrderSide::ASK && msg.order.cancel_reason.is_some()
+                }
+                _ => false,
+            })
+            .count();
+        assert_eq!(cancelled_makers, 2);
+        let trade_count = persistor
+            .messages
+            .iter()
+            .filter(|msg| matches!(msg, Message::TradeMessage(_)))
+            .count();
+        assert_eq!(trade_count, 1);
+    }
+
+    #[test]
+    fn test_self_trade_allowed() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(601, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+        balance_manager.add(601, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let persistor = &mut crate::persist::DummyPersistor::default();
+        let market_conf = config::Market {
+            self_trade_prevention: Some(SelfTradePrevention::NONE),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                get_simple_order_input(601, OrderSide::ASK, dec!(10), dec!(0.1)),
+            )
+            .unwrap();
+
+        // both sides of the trade settle the same user with the same trade id
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                get_simple_order_input(601, OrderSide::BID, dec!(10), dec!(0.1)),
+            )
+            .unwrap();
+        assert_eq!(bid.remain, dec!(0));
+        assert_eq!(bid.finished_base, dec!(10));
+        assert!(market.asks.is_empty());
+        assert_eq!(balance_manager.get(601, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(1000));
+        assert_eq!(balance_manager.get(601, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(300));
+        assert_eq!(balance_manager.get(601, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+    }
+
+    #[test]
+    fn test_client_order_id() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(701, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(702, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let persistor = &mut crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let ask_input = |client_order_id| OrderInput {
+            client_order_id,
+            ..get_simple_order_input(701, OrderSide::ASK, dec!(10), dec!(0.1))
+        };
+        let order = market
+            .put_order(sequencer, balance_manager.into(), &mut update_controller, persistor, ask_input(7))
+            .unwrap();
+        assert_eq!(order.client_order_id, 7);
+        assert_eq!(market.get_by_client_order_id(701, 7).unwrap().id, order.id);
+        assert!(market.get_by_client_order_id(702, 7).is_none());
+
+        // the resubmission returns the same order
+        let resubmitted = market
+            .put_order(sequencer, balance_manager.into(), &mut update_controller, persistor, ask_input(7))
+            .unwrap();
+        assert_eq!(resubmitted.id, order.id);
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(balance_manager.get(701, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(10));
+
+        // orders without a client order id are never merged
+        market
+            .put_order(sequencer, balance_manager.into(), &mut update_controller, persistor, ask_input(0))
+            .unwrap();
+        market
+            .put_order(sequencer, balance_manager.into(), &mut update_controller, persistor, ask_input(0))
+            .unwrap();
+        assert_eq!(market.asks.len(), 3);
+
+        // the finished order is still found, and its resubmission is not placed again
+        market.cancel(sequencer, balance_manager.into(), persistor, order.id, current_timestamp());
+        let finished = market.get_by_client_order_id(701, 7).unwrap();
+        assert_eq!(finished.id, order.id);
+        assert_eq!(finished.cancel_reason, Some(CancelReason::USER));
+        let resubmitted = market
+            .put_order(sequencer, balance_manager.into(), &mut update_controller, persistor, ask_input(7))
+            .unwrap();
+        assert_eq!(resubmitted.id, order.id);
+        assert_eq!(market.asks.len(), 2);
+
+        // an order filled at once is remembered as well
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                OrderInput {
+                    user_id: 702,
+                    side: OrderSide::BID,
+                    amount: dec!(1),
+                    ..ask_input(9)
+                },
+            )
+            .unwrap();
+        assert!(market.get(bid.id).is_none());
+        assert_eq!(market.get_by_client_order_id(702, 9).unwrap().remain, dec!(0));
+
+        // the id can be used again once the order is beyond the retention
+        market.client_order_retention = 1;
+        market.remember_client_order(Order {
+            id: bid.id + 1,
+            client_order_id: 8,
+            ..finished
+        });
+        assert!(market.get_by_client_order_id(701, 7).is_none());
+        assert!(market.get_by_client_order_id(702, 9).is_none());
+        assert!(market.get_by_client_order_id(701, 8).is_some());
+        let new_order = market
+            .put_order(sequencer, balance_manager.into(), &mut update_controller, persistor, ask_input(7))
+            .unwrap();
+        assert_ne!(new_order.id, order.id);
+    }
+
+    #[test]
+    fn test_call_auction() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        for user_id in 801..=802 {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        }
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        market.start_auction().unwrap();
+        assert!(market.start_auction().is_err());
+
+        let mut put = |market: &mut Market, user_id, side, type_, amount, price| {
+            let order_input = OrderInput {
+                type_,
+                ..get_simple_order_input(user_id, side, amount, price)
+            };
+            market.put_order(
+                sequencer,
+                (&mut *balance_manager).into(),
+                &mut update_controller,
+                &mut persistor,
+                order_input,
+            )
+        };
+
+        let high_bid = put(&mut market, 801, OrderSide::BID, OrderType::LIMIT, dec!(10), dec!(0.12)).unwrap();
+        put(&mut market, 801, OrderSide::BID, OrderType::LIMIT, dec!(10), dec!(0.10)).unwrap();
+        put(&mut market, 802, OrderSide::ASK, OrderType::LIMIT, dec!(5), dec!(0.09)).unwrap();
+        let mid_ask = put(&mut market, 802, OrderSide::ASK, OrderType::LIMIT, dec!(10), dec!(0.11)).unwrap();
+        put(&mut market, 802, OrderSide::ASK, OrderType::LIMIT, dec!(10), dec!(0.13)).unwrap();
+        assert!(put(&mut market, 801, OrderSide::BID, OrderType::MARKET, dec!(1), dec!(0)).is_err());
+
+        // nothing is matched during the auction, even if the book is crossed
+        assert_eq!(market.trade_count, 0);
+        assert_eq!(market.bids.len(), 2);
+        assert_eq!(market.asks.len(), 3);
+
+        // 10 can be traded at both 0.11 and 0.12 with the same imbalance, the price closer to the last one is chosen
+        assert_eq!(market.auction_price(), Some((dec!(0.11), dec!(10))));
+        let auction_price = market
+            .end_auction(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                current_timestamp(),
+            )
+            .unwrap();
+        assert_eq!(auction_price, Some(dec!(0.11)));
+        assert_eq!(market.phase, MarketPhase::CONTINUOUS);
+        assert_eq!(market.price, dec!(0.11));
+        assert_eq!(market.trade_count, 2);
+        assert!(market.get(high_bid.id).is_none());
+        assert_eq!(market.get(mid_ask.id).unwrap().remain, dec!(5));
+        assert_eq!(market.bids.values().next().unwrap().borrow().price, dec!(0.10));
+        assert_eq!(market.asks.values().next().unwrap().borrow().price, dec!(0.11));
+
+        // all trades are done at the auction price, the bid gets back what it froze above it
+        let trade_prices: Vec<Decimal> = persistor
+            .messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::TradeMessage(trade) => Some(trade.price),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(trade_prices, vec![dec!(0.11), dec!(0.11)]);
+        assert_eq!(balance_manager.get(801, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(1010));
+        assert_eq!(balance_manager.get(801, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(1));
+        assert_eq!(balance_manager.get(801, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(297.9));
+        assert_eq!(balance_manager.get(802, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(301.1));
+        assert_eq!(balance_manager.get(802, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(15));
+    }
+
+    #[test]
+    fn test_trading_status() {
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        assert!(market.check_order_accepted(false).is_ok());
+        assert!(market.check_cancel_accepted().is_ok());
+
+        market.trading_status = TradingStatus::POST_ONLY;
+        assert!(market.check_order_accepted(false).is_err());
+        assert!(market.check_order_accepted(true).is_ok());
+        assert!(market.check_cancel_accepted().is_ok());
+
+        market.trading_status = TradingStatus::CANCEL_ONLY;
+        assert!(market.check_order_accepted(true).is_err());
+        assert!(market.check_cancel_accepted().is_ok());
+
+        market.trading_status = TradingStatus::HALTED;
+        assert!(market.check_order_accepted(true).is_err());
+        assert!(market.check_cancel_accepted().is_err());
+
+        market.reset();
+        assert_eq!(market.trading_status, TradingStatus::OPEN);
+    }
+
+    #[test]
+    fn test_price_band() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(601, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(602, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let persistor = &mut crate::persist::DummyPersistor::default();
+        let market_conf = config::Market {
+            price_band: dec!(0.1),
+            halt_band: dec!(0.2),
+            halt_cooldown: 60,
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let input = |user_id, side, type_, amount, price| OrderInput {
+            type_,
+            ..get_simple_order_input(user_id, side, amount, price)
+        };
+
+        // there is no band before the first trade
+        for price in [dec!(100), dec!(105), dec!(115), dec!(140)] {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    persistor,
+                    input(601, OrderSide::ASK, OrderType::LIMIT, dec!(1), price),
+                )
+                .unwrap();
+        }
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                input(602, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(100)),
+            )
+            .unwrap();
+        assert_eq!(market.price, dec!(100));
+
+        // a resting order cannot be moved out of the band
+        let ask = market.asks.values().next().unwrap().deep();
+        assert!(market
+            .amend(
+                sequencer,
+                balance_manager.into(),
+                persistor,
+                ask.id,
+                None,
+                Some(dec!(111)),
+                current_timestamp()
+            )
+            .is_err());
+
+        // limit orders out of the band are rejected
+        assert!(market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                input(602, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(111)),
+            )
+            .is_err());
+
+        // a fill or kill stop order, triggered by the next trade, could only be filled beyond the bands
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                OrderInput {
+                    trigger_price: dec!(101),
+                    time_in_force: TimeInForce::FOK,
+                    ..input(602, OrderSide::BID, OrderType::STOP_LIMIT, dec!(2), dec!(140))
+                },
+            )
+            .unwrap();
+
+        // a market order stops at the band: 115 is 15% away from 100
+        let order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                input(602, OrderSide::BID, OrderType::MARKET, dec!(3), dec!(0)),
+            )
+            .unwrap();
+        assert_eq!(order.finished_base, dec!(1));
+        assert_eq!(market.price, dec!(105));
+        assert_eq!(market.trading_status, TradingStatus::OPEN);
+        // the stop order is killed without trading
+        assert!(market.stop_bids.is_empty());
+        assert_eq!(market.asks.len(), 2);
+        assert_eq!(balance_manager.get(602, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(0));
+
+        // 115 is in the band around 105 now, but 140 is 33% away from 105 and halts the market.
+        // The order is logged an hour ago, when the cooldown is long over by the wall clock
+        let time = current_timestamp() - 3600.0;
+        let order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                OrderInput {
+                    time,
+                    ..input(602, OrderSide::BID, OrderType::MARKET, dec!(3), dec!(0))
+                },
+            )
+            .unwrap();
+        assert_eq!(order.finished_base, dec!(1));
+        assert_eq!(market.price, dec!(115));
+        assert_eq!(market.trading_status, TradingStatus::HALTED);
+        assert_eq!(market.asks.len(), 1);
+
+        // the cooldown starts at the time the order is logged with
+        assert_eq!(market.halted_until, Some(time + 60.0));
+        assert!(!market.is_halt_expired(time + 59.0));
+        assert!(market.is_halt_expired(time + 60.0));
+        market.set_trading_status(TradingStatus::OPEN);
+        assert!(market.halted_until.is_none());
+    }
+
+    #[test]
+    fn test_fee_schedule() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(901, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(902, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let persistor = &mut crate::persist::DummyPersistor::default();
+        let market_conf = config::Market {
+            taker_fee: dec!(0.002),
+            maker_fee: dec!(0.001),
+            fee_tiers: vec![config::FeeTier {
+                volume: dec!(100),
+                taker_fee: dec!(0.001),
+                maker_fee: dec!(0),
+            }],
+            ..get_simple_market_config()
+        };
+        // the USDT fees go to the default receiver 0
+        let settings = Settings {
+            fee_receivers: [(MockAsset::ETH.id(), 999)].into_iter().collect(),
+            ..Settings::default()
+        };
+        let mut market = Market::new(&market_conf, &settings, balance_manager).unwrap();
+        let now = current_timestamp();
+        let input = |user_id, side, taker_fee| OrderInput {
+            taker_fee,
+            time: now,
+            ..get_simple_order_input(user_id, side, dec!(10), dec!(5))
+        };
+
+        // the client cannot choose a lower rate
+        assert!(market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                input(901, OrderSide::ASK, dec!(0.001))
+            )
+            .is_err());
+
+        for _ in 0..2 {
+            let ask = market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    persistor,
+                    input(901, OrderSide::ASK, dec!(0)),
+                )
+                .unwrap();
+            assert_eq!((ask.taker_fee, ask.maker_fee), (dec!(0.002), dec!(0.001)));
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    persistor,
+                    input(902, OrderSide::BID, dec!(0.002)),
+                )
+                .unwrap();
+        }
+        assert_eq!(market.fee_schedule.volume(901, now), dec!(100));
+        // the user maker fee of 0.001 is paid in USDT
+        assert_eq!(balance_manager.get(901, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(99.9));
+        assert_eq!(balance_manager.get(0, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(0.1));
+        assert_eq!(balance_manager.get(999, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(0.04));
+
+        // both users reach the tier
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                input(901, OrderSide::ASK, dec!(0)),
+            )
+            .unwrap();
+        assert_eq!((ask.taker_fee, ask.maker_fee), (dec!(0.001), dec!(0)));
+
+        // an override wins over the tiers
+        market.fee_schedule.overrides.insert(
+            902,
+            FeeRate {
+                taker_fee: dec!(0),
+                maker_fee: dec!(0),
+            },
+        );
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                input(902, OrderSide::BID, dec!(0)),
+            )
+            .unwrap();
+        assert_eq!((bid.taker_fee, bid.maker_fee), (dec!(0), dec!(0)));
+        assert_eq!(balance_manager.get(902, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(29.96));
+        // nothing is lost to the fees
+        assert_eq!(balance_manager.status(&MockAsset::ETH.id()).total, dec!(100));
+        assert_eq!(balance_manager.status(&MockAsset::USDT.id()).total, dec!(1000));
+
+        // the volume leaves the window after 30 days
+        assert_eq!(market.fee_schedule.volume(901, now + 30.0 * 86400.0), dec!(0));
+        // the tier is decided by the time of the order, which a replay takes from the log
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                persistor,
+                OrderInput {
+                    time: now + 30.0 * 86400.0,
+                    ..input(901, OrderSide::ASK, dec!(0))
+                },
+            )
+            .unwrap();
+        assert_eq!((ask.taker_fee, ask.maker_fee), (dec!(0.002), dec!(0.001)));
+        assert_eq!(ask.create_time, now + 30.0 * 86400.0);
+    }
+
+    #[test]
+    fn test_iceberg_order() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(1001, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(1002, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(3));
+        balance_manager.add(1003, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let input = |user_id, side, amount, display_amount| OrderInput {
+            display_amount,
+            ..get_simple_order_input(user_id, side, amount, dec!(1))
+        };
+
+        let iceberg = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1001, OrderSide::ASK, dec!(10), dec!(2)),
+            )
+            .unwrap();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1002, OrderSide::ASK, dec!(3), dec!(0)),
+            )
+            .unwrap();
+        assert_eq!(market.depth(10, &dec!(0)).asks[0].amount, dec!(5));
+        // the whole amount is frozen, but only the visible part is published
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(10));
+        match persistor.messages.first().unwrap() {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.order.id, iceberg.id);
+                assert_eq!((msg.order.amount, msg.order.remain), (dec!(2), dec!(2)));
+            }
+            _ => panic!("expect OrderMessage"),
+        }
+
+        // the visible part is filled first, then the refreshed part queues behind the order of 1002
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1003, OrderSide::BID, dec!(4), dec!(0)),
+            )
+            .unwrap();
+        let iceberg = market.get(iceberg.id).unwrap();
+        assert_eq!((iceberg.remain, iceberg.visible), (dec!(8), dec!(2)));
+        assert_eq!(market.get(iceberg.id + 1).unwrap().remain, dec!(1));
+        assert_eq!(market.asks.values().next().unwrap().borrow().user, 1002);
+        assert_eq!(market.depth(10, &dec!(0)).asks[0].amount, dec!(3));
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(8));
+    }
+
+    #[test]
+    fn test_order_group() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(1001, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(1003, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let input = |user_id, side, type_, amount, price, trigger_price| OrderInput {
+            type_,
+            trigger_price,
+            ..get_simple_order_input(user_id, side, amount, price)
+        };
+        let take_profit = input(1001, OrderSide::ASK, OrderType::LIMIT, dec!(2), dec!(1.2), dec!(0));
+        let stop_loss = input(1001, OrderSide::ASK, OrderType::STOP_LIMIT, dec!(2), dec!(0.8), dec!(0.8));
+
+        // an order which cannot rest rejects the whole group
+        let immediate = OrderInput {
+            time_in_force: TimeInForce::IOC,
+            ..input(1001, OrderSide::ASK, OrderType::LIMIT, dec!(2), dec!(1.3), dec!(0))
+        };
+        assert!(market
+            .put_order_group(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                vec![take_profit, immediate],
+                OcoTrigger::PARTIAL_FILL,
+            )
+            .is_err());
+        assert!(market.orders.is_empty() && market.groups.is_empty());
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+
+        let take_profit = input(1001, OrderSide::ASK, OrderType::LIMIT, dec!(2), dec!(1.2), dec!(0));
+        let orders = market
+            .put_order_group(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                vec![take_profit, stop_loss],
+                OcoTrigger::PARTIAL_FILL,
+            )
+            .unwrap();
+        let (take_profit, stop_loss) = (orders[0], orders[1]);
+        assert_eq!((take_profit.group_id, stop_loss.group_id), (take_profit.id, take_profit.id));
+        assert_eq!(market.groups[&take_profit.id].len(), 2);
+
+        // a partial fill of the take profit order cancels the stop order
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1003, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1.2), dec!(0)),
+            )
+            .unwrap();
+        assert_eq!(market.get(take_profit.id).unwrap().remain, dec!(1));
+        assert!(market.get(stop_loss.id).is_none());
+        assert!(market.stop_asks.is_empty());
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(1));
+        // the orderbook deltas come after the order messages
+        match persistor
+            .messages
+            .iter()
+            .rev()
+            .find(|msg| matches!(msg, Message::OrderMessage(_)))
+            .unwrap()
+        {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.event, OrderEventType::FINISH);
+                assert_eq!((msg.order.id, msg.order.group_id), (stop_loss.id, take_profit.id));
+                assert_eq!(msg.order.cancel_reason, Some(CancelReason::ORDER_GROUP));
+            }
+            _ => panic!("expect OrderMessage"),
+        }
+
+        // with FULL_FILL, the other orders are cancelled only after an order is fully filled
+        let orders = market
+            .put_order_group(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                vec![
+                    input(1001, OrderSide::ASK, OrderType::LIMIT, dec!(2), dec!(1.3), dec!(0)),
+                    input(1001, OrderSide::ASK, OrderType::LIMIT, dec!(2), dec!(1.4), dec!(0)),
+                ],
+                OcoTrigger::FULL_FILL,
+            )
+            .unwrap();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1003, OrderSide::BID, OrderType::LIMIT, dec!(2), dec!(1.3), dec!(0)),
+            )
+            .unwrap();
+        assert!(market.get(take_profit.id).is_none());
+        assert_eq!(market.get(orders[0].id).unwrap().remain, dec!(1));
+        assert!(market.get(orders[1].id).is_some());
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1003, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1.3), dec!(0)),
+            )
+            .unwrap();
+        assert!(market.orders.is_empty() && market.groups.is_empty());
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+        assert_eq!(balance_manager.get(1001, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(6));
+    }
+
+    #[test]
+    fn test_shared_collateral() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(1001, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(1002, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+        balance_manager.pool_add(1001, &MockAsset::ETH.id(), &dec!(5));
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(5));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market_a = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_b_conf = config::Market {
+            name: String::from("ETH_USDT_B"),
+            ..get_simple_market_config()
+        };
+        let mut market_b = Market::new(&market_b_conf, &Settings::default(), balance_manager).unwrap();
+        let input = |user_id, side, amount, shared_collateral| OrderInput {
+            shared_collateral,
+            ..get_simple_order_input(user_id, side, amount, dec!(1))
+        };
+
+        // both asks draw on the same 5 ETH, but each of them must fit in it
+        let ask_a = market_a
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1001, OrderSide::ASK, dec!(4), true),
+            )
+            .unwrap();
+        let ask_b = market_b
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1001, OrderSide::ASK, dec!(4), true),
+            )
+            .unwrap();
+        assert!(market_a
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1001, OrderSide::ASK, dec!(6), true),
+            )
+            .is_err());
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(5));
+        assert_eq!(balance_manager.get(1001, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(5));
+
+        market_a
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1002, OrderSide::BID, dec!(3), false),
+            )
+            .unwrap();
+        assert_eq!(balance_manager.pool_get(1001, &MockAsset::ETH.id()), dec!(2));
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(2));
+
+        // a fill or kill order does not count on more than the pool can pay for
+        assert!(market_b
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                OrderInput {
+                    time_in_force: TimeInForce::FOK,
+                    ..input(1002, OrderSide::BID, dec!(3), false)
+                },
+            )
+            .is_err());
+
+        // the trade is capped by the pool, then the exhausted ask is cancelled
+        let bid = market_b
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1002, OrderSide::BID, dec!(3), false),
+            )
+            .unwrap();
+        assert_eq!(bid.remain, dec!(1));
+        assert!(market_b.get(ask_b.id).is_none());
+        match persistor
+            .messages
+            .iter()
+            .rev()
+            .find(|msg| matches!(msg, Message::OrderMessage(_)))
+            .unwrap()
+        {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.order.id, ask_b.id);
+                assert_eq!(msg.order.finished_base, dec!(2));
+                assert_eq!(msg.order.cancel_reason, Some(CancelReason::COLLATERAL_EXHAUSTED));
+            }
+            _ => panic!("expect OrderMessage"),
+        }
+
+        // the other market finds its order can no longer be paid for
+        assert_eq!(market_a.get(ask_a.id).unwrap().remain, dec!(1));
+        market_a.fit_pooled_orders(sequencer, balance_manager.into(), &mut persistor, current_timestamp());
+        assert!(market_a.get(ask_a.id).is_none());
+        assert!(market_a.pooled_orders.is_empty() && market_b.pooled_orders.is_empty());
+        assert!(balance_manager.pools.is_empty());
+        assert_eq!(balance_manager.get(1001, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+        assert_eq!(balance_manager.get(1001, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(5));
+        assert_eq!(balance_manager.get(1001, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(5));
+        assert_eq!(balance_manager.get(1002, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(5));
+    }
+
+    #[test]
+    fn test_order_expiry() {
         let mut update_controller = BalanceUpdateController::new();
         let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
-
-        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
-        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
-        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
-        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(1101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
 
         let sequencer = &mut Sequencer::default();
-        let mut persistor = crate::persist::DummyPersistor::default();
-        let ask_user_id = 101;
+        let mut persistor = crate::persist::MemBasedPersistor::default();
         let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
-        let ask_order_input = OrderInput {
-            user_id: ask_user_id,
-            side: OrderSide::ASK,
-            type_: OrderType::LIMIT,
-            amount: dec!(20.0),
-            price: dec!(0.1),
-            quote_limit: dec!(0),
-            taker_fee: dec!(0.001),
-            maker_fee: dec!(0.001),
-            market: market.name.to_string(),
-            post_only: false,
-            signature: [0; 64],
+        let input = |type_, trigger_price, time_in_force, expire_time| OrderInput {
+            type_,
+            trigger_price,
+            time_in_force,
+            expire_time,
+            ..get_simple_order_input(1101, OrderSide::ASK, dec!(2), dec!(1))
         };
-        let ask_order = market
+
+        // an order which never rests cannot expire
+        assert!(market
             .put_order(
                 sequencer,
                 balance_manager.into(),
                 &mut update_controller,
                 &mut persistor,
-                ask_order_input,
+                input(OrderType::LIMIT, dec!(0), TimeInForce::IOC, 100.0),
             )
-            .unwrap();
-        assert_eq!(ask_order.id, 1);
-        assert_eq!(ask_order.remain, dec!(20.0));
+            .is_err());
 
-        let bid_user_id = 102;
-        let bid_order_input = OrderInput {
-            user_id: bid_user_id,
-            side: OrderSide::BID,
-            type_: OrderType::MARKET,
-            amount: dec!(10.0),
-            price: dec!(0),
-            quote_limit: dec!(0),
-            taker_fee: dec!(0.001),
-            maker_fee: dec!(0.001),
-            market: market.name.to_string(),
-            post_only: false,
-            signature: [0; 64],
+        let mut put = |market: &mut Market, type_, trigger_price, expire_time| {
+            market
+                .put_order(
+                    sequencer,
+                    (&mut *balance_manager).into(),
+                    &mut update_controller,
+                    &mut persistor,
+                    input(type_, trigger_price, TimeInForce::GTC, expire_time),
+                )
+                .unwrap()
         };
-        let bid_order = market
-            .put_order(
+        let expiring = put(&mut market, OrderType::LIMIT, dec!(0), 100.0);
+        let stop = put(&mut market, OrderType::STOP_LIMIT, dec!(0.5), 200.0);
+        let kept = put(&mut market, OrderType::LIMIT, dec!(0), 0.0);
+        assert_eq!(market.expiring_orders.len(), 2);
+        assert!(!market.has_expired_orders(99.0));
+        assert!(market.has_expired_orders(100.0));
+
+        let expired = market.expire_orders(sequencer, balance_manager.into(), &mut persistor, 100.0);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, expiring.id);
+        assert_eq!(expired[0].cancel_reason, Some(CancelReason::EXPIRED));
+        assert!(market.get(expiring.id).is_none());
+        match persistor
+            .messages
+            .iter()
+            .rev()
+            .find(|msg| matches!(msg, Message::OrderMessage(_)))
+            .unwrap()
+        {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.event, OrderEventType::EXPIRED);
+                assert_eq!(msg.order.id, expiring.id);
+            }
+            _ => panic!("expect OrderMessage"),
+        }
+
+        let expired = market.expire_orders(sequencer, balance_manager.into(), &mut persistor, 300.0);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, stop.id);
+        assert!(market.stop_asks.is_empty() && market.expiring_orders.is_empty());
+        assert!(market.get(kept.id).is_some());
+        assert_eq!(balance_manager.get(1101, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(2));
+        assert_eq!(balance_manager.get(1101, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(8));
+    }
+
+    #[test]
+    fn test_mass_cancel() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(1201, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(1201, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(10));
+        balance_manager.add(1202, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let mut put = |market: &mut Market, user_id, side, price| {
+            market
+                .put_order(
+                    sequencer,
+                    (&mut *balance_manager).into(),
+                    &mut update_controller,
+                    &mut persistor,
+                    get_simple_order_input(user_id, side, dec!(1), price),
+                )
+                .unwrap()
+        };
+        let ask_10 = put(&mut market, 1201, OrderSide::ASK, dec!(10));
+        let ask_12 = put(&mut market, 1201, OrderSide::ASK, dec!(12));
+        let ask_14 = put(&mut market, 1201, OrderSide::ASK, dec!(14));
+        let bid_5 = put(&mut market, 1201, OrderSide::BID, dec!(5));
+        let other_ask = put(&mut market, 1202, OrderSide::ASK, dec!(12));
+
+        // nothing is older than the time
+        let filter = MassCancelFilter {
+            before: Some(1.0),
+            ..Default::default()
+        };
+        assert!(market
+            .mass_cancel(
                 sequencer,
                 balance_manager.into(),
-                &mut update_controller,
                 &mut persistor,
-                bid_order_input,
+                Some(1201),
+                &filter,
+                current_timestamp()
             )
-            .unwrap();
-        // trade: price: 0.10 amount: 10
-        assert_eq!(bid_order.id, 2);
-        assert_eq!(bid_order.remain, dec!(0));
-        assert_eq!(bid_order.finished_quote, dec!(1));
-        assert_eq!(bid_order.finished_base, dec!(10));
-        assert_eq!(bid_order.finished_fee, dec!(0.01));
-
-        //market.print();
-
-        let ask_order = market.get(ask_order.id).unwrap();
-        assert_eq!(ask_order.remain, dec!(10));
-        assert_eq!(ask_order.finished_quote, dec!(1));
-        assert_eq!(ask_order.finished_base, dec!(10));
-        assert_eq!(ask_order.finished_fee, dec!(0.001));
+            .is_empty());
 
-        // original balance: btc 300, eth 1000
-        assert_eq!(
-            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
-            dec!(980)
-        );
-        assert_eq!(
-            balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()),
-            dec!(10)
+        // only the asks of the user priced at or above the bound
+        let filter = MassCancelFilter {
+            side: Some(OrderSide::ASK),
+            min_price: Some(dec!(11)),
+            ..Default::default()
+        };
+        let mut cancelled = market.mass_cancel(
+            sequencer,
+            balance_manager.into(),
+            &mut persistor,
+            Some(1201),
+            &filter,
+            current_timestamp(),
         );
+        cancelled.sort_unstable();
+        assert_eq!(cancelled, vec![ask_12.id, ask_14.id]);
+        assert!(market.get(ask_10.id).is_some());
+        assert!(market.get(other_ask.id).is_some());
+        assert_eq!(balance_manager.get(1201, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(1));
 
-        assert_eq!(
-            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
-            dec!(300.999)
-        );
-        assert_eq!(
-            balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
-            dec!(0)
+        // the bids of every user
+        let filter = MassCancelFilter {
+            side: Some(OrderSide::BID),
+            ..Default::default()
+        };
+        let cancelled = market.mass_cancel(
+            sequencer,
+            balance_manager.into(),
+            &mut persistor,
+            None,
+            &filter,
+            current_timestamp(),
         );
+        assert_eq!(cancelled, vec![bid_5.id]);
+        assert!(market.bids.is_empty());
+        assert_eq!(balance_manager.get(1201, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(10));
+        assert_eq!(market.asks.len(), 2);
+    }
 
-        assert_eq!(
-            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
-            dec!(1009.99)
-        );
-        assert_eq!(balance_manager.get(bid_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+    #[test]
+    fn test_orderbook_deltas() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(1301, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(1302, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
 
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let mut put = |market: &mut Market, persistor: &mut crate::persist::MemBasedPersistor, user_id, side, amount| {
+            market
+                .put_order(
+                    sequencer,
+                    (&mut *balance_manager).into(),
+                    &mut update_controller,
+                    persistor,
+                    get_simple_order_input(user_id, side, amount, dec!(10)),
+                )
+                .unwrap()
+        };
+        let deltas = |persistor: &crate::persist::MemBasedPersistor| -> Vec<(u64, OrderSide, Decimal, Decimal)> {
+            persistor
+                .messages
+                .iter()
+                .filter_map(|msg| match msg {
+                    Message::OrderBookDeltaMessage(delta) => Some((delta.sequence, delta.side, delta.price, delta.amount)),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        put(&mut market, &mut persistor, 1301, OrderSide::ASK, dec!(2));
+        let ask = put(&mut market, &mut persistor, 1301, OrderSide::ASK, dec!(1));
+        // fills the first ask and a half of the second, the level is published once
+        put(&mut market, &mut persistor, 1302, OrderSide::BID, dec!(2.5));
+        market.cancel(sequencer, balance_manager.into(), &mut persistor, ask.id, current_timestamp());
         assert_eq!(
-            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
-            dec!(299)
-        );
-        assert_eq!(
-            balance_manager.get(bid_user_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
-            dec!(0)
+            deltas(&persistor),
+            vec![
+                (1, OrderSide::ASK, dec!(10), dec!(2)),
+                (2, OrderSide::ASK, dec!(10), dec!(3)),
+                (3, OrderSide::ASK, dec!(10), dec!(0.5)),
+                (4, OrderSide::ASK, dec!(10), dec!(0)),
+            ]
         );
-
-        //assert_eq!(persistor.orders.len(), 3);
-        //assert_eq!(persistor.trades.len(), 1);
+        assert_eq!(market.book_sequence, 4);
+        assert!(market.asks.is_empty() && market.bids.is_empty());
     }
 
     #[test]
-    fn test_limit_post_only_orders() {
+    fn test_book_orders() {
         let mut update_controller = BalanceUpdateController::new();
         let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(1401, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
 
-        balance_manager.add(201, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
-        balance_manager.add(202, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
-        balance_manager.add(201, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
-        balance_manager.add(202, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let mut put = |market: &mut Market, price| {
+            market
+                .put_order(
+                    sequencer,
+                    (&mut *balance_manager).into(),
+                    &mut update_controller,
+                    &mut persistor,
+                    get_simple_order_input(1401, OrderSide::ASK, dec!(1), price),
+                )
+                .unwrap()
+        };
+        let ask_11 = put(&mut market, dec!(11));
+        let ask_10_first = put(&mut market, dec!(10));
+        let ask_10_second = put(&mut market, dec!(10));
+
+        let ids = |orders: Vec<Order>| orders.iter().map(|order| order.id).collect::<Vec<_>>();
+        let page = market.book_orders(OrderSide::ASK, None, 2);
+        assert_eq!(ids(page.clone()), vec![ask_10_first.id, ask_10_second.id]);
+        let last = page.last().unwrap();
+        let page = market.book_orders(OrderSide::ASK, Some((last.price, last.priority, last.id)), 2);
+        assert_eq!(ids(page), vec![ask_11.id]);
+
+        // the cursor still works after its order has left the book
+        market.cancel(
+            sequencer,
+            balance_manager.into(),
+            &mut persistor,
+            ask_10_second.id,
+            current_timestamp(),
+        );
+        let page = market.book_orders(OrderSide::ASK, Some((last.price, last.priority, last.id)), 2);
+        assert_eq!(ids(page), vec![ask_11.id]);
+        assert!(market.book_orders(OrderSide::BID, None, 2).is_empty());
+    }
+
+    #[test]
+    fn test_pegged_orders() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(1501, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1));
+        balance_manager.add(1501, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+        balance_manager.add(1502, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1));
+        balance_manager.add(1502, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(21));
 
         let sequencer = &mut Sequencer::default();
         let mut persistor = crate::persist::MemBasedPersistor::default();
-        let ask_user_id = 201;
         let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
-        let ask_order_input = OrderInput {
-            user_id: ask_user_id,
-            side: OrderSide::ASK,
-            type_: OrderType::LIMIT,
-            amount: dec!(20.0),
-            price: dec!(0.1),
-            quote_limit: dec!(0),
-            taker_fee: dec!(0.001),
-            maker_fee: dec!(0.001),
-            market: market.name.to_string(),
-            post_only: true,
-            signature: [0; 64],
+        let input = |user_id, side, amount, price, peg| OrderInput {
+            peg,
+            ..get_simple_order_input(user_id, side, amount, price)
         };
-        let ask_order = market
+
+        // nothing to peg to yet
+        assert!(market
             .put_order(
                 sequencer,
                 balance_manager.into(),
                 &mut update_controller,
                 &mut persistor,
-                ask_order_input,
+                input(1502, OrderSide::BID, dec!(2), dec!(0), PegReference::BEST_BID)
+            )
+            .is_err());
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1501, OrderSide::BID, dec!(1), dec!(10), PegReference::NONE),
+            )
+            .unwrap();
+        let ask_12 = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1501, OrderSide::ASK, dec!(1), dec!(12), PegReference::NONE),
+            )
+            .unwrap();
+        let pegged_bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1502, OrderSide::BID, dec!(2), dec!(0), PegReference::BEST_BID),
+            )
+            .unwrap();
+        assert_eq!(pegged_bid.price, dec!(10));
+        assert_eq!(balance_manager.get(1502, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(20));
+        let pegged_ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                input(1502, OrderSide::ASK, dec!(1), dec!(0), PegReference::MID),
             )
             .unwrap();
+        assert_eq!(pegged_ask.price, dec!(11));
 
-        assert_eq!(ask_order.id, 1);
-        assert_eq!(ask_order.remain, dec!(20));
+        // a better bid moves both, the pegged bid queues behind it and freezes more quote
+        let bid_10_5 = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                OrderInput {
+                    time: pegged_bid.create_time + 1.0,
+                    ..input(1501, OrderSide::BID, dec!(1), dec!(10.5), PegReference::NONE)
+                },
+            )
+            .unwrap();
+        assert_eq!(market.get(pegged_bid.id).unwrap().price, dec!(10.5));
+        // the moved orders take the time of the order which moved them
+        assert_eq!(market.get(pegged_bid.id).unwrap().update_time, pegged_bid.create_time + 1.0);
+        assert_eq!(market.bids.values().next().unwrap().borrow().id, bid_10_5.id);
+        assert_eq!(balance_manager.get(1502, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(21));
+        assert_eq!(market.get(pegged_ask.id).unwrap().price, dec!(11.25));
+        let moved = persistor.messages.iter().any(|msg| match msg {
+            Message::OrderMessage(msg) => msg.event == OrderEventType::UPDATE && msg.order.id == pegged_bid.id,
+            _ => false,
+        });
+        assert!(moved);
 
-        let bid_user_id = 202;
-        let bid_order_input = OrderInput {
-            user_id: bid_user_id,
-            side: OrderSide::BID,
-            type_: OrderType::LIMIT,
-            amount: dec!(10.0),
-            price: dec!(0.1),
-            quote_limit: dec!(0),
-            taker_fee: dec!(0.001),
-            maker_fee: dec!(0.001),
-            market: market.name.to_string(),
-            post_only: true,
-            signature: [0; 64],
-        };
-        let bid_order = market
+        // the user cannot pay for the next move
+        market
             .put_order(
                 sequencer,
                 balance_manager.into(),
                 &mut update_controller,
                 &mut persistor,
-                bid_order_input,
+                input(1501, OrderSide::BID, dec!(1), dec!(10.8), PegReference::NONE),
             )
             .unwrap();
+        assert!(market.get(pegged_bid.id).is_none());
+        assert_eq!(market.pegged_orders.len(), 1);
+        assert_eq!(balance_manager.get(1502, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(0));
+        assert_eq!(balance_manager.get(1502, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(21));
+        assert_eq!(market.get(pegged_ask.id).unwrap().price, dec!(11.4));
 
-        // No trade occurred since limit and post only. This BID order should be finished.
-        assert_eq!(bid_order.id, 2);
-        assert_eq!(bid_order.remain, dec!(10));
-        assert_eq!(bid_order.finished_quote, dec!(0));
-        assert_eq!(bid_order.finished_base, dec!(0));
-        assert_eq!(bid_order.finished_fee, dec!(0));
+        // the price is kept while the reference is missing
+        market.cancel(sequencer, balance_manager.into(), &mut persistor, ask_12.id, current_timestamp());
+        assert_eq!(market.get(pegged_ask.id).unwrap().price, dec!(11.4));
+    }
 
-        let ask_order = market.get(ask_order.id).unwrap();
-        assert_eq!(ask_order.remain, dec!(20));
-        assert_eq!(ask_order.finished_quote, dec!(0));
-        assert_eq!(ask_order.finished_base, dec!(0));
-        assert_eq!(ask_order.finished_fee, dec!(0));
+    #[test]
+    fn test_pro_rata_matching() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(1601, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(4));
+        balance_manager.add(1602, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(8));
+        balance_manager.add(1603, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(1604, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
 
-        let bid_order_message = persistor.messages.last().unwrap();
-        match bid_order_message {
-            Message::OrderMessage(msg) => {
-                assert!(matches!(
-                    **msg,
-                    OrderMessage {
-                        event: OrderEventType::FINISH,
-                        order: Order { id: 2, user: 202, .. },
-                        ..
-                    }
-                ));
-            }
-            _ => panic!("expect OrderMessage only"),
-        }
+        let sequencer = &mut Sequencer::default();
+        let persistor = &mut crate::persist::DummyPersistor::default();
+        let market_conf = config::Market {
+            matching: MatchingMode::PRO_RATA,
+            pro_rata_top_order_share: dec!(0.2),
+            pro_rata_min_allocation: dec!(1),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let mut put = |market: &mut Market, user_id, side, amount, price| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    persistor,
+                    get_simple_order_input(user_id, side, amount, price),
+                )
+                .unwrap()
+        };
 
-        assert_eq!(
-            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
-            dec!(980)
-        );
-        assert_eq!(
-            balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()),
-            dec!(20)
-        );
-        assert_eq!(
-            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
-            dec!(300)
-        );
-        assert_eq!(
-            balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
-            dec!(0)
-        );
+        let ask_a = put(&mut market, 1601, OrderSide::ASK, dec!(4), dec!(10));
+        let ask_b = put(&mut market, 1602, OrderSide::ASK, dec!(6), dec!(10));
+        let ask_c = put(&mut market, 1603, OrderSide::ASK, dec!(10), dec!(10));
+        let remain = |market: &Market| [ask_a.id, ask_b.id, ask_c.id].map(|id| market.get(id).unwrap().remain);
 
-        assert_eq!(
-            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
-            dec!(1000)
-        );
-        assert_eq!(balance_manager.get(bid_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
-        assert_eq!(
-            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
-            dec!(300)
-        );
-        assert_eq!(
-            balance_manager.get(bid_user_id, BalanceType::FREEZE, &MockAsset::USDT.id()),
-            dec!(0)
-        );
+        // 10 against 4, 6 and 10: A takes 20% = 2 first, the other 8 is split by the sizes left, 18 in all.
+        // A's split 8 * 2 / 18 = 0.8888 is below the minimum, B gets 8 * 6 / 18 = 2.6666 and C gets
+        // 8 * 10 / 18 = 4.4444, and the 0.889 left over goes to A by time priority
+        let order = put(&mut market, 1604, OrderSide::BID, dec!(10), dec!(10));
+        assert_eq!(order.finished_base, dec!(10));
+        assert_eq!(remain(&market), [dec!(1.111), dec!(3.3334), dec!(5.5556)]);
+
+        // 4 against 1.111, 3.3334 and 5.5556: A takes 0.8 first, the other 3.2 is split over 9.2,
+        // so B gets 1.1594 and C gets 1.9323, A's split 0.1081 is dropped and A gets the 0.1083 left over
+        put(&mut market, 1604, OrderSide::BID, dec!(4), dec!(10));
+        assert_eq!(remain(&market), [dec!(0.2027), dec!(2.174), dec!(3.6233)]);
+
+        // a taker larger than the level takes all of it before moving on to the next level
+        let ask_d = put(&mut market, 1602, OrderSide::ASK, dec!(2), dec!(11));
+        let order = put(&mut market, 1604, OrderSide::BID, dec!(8), dec!(11));
+        assert_eq!(order.finished_base, dec!(8));
+        assert!(market.asks.is_empty());
+        assert!(market.get(ask_d.id).is_none());
+        assert_eq!(market.price, dec!(11));
     }
 }
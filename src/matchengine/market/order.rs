@@ -1,4 +1,4 @@
-use crate::types::{OrderSide, OrderType};
+use crate::types::{CancelReason, OcoTrigger, OrderSide, OrderType, PegReference, SelfTradePrevention, TimeInForce};
 use crate::utils::InternedString;
 use fluidex_common::types::{BigInt, Decimal, Fr, FrExt};
 use serde::{Deserialize, Serialize};
@@ -9,12 +9,14 @@ use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 pub struct MarketKeyAsk {
     pub order_price: Decimal,
+    pub priority: u64,
     pub order_id: u64,
 }
 
 #[derive(PartialEq, Eq)]
 pub struct MarketKeyBid {
     pub order_price: Decimal,
+    pub priority: u64,
     pub order_id: u64,
 }
 
@@ -24,7 +26,7 @@ impl Ord for MarketKeyBid {
         if price_order != Ordering::Equal {
             price_order
         } else {
-            self.order_id.cmp(&other.order_id)
+            (self.priority, self.order_id).cmp(&(other.priority, other.order_id))
         }
     }
 }
@@ -37,14 +39,17 @@ fn test_order_sort() {
     {
         let o1 = MarketKeyBid {
             order_price: Decimal::zero(),
+            priority: 5,
             order_id: 5,
         };
         let o2 = MarketKeyBid {
             order_price: Decimal::zero(),
+            priority: 6,
             order_id: 6,
         };
         let o3 = MarketKeyBid {
             order_price: Decimal::one(),
+            priority: 7,
             order_id: 7,
         };
         assert!(o1 < o2);
@@ -53,19 +58,36 @@ fn test_order_sort() {
     {
         let o1 = MarketKeyAsk {
             order_price: Decimal::zero(),
+            priority: 5,
             order_id: 5,
         };
         let o2 = MarketKeyAsk {
             order_price: Decimal::zero(),
+            priority: 6,
             order_id: 6,
         };
         let o3 = MarketKeyAsk {
             order_price: Decimal::one(),
+            priority: 7,
             order_id: 7,
         };
         assert!(o1 < o2);
         assert!(o3 > o2);
     }
+    {
+        // an order which is refreshed gets a new priority and goes behind
+        let o1 = MarketKeyAsk {
+            order_price: Decimal::zero(),
+            priority: 8,
+            order_id: 5,
+        };
+        let o2 = MarketKeyAsk {
+            order_price: Decimal::zero(),
+            priority: 6,
+            order_id: 6,
+        };
+        assert!(o1 > o2);
+    }
 }
 
 impl PartialOrd for MarketKeyBid {
@@ -87,11 +109,42 @@ pub struct Order {
     pub type_: OrderType, // enum
     pub side: OrderSide,
     pub user: u32,
+    // chosen by the user to recognize the order, 0 means not set
+    #[serde(default)]
+    pub client_order_id: u64,
     pub post_only: bool,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub self_trade_prevention: SelfTradePrevention,
+    // the orders of a group are cancelled together once one of them is filled,
+    // the group id is the id of its first order, 0 means not in a group
+    #[serde(default)]
+    pub group_id: u64,
+    #[serde(default)]
+    pub oco_trigger: OcoTrigger,
+    // the order draws on the collateral pool of the user instead of freezing its own balance,
+    // see `BalanceManager::pools`
+    #[serde(default)]
+    pub shared_collateral: bool,
+    // the order is cancelled by the engine once this time has passed, 0 means never
+    #[serde(default)]
+    pub expire_time: f64,
+    // the price of a pegged order is its reference price plus the offset, it is moved
+    // by the engine when the reference changes, see `Market::reprice_pegged_orders`
+    #[serde(default)]
+    pub peg: PegReference,
+    #[serde(default)]
+    pub peg_offset: Decimal,
     #[serde(with = "crate::utils::serde::HexArray")]
     pub signature: [u8; 64],
     pub price: Decimal,
     pub amount: Decimal,
+    // the amount an iceberg order shows at a time, zero for other orders
+    #[serde(default)]
+    pub display_amount: Decimal,
+    // the last price which triggers a stop order, zero for other orders
+    pub trigger_price: Decimal,
     // fee rate when the order be treated as a taker
     pub maker_fee: Decimal,
     // fee rate when the order be treated as a taker, not useful when post_only
@@ -102,11 +155,23 @@ pub struct Order {
     // remain + finished_base == amount
     pub remain: Decimal,
     // frozen = if ask { amount (base) } else { amount * price (quote) }
+    // for an untriggered stop market bid, frozen is the quote to be spent once triggered
     pub frozen: Decimal,
     pub finished_base: Decimal,
     pub finished_quote: Decimal,
     pub finished_fee: Decimal,
     pub update_time: f64,
+    // time priority in the orderbook, taken from the priority sequence of the sequencer
+    // when the order is placed, and again when it loses its place
+    #[serde(default)]
+    pub priority: u64,
+    // set when the order is finished without being fully filled
+    #[serde(default)]
+    pub cancel_reason: Option<CancelReason>,
+    // the shown part of the remain of an iceberg order in the orderbook,
+    // a new one is taken from the hidden part when it is filled
+    #[serde(default)]
+    pub visible: Decimal,
 }
 
 /*
@@ -119,18 +184,85 @@ impl Order {
     pub fn get_ask_key(&self) -> MarketKeyAsk {
         MarketKeyAsk {
             order_price: self.price,
+            priority: self.priority,
             order_id: self.id,
         }
     }
     pub fn get_bid_key(&self) -> MarketKeyBid {
         MarketKeyBid {
             order_price: self.price,
+            priority: self.priority,
+            order_id: self.id,
+        }
+    }
+    // sell stops are triggered when the price falls to the trigger price,
+    // so the higher trigger price comes first
+    pub fn get_stop_ask_key(&self) -> MarketKeyBid {
+        MarketKeyBid {
+            order_price: self.trigger_price,
+            priority: self.id,
+            order_id: self.id,
+        }
+    }
+    // buy stops are triggered when the price rises to the trigger price,
+    // so the lower trigger price comes first
+    pub fn get_stop_bid_key(&self) -> MarketKeyAsk {
+        MarketKeyAsk {
+            order_price: self.trigger_price,
+            priority: self.id,
             order_id: self.id,
         }
     }
     pub fn is_ask(&self) -> bool {
         self.side == OrderSide::ASK
     }
+    pub fn is_stop_order(&self) -> bool {
+        self.type_ == OrderType::STOP_LIMIT || self.type_ == OrderType::STOP_MARKET
+    }
+    // whether the trades so far cancel the other orders of its group
+    pub fn fires_group(&self) -> bool {
+        self.group_id != 0
+            && match self.oco_trigger {
+                OcoTrigger::PARTIAL_FILL => !self.finished_base.is_zero(),
+                OcoTrigger::FULL_FILL => self.remain.is_zero(),
+            }
+    }
+    pub fn is_expired(&self, now: f64) -> bool {
+        self.expire_time > 0.0 && self.expire_time <= now
+    }
+    pub fn is_pegged(&self) -> bool {
+        self.peg != PegReference::NONE
+    }
+    pub fn is_iceberg(&self) -> bool {
+        !self.display_amount.is_zero()
+    }
+    // the remain others can see
+    pub fn visible_remain(&self) -> Decimal {
+        if self.is_iceberg() {
+            self.visible
+        } else {
+            self.remain
+        }
+    }
+    // the order as it is shown to others, the hidden part of an iceberg order is left out
+    pub fn displayed(&self) -> Order {
+        if !self.is_iceberg() {
+            return *self;
+        }
+        let hidden = self.remain - self.visible;
+        Order {
+            amount: self.amount - hidden,
+            remain: self.visible,
+            frozen: if self.shared_collateral {
+                self.frozen
+            } else if self.is_ask() {
+                self.visible
+            } else {
+                self.visible * self.price
+            },
+            ..*self
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -165,11 +297,29 @@ pub struct OrderInput {
     pub type_: OrderType,
     pub amount: Decimal,
     pub price: Decimal,
+    pub trigger_price: Decimal,
     pub quote_limit: Decimal,
-    pub taker_fee: Decimal, // FIXME fee should be determined inside engine rather than take from input
+    // the highest rates the client accepts, 0 means any. The rates come from the market fee schedule
+    pub taker_fee: Decimal,
     pub maker_fee: Decimal,
     pub market: String,
     pub post_only: bool,
+    pub time_in_force: TimeInForce,
+    // the market default is used when not given
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    // 0 means not set
+    pub client_order_id: u64,
+    // non-zero for iceberg orders
+    pub display_amount: Decimal,
+    // draw on the collateral pool of the user
+    pub shared_collateral: bool,
+    // 0 means never
+    pub expire_time: f64,
+    // the price is taken from the reference for pegged orders, so no price is given
+    pub peg: PegReference,
+    pub peg_offset: Decimal,
+    // when the order is placed, it is logged with the order so that a replay gets the same fee tier
+    pub time: f64,
     pub signature: [u8; 64],
 }
 
@@ -1,7 +1,10 @@
 use crate::asset::{AssetManager, BalanceManager};
 use crate::config;
+use crate::market::OrderInput;
+use crate::types::{OrderSide, OrderType, PegReference, TimeInForce};
 use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::rust_decimal_macros::*;
+use fluidex_common::utils::timeutil::current_timestamp;
 
 pub fn get_simple_market_config() -> config::Market {
     config::Market {
@@ -12,6 +15,7 @@ pub fn get_simple_market_config() -> config::Market {
         price_prec: 2,
         fee_prec: 2,
         min_amount: dec!(0.01),
+        ..Default::default()
     }
 }
 pub fn get_integer_prec_market_config() -> config::Market {
@@ -23,6 +27,34 @@ pub fn get_integer_prec_market_config() -> config::Market {
         price_prec: 0,
         fee_prec: 0,
         min_amount: dec!(0),
+        ..Default::default()
+    }
+}
+
+// a plain limit order of the simple market, tests change the fields they need
+pub fn get_simple_order_input(user_id: u32, side: OrderSide, amount: Decimal, price: Decimal) -> OrderInput {
+    OrderInput {
+        user_id,
+        side,
+        type_: OrderType::LIMIT,
+        amount,
+        price,
+        trigger_price: dec!(0),
+        quote_limit: dec!(0),
+        taker_fee: dec!(0),
+        maker_fee: dec!(0),
+        market: String::from("ETH_USDT"),
+        post_only: false,
+        time_in_force: TimeInForce::GTC,
+        self_trade_prevention: None,
+        client_order_id: 0,
+        display_amount: dec!(0),
+        shared_collateral: false,
+        expire_time: 0.0,
+        peg: PegReference::NONE,
+        peg_offset: dec!(0),
+        time: current_timestamp(),
+        signature: [0; 64],
     }
 }
 
@@ -1,10 +1,12 @@
 pub mod asset;
+pub mod audit;
 pub mod controller;
 pub mod dto;
 pub mod eth_guard;
 pub mod history;
 pub mod market;
 pub mod persist;
+pub mod rpc_ext;
 pub mod sequencer;
 pub mod server;
 pub mod user_manager;
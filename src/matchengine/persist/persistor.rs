@@ -1,8 +1,9 @@
 use crate::history::HistoryWriter;
 use crate::matchengine::market::{Order, Trade};
-use crate::message::{self, MessageManager, OrderMessage};
-pub use crate::models::{AccountDesc, BalanceHistory, InternalTx};
+use crate::message::{self, MessageManager, OrderBookDeltaMessage, OrderMessage, WithdrawStatusMessage};
+pub use crate::models::{AccountDesc, BalanceHistory, InternalTx, WithdrawHistory};
 use crate::types::OrderEventType;
+use fluidex_common::utils::timeutil::FTimestamp;
 
 ///////////////////////////// PersistExector interface ////////////////////////////
 
@@ -20,10 +21,12 @@ pub trait PersistExector: Send + Sync {
     fn put_balance(&mut self, balance: &BalanceHistory);
     fn put_deposit(&mut self, balance: &BalanceHistory);
     fn put_withdraw(&mut self, balance: &BalanceHistory);
+    fn put_withdraw_status(&mut self, status: &WithdrawStatusMessage);
     fn put_transfer(&mut self, tx: InternalTx);
     fn put_order(&mut self, order: &Order, at_step: OrderEventType);
     fn put_trade(&mut self, trade: &Trade);
     fn register_user(&mut self, user: AccountDesc);
+    fn put_orderbook_delta(&mut self, delta: &OrderBookDeltaMessage);
 }
 
 impl PersistExector for Box<dyn PersistExector + '_> {
@@ -42,6 +45,9 @@ impl PersistExector for Box<dyn PersistExector + '_> {
     fn put_withdraw(&mut self, balance: &BalanceHistory) {
         self.as_mut().put_withdraw(balance)
     }
+    fn put_withdraw_status(&mut self, status: &WithdrawStatusMessage) {
+        self.as_mut().put_withdraw_status(status)
+    }
     fn put_transfer(&mut self, tx: InternalTx) {
         self.as_mut().put_transfer(tx)
     }
@@ -54,6 +60,9 @@ impl PersistExector for Box<dyn PersistExector + '_> {
     fn register_user(&mut self, user: AccountDesc) {
         self.as_mut().register_user(user)
     }
+    fn put_orderbook_delta(&mut self, delta: &OrderBookDeltaMessage) {
+        self.as_mut().put_orderbook_delta(delta)
+    }
 }
 
 impl PersistExector for &mut Box<dyn PersistExector + '_> {
@@ -72,6 +81,9 @@ impl PersistExector for &mut Box<dyn PersistExector + '_> {
     fn put_withdraw(&mut self, balance: &BalanceHistory) {
         self.as_mut().put_withdraw(balance)
     }
+    fn put_withdraw_status(&mut self, status: &WithdrawStatusMessage) {
+        self.as_mut().put_withdraw_status(status)
+    }
     fn put_transfer(&mut self, tx: InternalTx) {
         self.as_mut().put_transfer(tx)
     }
@@ -84,6 +96,9 @@ impl PersistExector for &mut Box<dyn PersistExector + '_> {
     fn register_user(&mut self, user: AccountDesc) {
         self.as_mut().register_user(user)
     }
+    fn put_orderbook_delta(&mut self, delta: &OrderBookDeltaMessage) {
+        self.as_mut().put_orderbook_delta(delta)
+    }
 }
 
 ///////////////////////////// DummyPersistor  ////////////////////////////
@@ -107,10 +122,12 @@ impl PersistExector for DummyPersistor {
     fn put_balance(&mut self, _balance: &BalanceHistory) {}
     fn put_deposit(&mut self, _balance: &BalanceHistory) {}
     fn put_withdraw(&mut self, _balance: &BalanceHistory) {}
+    fn put_withdraw_status(&mut self, _status: &WithdrawStatusMessage) {}
     fn put_transfer(&mut self, _tx: InternalTx) {}
     fn put_order(&mut self, _order: &Order, _as_step: OrderEventType) {}
     fn put_trade(&mut self, _trade: &Trade) {}
     fn register_user(&mut self, _user: AccountDesc) {}
+    fn put_orderbook_delta(&mut self, _delta: &OrderBookDeltaMessage) {}
 }
 
 impl PersistExector for &mut DummyPersistor {
@@ -120,10 +137,12 @@ impl PersistExector for &mut DummyPersistor {
     fn put_balance(&mut self, _balance: &BalanceHistory) {}
     fn put_deposit(&mut self, _balance: &BalanceHistory) {}
     fn put_withdraw(&mut self, _balance: &BalanceHistory) {}
+    fn put_withdraw_status(&mut self, _status: &WithdrawStatusMessage) {}
     fn put_transfer(&mut self, _tx: InternalTx) {}
     fn put_order(&mut self, _order: &Order, _as_step: OrderEventType) {}
     fn put_trade(&mut self, _trade: &Trade) {}
     fn register_user(&mut self, _user: AccountDesc) {}
+    fn put_orderbook_delta(&mut self, _delta: &OrderBookDeltaMessage) {}
 }
 
 ///////////////////////////// MemBasedPersistor ////////////////////////////
@@ -155,12 +174,19 @@ impl PersistExector for MemBasedPersistor {
     fn put_withdraw(&mut self, balance: &BalanceHistory) {
         self.messages.push(message::Message::WithdrawMessage(Box::new(balance.into())));
     }
+    fn put_withdraw_status(&mut self, status: &WithdrawStatusMessage) {
+        self.messages
+            .push(message::Message::WithdrawStatusMessage(Box::new(status.clone())));
+    }
     fn put_transfer(&mut self, tx: InternalTx) {
         self.messages.push(message::Message::TransferMessage(Box::new(tx.into())));
     }
     fn register_user(&mut self, user: AccountDesc) {
         self.messages.push(message::Message::UserMessage(Box::new(user.into())));
     }
+    fn put_orderbook_delta(&mut self, delta: &OrderBookDeltaMessage) {
+        self.messages.push(message::Message::OrderBookDeltaMessage(Box::new(delta.clone())));
+    }
 }
 
 ///////////////////////////// FileBasedPersistor ////////////////////////////
@@ -201,6 +227,10 @@ impl PersistExector for FileBasedPersistor {
         let msg = message::Message::WithdrawMessage(Box::new(balance.into()));
         self.write_msg(msg);
     }
+    fn put_withdraw_status(&mut self, status: &WithdrawStatusMessage) {
+        let msg = message::Message::WithdrawStatusMessage(Box::new(status.clone()));
+        self.write_msg(msg);
+    }
     fn put_transfer(&mut self, tx: InternalTx) {
         let msg = message::Message::TransferMessage(Box::new(tx.into()));
         self.write_msg(msg);
@@ -209,6 +239,10 @@ impl PersistExector for FileBasedPersistor {
         let msg = message::Message::UserMessage(Box::new(user.into()));
         self.write_msg(msg);
     }
+    fn put_orderbook_delta(&mut self, delta: &OrderBookDeltaMessage) {
+        let msg = message::Message::OrderBookDeltaMessage(Box::new(delta.clone()));
+        self.write_msg(msg);
+    }
 }
 
 ///////////////////////////// MessengerBasedPersistor  ////////////////////////////
@@ -240,6 +274,9 @@ impl PersistExector for MessengerBasedPersistor {
     fn put_withdraw(&mut self, balance: &BalanceHistory) {
         self.inner.push_withdraw_message(&balance.into());
     }
+    fn put_withdraw_status(&mut self, status: &WithdrawStatusMessage) {
+        self.inner.push_withdraw_status_message(status);
+    }
     fn put_transfer(&mut self, tx: InternalTx) {
         self.inner.push_transfer_message(&tx.into());
     }
@@ -252,6 +289,9 @@ impl PersistExector for MessengerBasedPersistor {
     fn register_user(&mut self, user: AccountDesc) {
         self.inner.push_user_message(&user.into());
     }
+    fn put_orderbook_delta(&mut self, delta: &OrderBookDeltaMessage) {
+        self.inner.push_orderbook_delta_message(delta);
+    }
 }
 
 ///////////////////////////// DBBasedPersistor  ////////////////////////////
@@ -283,6 +323,18 @@ impl PersistExector for DBBasedPersistor {
     fn put_withdraw(&mut self, _balance: &BalanceHistory) {
         // TODO
     }
+    fn put_withdraw_status(&mut self, status: &WithdrawStatusMessage) {
+        self.inner.append_withdraw_history(WithdrawHistory {
+            time: FTimestamp(status.timestamp).into(),
+            user_id: status.user_id as i32,
+            business_id: status.business_id as i64,
+            asset: status.asset.clone(),
+            business: status.business.clone(),
+            amount: status.amount.parse().unwrap_or_default(),
+            status: status.status,
+            detail: status.detail.clone(),
+        });
+    }
     fn put_transfer(&mut self, tx: InternalTx) {
         self.inner.append_internal_transfer(tx);
     }
@@ -301,6 +353,9 @@ impl PersistExector for DBBasedPersistor {
     fn register_user(&mut self, user: AccountDesc) {
         self.inner.append_user(user);
     }
+    fn put_orderbook_delta(&mut self, _delta: &OrderBookDeltaMessage) {
+        // the book can be rebuilt from the orders, deltas are not kept
+    }
 }
 
 ///////////////////////////// CompositePersistor  ////////////////////////////
@@ -340,6 +395,11 @@ impl PersistExector for CompositePersistor {
             p.put_withdraw(balance);
         }
     }
+    fn put_withdraw_status(&mut self, status: &WithdrawStatusMessage) {
+        for p in &mut self.persistors {
+            p.put_withdraw_status(status);
+        }
+    }
     fn put_transfer(&mut self, tx: InternalTx) {
         for p in &mut self.persistors {
             p.put_transfer(tx.clone());
@@ -360,4 +420,9 @@ impl PersistExector for CompositePersistor {
             p.register_user(user.clone());
         }
     }
+    fn put_orderbook_delta(&mut self, delta: &OrderBookDeltaMessage) {
+        for p in &mut self.persistors {
+            p.put_orderbook_delta(delta);
+        }
+    }
 }
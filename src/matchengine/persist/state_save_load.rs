@@ -2,7 +2,8 @@ use crate::asset;
 use crate::asset::BalanceManager;
 use crate::controller::Controller;
 use crate::database;
-use crate::market::Order;
+use crate::eth_guard::{EthBlock, EthLog, EthLogGuard, EthLogMetadata};
+use crate::market::{FeeRate, Market, Order};
 use crate::models;
 use crate::sqlxextend::*;
 use crate::types;
@@ -10,9 +11,14 @@ use crate::types::SimpleResult;
 use crate::{config, storage};
 use arrayref::array_ref;
 use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
-use models::{tablenames, BalanceSlice, BalanceSliceInsert, OperationLog, OrderSlice, SliceHistory};
+use models::{
+    tablenames, AssetFlowSlice, BalanceSlice, BalanceSliceInsert, BalanceUpdateSlice, CancelAllTimerSlice, ClientOrderSlice,
+    CollateralPoolSlice, EthBlockSlice, EthLogSlice, FeeOverrideSlice, MarketSlice, OperationLog, OrderSlice, SliceHistory,
+    UserVolumeSlice, WithdrawSlice,
+};
 use sqlx::migrate::Migrator;
 use sqlx::Connection;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::time::{Duration, Instant};
 use types::{ConnectionType, DbType};
@@ -49,6 +55,7 @@ fn sqlverf_load_slice_from_db() -> impl std::any::Any {
     let last_balance_id = 0;
     let slice_id: i64 = 1;
     let order_id: i64 = 0;
+    let seq: i64 = 0;
     (
         sqlx::query!(
             "select * from balance_slice where slice_id = $1 and id > $2 order by id asc limit 1000",
@@ -60,6 +67,25 @@ fn sqlverf_load_slice_from_db() -> impl std::any::Any {
             slice_id,
             order_id
         ),
+        sqlx::query!("select * from market_slice where slice_id = $1", slice_id),
+        sqlx::query!("select * from fee_override_slice where slice_id = $1", slice_id),
+        sqlx::query!("select * from user_volume_slice where slice_id = $1", slice_id),
+        sqlx::query!("select * from collateral_pool_slice where slice_id = $1", slice_id),
+        sqlx::query!("select * from withdraw_slice where slice_id = $1", slice_id),
+        sqlx::query!("select * from asset_flow_slice where slice_id = $1", slice_id),
+        sqlx::query!(
+            "select * from balance_update_slice where slice_id = $1 and seq > $2 order by seq asc limit 1000",
+            slice_id,
+            seq
+        ),
+        sqlx::query!("select * from eth_block_slice where slice_id = $1", slice_id),
+        sqlx::query!("select * from eth_log_slice where slice_id = $1 order by seq asc", slice_id),
+        sqlx::query!(
+            "select * from client_order_slice where slice_id = $1 and order_id > $2 order by order_id asc limit 1000",
+            slice_id,
+            order_id
+        ),
+        sqlx::query!("select * from cancel_all_timer_slice where slice_id = $1", slice_id),
     )
 }
 
@@ -82,6 +108,69 @@ fn utest_load_slice_from_db() {
         ),
         "select * from order_slice where slice_id = $1 and id > $2 order by id asc limit 1000"
     );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1", tablenames::MARKETSLICE),
+        "select * from market_slice where slice_id = $1"
+    );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1", tablenames::FEEOVERRIDESLICE),
+        "select * from fee_override_slice where slice_id = $1"
+    );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1", tablenames::USERVOLUMESLICE),
+        "select * from user_volume_slice where slice_id = $1"
+    );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1", tablenames::COLLATERALPOOLSLICE),
+        "select * from collateral_pool_slice where slice_id = $1"
+    );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1", tablenames::WITHDRAWSLICE),
+        "select * from withdraw_slice where slice_id = $1"
+    );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1", tablenames::ASSETFLOWSLICE),
+        "select * from asset_flow_slice where slice_id = $1"
+    );
+
+    assert_eq!(
+        format!(
+            "select * from {} where slice_id = $1 and seq > $2 order by seq asc limit {}",
+            tablenames::BALANCEUPDATESLICE,
+            database::QUERY_LIMIT
+        ),
+        "select * from balance_update_slice where slice_id = $1 and seq > $2 order by seq asc limit 1000"
+    );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1", tablenames::ETHBLOCKSLICE),
+        "select * from eth_block_slice where slice_id = $1"
+    );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1 order by seq asc", tablenames::ETHLOGSLICE),
+        "select * from eth_log_slice where slice_id = $1 order by seq asc"
+    );
+
+    assert_eq!(
+        format!(
+            "select * from {} where slice_id = $1 and order_id > $2 order by order_id asc limit {}",
+            tablenames::CLIENTORDERSLICE,
+            database::QUERY_LIMIT
+        ),
+        "select * from client_order_slice where slice_id = $1 and order_id > $2 order by order_id asc limit 1000"
+    );
+
+    assert_eq!(
+        format!("select * from {} where slice_id = $1", tablenames::CANCELALLTIMERSLICE),
+        "select * from cancel_all_timer_slice where slice_id = $1"
+    );
 }
 
 pub async fn load_slice_from_db(conn: &mut ConnectionType, slice_id: i64, controller: &mut Controller) {
@@ -143,8 +232,11 @@ pub async fn load_slice_from_db(conn: &mut ConnectionType, slice_id: i64, contro
                 base: market.base.into(),
                 quote: market.quote.into(),
                 user: order.user_id as u32,
+                client_order_id: order.client_order_id as u64,
                 price: order.price,
                 amount: order.amount,
+                display_amount: order.display_amount,
+                trigger_price: order.trigger_price,
                 taker_fee: order.taker_fee,
                 maker_fee: order.maker_fee,
                 remain: order.remain,
@@ -153,6 +245,22 @@ pub async fn load_slice_from_db(conn: &mut ConnectionType, slice_id: i64, contro
                 finished_quote: order.finished_quote,
                 finished_fee: order.finished_fee,
                 post_only: order.post_only,
+                time_in_force: order.time_in_force,
+                visible: order.visible,
+                self_trade_prevention: order.self_trade_prevention,
+                group_id: order.group_id as u64,
+                oco_trigger: order.oco_trigger,
+                shared_collateral: order.shared_collateral,
+                expire_time: order.expire_time.as_ref().map_or(0.0, |t| FTimestamp::from(t).0),
+                peg: order.peg,
+                peg_offset: order.peg_offset,
+                // slices dumped before the column existed
+                priority: if order.priority == 0 {
+                    order.id as u64
+                } else {
+                    order.priority as u64
+                },
+                cancel_reason: None,
                 signature: match order.signature.len() == 64 {
                     true => *array_ref!(order.signature[..64], 0, 64),
                     false => {
@@ -170,6 +278,178 @@ pub async fn load_slice_from_db(conn: &mut ConnectionType, slice_id: i64, contro
             break;
         }
     }
+    // load market states
+    let markets: Vec<MarketSlice> = sqlx::query_as(&format!("select * from {} where slice_id = $1", tablenames::MARKETSLICE))
+        .bind(slice_id)
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+    for market_slice in markets {
+        // the market may have been removed from the config
+        if let Some(market) = controller.markets.get_mut(&market_slice.market) {
+            market_from_slice(market, &market_slice);
+        }
+    }
+    // load the finished orders remembered by their client order ids
+    let mut last_order_id: i64 = 0;
+    let client_order_query = format!(
+        "select * from {} where slice_id = $1 and order_id > $2 order by order_id asc limit {}",
+        tablenames::CLIENTORDERSLICE,
+        database::QUERY_LIMIT
+    );
+    loop {
+        let client_orders: Vec<ClientOrderSlice> = sqlx::query_as(&client_order_query)
+            .bind(slice_id)
+            .bind(last_order_id)
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        for client_order in &client_orders {
+            if let Some(market) = controller.markets.get_mut(&client_order.market) {
+                market.remember_client_order(serde_json::from_str(&client_order.detail).unwrap());
+            }
+        }
+        if let Some(client_order) = client_orders.last() {
+            last_order_id = client_order.order_id;
+        }
+        if client_orders.len() as i64 != database::QUERY_LIMIT {
+            break;
+        }
+    }
+    // load fee schedule states
+    let fee_overrides: Vec<FeeOverrideSlice> =
+        sqlx::query_as(&format!("select * from {} where slice_id = $1", tablenames::FEEOVERRIDESLICE))
+            .bind(slice_id)
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+    for fee_override in fee_overrides {
+        if let Some(market) = controller.markets.get_mut(&fee_override.market) {
+            market.fee_schedule.overrides.insert(
+                fee_override.user_id as u32,
+                FeeRate {
+                    taker_fee: fee_override.taker_fee,
+                    maker_fee: fee_override.maker_fee,
+                },
+            );
+        }
+    }
+    let volumes: Vec<UserVolumeSlice> = sqlx::query_as(&format!("select * from {} where slice_id = $1", tablenames::USERVOLUMESLICE))
+        .bind(slice_id)
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+    for volume in volumes {
+        if let Some(market) = controller.markets.get_mut(&volume.market) {
+            market
+                .fee_schedule
+                .volumes
+                .entry(volume.user_id as u32)
+                .or_default()
+                .insert(volume.day as u32, volume.volume);
+        }
+    }
+    // load collateral pools, their balances are already in the frozen balances
+    let pools: Vec<CollateralPoolSlice> = sqlx::query_as(&format!("select * from {} where slice_id = $1", tablenames::COLLATERALPOOLSLICE))
+        .bind(slice_id)
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+    for pool in pools {
+        controller
+            .balance_manager
+            .pools
+            .insert((pool.user_id as u32, pool.asset), pool.amount);
+    }
+    // load withdrawals, their pending amounts are already in the balances
+    let withdrawals: Vec<WithdrawSlice> = sqlx::query_as(&format!("select * from {} where slice_id = $1", tablenames::WITHDRAWSLICE))
+        .bind(slice_id)
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+    for withdrawal in withdrawals {
+        controller.balance_manager.withdrawals.insert(
+            withdrawal.business_id as u64,
+            asset::Withdrawal {
+                user_id: withdrawal.user_id as u32,
+                asset: withdrawal.asset,
+                business: withdrawal.business,
+                amount: withdrawal.amount,
+                status: withdrawal.status,
+            },
+        );
+    }
+    // load the deposits and withdrawals counted for the balance audit
+    let flows: Vec<AssetFlowSlice> = sqlx::query_as(&format!("select * from {} where slice_id = $1", tablenames::ASSETFLOWSLICE))
+        .bind(slice_id)
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+    for flow in flows {
+        controller.balance_manager.flows.insert(
+            flow.asset,
+            asset::AssetFlow {
+                deposit: flow.deposit,
+                withdraw: flow.withdraw,
+            },
+        );
+    }
+    // slices made before the counters existed, what has been deposited of the assets held is unknown
+    let unknown_flows: Vec<String> = controller
+        .balance_manager
+        .balances
+        .keys()
+        .map(|key| key.asset.clone())
+        .filter(|asset| !controller.balance_manager.flows.contains_key(asset))
+        .collect();
+    for asset in unknown_flows {
+        if controller.balance_manager.unknown_flows.insert(asset.clone()) {
+            log::warn!("the flows of {} are unknown, its supply is not audited", asset);
+        }
+    }
+    // load the balance updates remembered to refuse duplicates, oldest first
+    let mut last_seq = 0;
+    let update_query = format!(
+        "select * from {} where slice_id = $1 and seq > $2 order by seq asc limit {}",
+        tablenames::BALANCEUPDATESLICE,
+        database::QUERY_LIMIT
+    );
+    loop {
+        let updates: Vec<BalanceUpdateSlice> = sqlx::query_as(&update_query)
+            .bind(slice_id)
+            .bind(last_seq)
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        for update in &updates {
+            controller.update_controller.remember(asset::BalanceUpdateKey {
+                balance_type: asset::BalanceType::try_from(update.balance_type).unwrap(),
+                business_type: asset::BusinessType::try_from(update.business_type).unwrap(),
+                user_id: update.user_id as u32,
+                asset: update.asset.clone(),
+                business: update.business.clone(),
+                business_id: update.business_id as u64,
+            });
+        }
+        if let Some(update) = updates.last() {
+            last_seq = update.seq;
+        }
+        if updates.len() as i64 != database::QUERY_LIMIT {
+            break;
+        }
+    }
+    // load the cancel-all-after timers, the ones which lapsed meanwhile fire on the first tick
+    let timers: Vec<CancelAllTimerSlice> =
+        sqlx::query_as(&format!("select * from {} where slice_id = $1", tablenames::CANCELALLTIMERSLICE))
+            .bind(slice_id)
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+    for timer in timers {
+        controller
+            .cancel_all_timers
+            .insert(timer.user_id as u32, FTimestamp::from(&timer.trigger_time).0);
+    }
 }
 
 #[cfg(sqlxverf)]
@@ -233,15 +513,212 @@ pub async fn init_config_from_db(conn: &mut ConnectionType, config: &mut config:
     Ok(market_cfg)
 }
 
+// the recent blocks of the guard are in the slice tables, its block number is in the slice history
+pub async fn load_eth_guard_from_db(conn: &mut ConnectionType, slice: &SliceHistory, controller: &mut Controller) {
+    let blocks: Vec<EthBlockSlice> = sqlx::query_as(&format!("select * from {} where slice_id = $1", tablenames::ETHBLOCKSLICE))
+        .bind(slice.time)
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+    let logs: Vec<EthLogSlice> = sqlx::query_as(&format!(
+        "select * from {} where slice_id = $1 order by seq asc",
+        tablenames::ETHLOGSLICE
+    ))
+    .bind(slice.time)
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap();
+    controller.eth_guard = eth_guard_from_slices(slice.eth_block_number as u64, blocks, logs);
+}
+
+pub fn eth_guard_from_slices(block_number: u64, blocks: Vec<EthBlockSlice>, logs: Vec<EthLogSlice>) -> EthLogGuard {
+    let mut restored: BTreeMap<u64, EthBlock> = BTreeMap::new();
+    for block in blocks {
+        restored.entry(block.block_number as u64).or_default().hash = block.block_hash;
+    }
+    for log in logs {
+        restored.entry(log.block_number as u64).or_default().logs.push(EthLog {
+            meta: EthLogMetadata {
+                block_number: log.block_number as u64,
+                tx_hash: log.tx_hash,
+                log_index: log.log_index,
+            },
+            effect: serde_json::from_str(&log.effect).unwrap(),
+        });
+    }
+    EthLogGuard::restore(block_number, restored)
+}
+
+pub fn eth_guard_to_slices(slice_id: i64, eth_guard: &EthLogGuard) -> (Vec<EthBlockSlice>, Vec<EthLogSlice>) {
+    let blocks = eth_guard
+        .blocks()
+        .iter()
+        .map(|(block_number, block)| EthBlockSlice {
+            slice_id,
+            block_number: *block_number as i64,
+            block_hash: block.hash.clone(),
+        })
+        .collect();
+    let logs = eth_guard
+        .blocks()
+        .values()
+        .flat_map(|block| block.logs.iter())
+        .enumerate()
+        .map(|(idx, log)| EthLogSlice {
+            slice_id,
+            seq: idx as i64,
+            block_number: log.meta.block_number as i64,
+            tx_hash: log.meta.tx_hash.clone(),
+            log_index: log.meta.log_index.clone(),
+            effect: serde_json::to_string(&log.effect).unwrap(),
+        })
+        .collect();
+    (blocks, logs)
+}
+
+#[test]
+fn utest_eth_guard_slices() {
+    use crate::eth_guard::EthLogEffect;
+
+    let meta = |block_number: u64, log_index: &str| EthLogMetadata {
+        block_number,
+        tx_hash: format!("0x{}{}", block_number, log_index),
+        log_index: log_index.to_owned(),
+    };
+    let effect = |business_id: u64| EthLogEffect::BalanceUpdate {
+        user_id: 1,
+        asset: "ETH".to_owned(),
+        business: "deposit".to_owned(),
+        business_id,
+        change: 1.into(),
+    };
+    let logged = vec![meta(10, "0"), meta(11, "0"), meta(11, "1")];
+
+    let mut eth_guard = EthLogGuard::new(0);
+    eth_guard.set_block_hash(10, "0xa").unwrap();
+    for (idx, log_meta) in logged.iter().enumerate() {
+        eth_guard.update(log_meta.clone(), effect(idx as u64));
+    }
+
+    // restart from a slice made in the middle of block 11
+    let (blocks, logs) = eth_guard_to_slices(1, &eth_guard);
+    let mut restored = eth_guard_from_slices(eth_guard.block_number(), blocks, logs);
+    assert_eq!(restored.block_number(), 11);
+    for log_meta in &logged {
+        assert!(!restored.accept(log_meta));
+    }
+    assert!(restored.accept(&meta(11, "2")));
+    restored.update(meta(12, "0"), effect(3));
+
+    // the block hashes are kept, so a reorg is still checked and undoes the logs of before the restart
+    assert_eq!(restored.blocks()[&10].hash.as_deref(), Some("0xa"));
+    assert!(restored.revert(10, "0xb").is_err());
+    let reverted = restored.revert(10, "0xa").unwrap();
+    assert_eq!(reverted.len(), 4);
+    assert_eq!(reverted[0].meta, meta(12, "0"));
+    assert_eq!(reverted[3].effect, effect(0));
+    assert!(restored.accept(&logged[0]));
+}
+
+// needs the database of `DATABASE_URL`, like the query checks
+#[cfg(sqlxverf)]
+#[tokio::test]
+async fn utest_eth_guard_restart() {
+    use crate::asset::BalanceType;
+    use crate::controller::create_controller;
+    use crate::dto::EthBlockRequest;
+    use crate::matchengine::mock::get_simple_asset_config;
+    use fluidex_common::rust_decimal::Decimal;
+    use orchestra::rpc::exchange::{self, BalanceUpdateRequest};
+
+    let mut settings = config::Settings::default();
+    settings.db_log = std::env::var("DATABASE_URL").unwrap();
+    settings.assets = get_simple_asset_config(8);
+    let new_controller = || create_controller((settings.clone(), MarketConfigs::new()));
+    let mut conn = ConnectionType::connect(&settings.db_log).await.unwrap();
+
+    let request = |block_number: u64, log_index: &str, business_id: u64| BalanceUpdateRequest {
+        user_id: 1,
+        asset: "ETH".to_owned(),
+        business: "deposit".to_owned(),
+        business_id,
+        delta: "1".to_owned(),
+        log_metadata: Some(exchange::EthLogMetadata {
+            block_number,
+            tx_hash: format!("0x{}", business_id),
+            log_index: log_index.to_owned(),
+        }),
+        ..Default::default()
+    };
+    let eth_balance = |controller: &Controller| controller.balance_manager.get(1, BalanceType::AVAILABLE, "ETH");
+    let stream = vec![request(10, "0", 1), request(11, "0", 2), request(11, "1", 3), request(12, "0", 4)];
+
+    let mut controller = new_controller();
+    controller
+        .eth_block_update(
+            false,
+            EthBlockRequest {
+                block_number: 10,
+                block_hash: "0xa".to_owned(),
+            },
+        )
+        .unwrap();
+    for req in &stream[..3] {
+        controller.update_balance(false, req.clone()).unwrap();
+    }
+    assert_eq!(eth_balance(&controller), Decimal::from(3));
+
+    // restart from a slice made in the middle of block 11
+    let slice_id = current_timestamp() as i64;
+    dump_to_db(&mut conn, slice_id, &controller).await.unwrap();
+    let slice: SliceHistory = sqlx::query_as(&format!("select * from {} where time = $1", tablenames::SLICEHISTORY))
+        .bind(slice_id)
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+    let mut restarted = new_controller();
+    load_slice_from_db(&mut conn, slice_id, &mut restarted).await;
+    load_eth_guard_from_db(&mut conn, &slice, &mut restarted).await;
+    delete_slice(&mut conn, slice_id).await.unwrap();
+    assert_eq!(eth_balance(&restarted), Decimal::from(3));
+
+    for req in &stream[..3] {
+        restarted.update_balance(false, req.clone()).unwrap();
+    }
+    assert_eq!(eth_balance(&restarted), Decimal::from(3));
+    restarted.update_balance(false, stream[3].clone()).unwrap();
+    assert_eq!(eth_balance(&restarted), Decimal::from(4));
+
+    // the recent blocks are kept as well, so a reorg undoes the deposits made before the restart
+    let reverted = restarted
+        .eth_block_revert(
+            false,
+            EthBlockRequest {
+                block_number: 10,
+                block_hash: "0xa".to_owned(),
+            },
+        )
+        .unwrap();
+    assert_eq!(reverted.reverted_logs, 4);
+    assert!(eth_balance(&restarted).is_zero());
+}
+
 pub async fn init_from_db(conn: &mut ConnectionType, controller: &mut Controller) -> anyhow::Result<()> {
     let last_slice = get_last_slice(conn).await;
     let mut end_operation_log_id = 0;
     if let Some(slice) = last_slice {
         log::debug!("last slice {:?}", slice);
         load_slice_from_db(conn, slice.time, controller).await;
+        load_eth_guard_from_db(conn, &slice, controller).await;
         end_operation_log_id = slice.end_operation_log_id;
         controller.sequencer.set_order_id(slice.end_order_id as u64);
         controller.sequencer.set_trade_id(slice.end_trade_id as u64);
+        // slices made before the priority sequence, the priorities were taken from the order ids
+        controller.sequencer.set_priority(if slice.end_priority == 0 {
+            slice.end_order_id as u64
+        } else {
+            slice.end_priority as u64
+        });
         log::info!("set order_id and trade_id to {} {}", slice.end_order_id, slice.end_trade_id);
     }
     load_operation_log_from_db(conn, end_operation_log_id as u64, controller).await;
@@ -340,6 +817,19 @@ pub async fn dump_orders(conn: &mut ConnectionType, slice_id: i64, controller: &
                 finished_fee: order.finished_fee,
                 post_only: order.post_only,
                 signature: order.signature.to_vec(),
+                trigger_price: order.trigger_price,
+                time_in_force: order.time_in_force,
+                priority: order.priority as i64,
+                self_trade_prevention: order.self_trade_prevention,
+                client_order_id: order.client_order_id as i64,
+                display_amount: order.display_amount,
+                visible: order.visible,
+                group_id: order.group_id as i64,
+                oco_trigger: order.oco_trigger,
+                shared_collateral: order.shared_collateral,
+                expire_time: Some(order.expire_time).filter(|t| *t > 0.0).map(|t| FTimestamp(t).into()),
+                peg: order.peg,
+                peg_offset: order.peg_offset,
             }
         });
 
@@ -348,6 +838,220 @@ pub async fn dump_orders(conn: &mut ConnectionType, slice_id: i64, controller: &
     Ok(())
 }
 
+pub async fn dump_markets(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
+    let records_iter = controller.markets.values().map(|market| market_to_slice(slice_id, market));
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} markets done", insert_count);
+    Ok(())
+}
+
+pub fn market_to_slice(slice_id: i64, market: &Market) -> MarketSlice {
+    MarketSlice {
+        slice_id,
+        market: market.name.to_string(),
+        phase: market.phase,
+        trading_status: market.trading_status,
+        halted_until: market.halted_until.map(|t| FTimestamp(t).into()),
+        book_sequence: market.book_sequence as i64,
+        price: market.price,
+    }
+}
+
+pub fn market_from_slice(market: &mut Market, market_slice: &MarketSlice) {
+    market.phase = market_slice.phase;
+    market.trading_status = market_slice.trading_status;
+    market.halted_until = market_slice.halted_until.as_ref().map(|t| FTimestamp::from(t).0);
+    market.book_sequence = market_slice.book_sequence as u64;
+    market.price = market_slice.price;
+}
+
+#[test]
+fn utest_market_restart() {
+    use crate::asset::{BalanceType, BalanceUpdateController};
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use crate::sequencer::Sequencer;
+    use crate::types::OrderSide;
+    use fluidex_common::rust_decimal_macros::*;
+
+    let mut update_controller = BalanceUpdateController::new();
+    let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+    balance_manager.add(601, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+    balance_manager.add(602, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+    let sequencer = &mut Sequencer::default();
+    let persistor = &mut DummyPersistor::default();
+    let market_conf = config::Market {
+        price_band: dec!(0.1),
+        ..get_simple_market_config()
+    };
+    let settings = config::Settings::default();
+
+    let mut market = Market::new(&market_conf, &settings, balance_manager).unwrap();
+    for input in [
+        get_simple_order_input(601, OrderSide::ASK, dec!(1), dec!(100)),
+        get_simple_order_input(602, OrderSide::BID, dec!(1), dec!(100)),
+    ] {
+        market
+            .put_order(sequencer, balance_manager.into(), &mut update_controller, persistor, input)
+            .unwrap();
+    }
+    assert_eq!(market.price, dec!(100));
+
+    // the band is still there after a restart
+    let mut restarted = Market::new(&market_conf, &settings, balance_manager).unwrap();
+    market_from_slice(&mut restarted, &market_to_slice(1, &market));
+    assert_eq!(restarted.price, dec!(100));
+    assert!(restarted
+        .put_order(
+            sequencer,
+            balance_manager.into(),
+            &mut update_controller,
+            persistor,
+            get_simple_order_input(602, OrderSide::BID, dec!(1), dec!(111)),
+        )
+        .is_err());
+}
+
+pub async fn dump_client_orders(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
+    let records_iter = controller.markets.values().flat_map(|market| {
+        market
+            .finished_client_orders
+            .values()
+            .flat_map(|client_map| client_map.values())
+            .map(move |order| ClientOrderSlice {
+                slice_id,
+                order_id: order.id as i64,
+                market: market.name.to_string(),
+                detail: serde_json::to_string(order).unwrap(),
+            })
+    });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} client orders done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_cancel_all_timers(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
+    let records_iter = controller
+        .cancel_all_timers
+        .iter()
+        .map(|(user_id, trigger_time)| CancelAllTimerSlice {
+            slice_id,
+            user_id: *user_id as i32,
+            trigger_time: FTimestamp(*trigger_time).into(),
+        });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} cancel all timers done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_fee_schedules(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
+    let overrides_iter = controller.markets.values().flat_map(|market| {
+        market.fee_schedule.overrides.iter().map(move |(user_id, rate)| FeeOverrideSlice {
+            slice_id,
+            market: market.name.to_string(),
+            user_id: *user_id as i32,
+            taker_fee: rate.taker_fee,
+            maker_fee: rate.maker_fee,
+        })
+    });
+    let insert_count = dump_records(overrides_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} fee overrides done", insert_count);
+
+    let volumes_iter = controller.markets.values().flat_map(|market| {
+        market.fee_schedule.volumes.iter().flat_map(move |(user_id, days)| {
+            days.iter().map(move |(day, volume)| UserVolumeSlice {
+                slice_id,
+                market: market.name.to_string(),
+                user_id: *user_id as i32,
+                day: *day as i32,
+                volume: *volume,
+            })
+        })
+    });
+    let insert_count = dump_records(volumes_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} user volumes done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_collateral_pools(conn: &mut ConnectionType, slice_id: i64, balance_manager: &BalanceManager) -> SimpleResult {
+    let records_iter = balance_manager.pools.iter().map(|((user_id, asset), amount)| CollateralPoolSlice {
+        slice_id,
+        user_id: *user_id as i32,
+        asset: asset.clone(),
+        amount: *amount,
+    });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} collateral pools done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_withdrawals(conn: &mut ConnectionType, slice_id: i64, balance_manager: &BalanceManager) -> SimpleResult {
+    let records_iter = balance_manager.withdrawals.iter().map(|(business_id, withdrawal)| WithdrawSlice {
+        slice_id,
+        business_id: *business_id as i64,
+        user_id: withdrawal.user_id as i32,
+        asset: withdrawal.asset.clone(),
+        business: withdrawal.business.clone(),
+        amount: withdrawal.amount,
+        status: withdrawal.status,
+    });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} withdrawals done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_asset_flows(conn: &mut ConnectionType, slice_id: i64, balance_manager: &BalanceManager) -> SimpleResult {
+    let records_iter = balance_manager
+        .flows
+        .iter()
+        .filter(|(asset, _)| !balance_manager.unknown_flows.contains(*asset))
+        .map(|(asset, flow)| AssetFlowSlice {
+            slice_id,
+            asset: asset.clone(),
+            deposit: flow.deposit,
+            withdraw: flow.withdraw,
+        });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} asset flows done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_balance_updates(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
+    // seq starts from 1 as the loading pages from 0
+    let records_iter = controller
+        .update_controller
+        .applied_keys()
+        .enumerate()
+        .map(|(idx, key)| BalanceUpdateSlice {
+            slice_id,
+            seq: idx as i64 + 1,
+            balance_type: key.balance_type as i16,
+            business_type: key.business_type as i16,
+            user_id: key.user_id as i32,
+            asset: key.asset.clone(),
+            business: key.business.clone(),
+            business_id: key.business_id as i64,
+        });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} balance updates done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_eth_guard(conn: &mut ConnectionType, slice_id: i64, eth_guard: &EthLogGuard) -> SimpleResult {
+    let (blocks, logs) = eth_guard_to_slices(slice_id, eth_guard);
+    let block_count = dump_records(blocks.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+    let log_count = dump_records(logs.into_iter(), DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} eth blocks and {} eth logs done", block_count, log_count);
+    Ok(())
+}
+
 pub async fn update_slice_history(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
     let sequencer = &controller.sequencer;
     let slice_history = SliceHistory {
@@ -355,6 +1059,8 @@ pub async fn update_slice_history(conn: &mut ConnectionType, slice_id: i64, cont
         end_operation_log_id: sequencer.get_operation_log_id() as i64,
         end_order_id: sequencer.get_order_id() as i64,
         end_trade_id: sequencer.get_trade_id() as i64,
+        eth_block_number: controller.eth_guard.block_number() as i64,
+        end_priority: sequencer.get_priority() as i64,
     };
 
     slice_history.sql_query(conn).await?;
@@ -365,6 +1071,15 @@ pub async fn dump_to_db(conn: &mut ConnectionType, slice_id: i64, controller: &C
     log::info!("persisting orders and balances to db");
     dump_orders(conn, slice_id, controller).await?;
     dump_balance(conn, slice_id, &controller.balance_manager).await?;
+    dump_markets(conn, slice_id, controller).await?;
+    dump_client_orders(conn, slice_id, controller).await?;
+    dump_fee_schedules(conn, slice_id, controller).await?;
+    dump_collateral_pools(conn, slice_id, &controller.balance_manager).await?;
+    dump_withdrawals(conn, slice_id, &controller.balance_manager).await?;
+    dump_asset_flows(conn, slice_id, &controller.balance_manager).await?;
+    dump_balance_updates(conn, slice_id, controller).await?;
+    dump_eth_guard(conn, slice_id, &controller.eth_guard).await?;
+    dump_cancel_all_timers(conn, slice_id, controller).await?;
     update_slice_history(conn, slice_id, controller).await?;
     Ok(())
 }
@@ -394,6 +1109,50 @@ pub async fn delete_slice(conn: &mut ConnectionType, slice_id: i64) -> SimpleRes
         .bind(slice_id)
         .execute(&mut *conn)
         .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::MARKETSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::FEEOVERRIDESLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::USERVOLUMESLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::COLLATERALPOOLSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::WITHDRAWSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::ASSETFLOWSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::BALANCEUPDATESLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::ETHBLOCKSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::ETHLOGSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::CLIENTORDERSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::CANCELALLTIMERSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
     sqlx::query(&format!("delete from {} where time = $1", tablenames::SLICEHISTORY))
         .bind(slice_id)
         .execute(&mut *conn)
@@ -0,0 +1,3 @@
+// the extension service, see proto/matchengine_ext.proto
+#![allow(clippy::all)]
+tonic::include_proto!("matchengine_ext");
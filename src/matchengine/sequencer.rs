@@ -4,6 +4,8 @@ pub struct Sequencer {
     trade_id: u64,
     msg_id: u64,
     operation_log_id: u64,
+    // the time priority of the orders in the orderbook, see `Order::priority`
+    priority: u64,
 }
 
 impl Sequencer {
@@ -12,6 +14,7 @@ impl Sequencer {
         self.set_order_id(0);
         self.set_trade_id(0);
         self.set_msg_id(0);
+        self.set_priority(0);
     }
     pub fn next_order_id(&mut self) -> u64 {
         self.order_id += 1;
@@ -30,6 +33,10 @@ impl Sequencer {
         self.msg_id += 1;
         self.msg_id
     }
+    pub fn next_priority(&mut self) -> u64 {
+        self.priority += 1;
+        self.priority
+    }
     pub fn get_operation_log_id(&self) -> u64 {
         self.operation_log_id
     }
@@ -42,6 +49,9 @@ impl Sequencer {
     pub fn get_msg_id(&self) -> u64 {
         self.msg_id
     }
+    pub fn get_priority(&self) -> u64 {
+        self.priority
+    }
     pub fn set_operation_log_id(&mut self, id: u64) {
         log::debug!("set operation_log id {}", id);
         self.operation_log_id = id;
@@ -58,4 +68,8 @@ impl Sequencer {
         log::debug!("set msg id {}", id);
         self.msg_id = id;
     }
+    pub fn set_priority(&mut self, priority: u64) {
+        log::debug!("set priority {}", priority);
+        self.priority = priority;
+    }
 }
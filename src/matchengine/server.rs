@@ -1,6 +1,12 @@
 use crate::config::{OrderSignatrueCheck, Settings};
 use crate::controller::Controller;
+use crate::dto::{
+    BalanceAuditRequest, FeeOverrideUpdateRequest, MarketPhaseUpdateRequest, MarketStatusUpdateRequest, OrderBookOrdersRequest,
+    OrderGroupPutRequest, OrderMassCancelRequest, OrderPutRequestExt,
+};
+use crate::rpc_ext::{self as ext, matchengine_admin_server, matchengine_ext_server};
 
+use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -21,6 +27,19 @@ pub struct GrpcHandler {
     set_close: Option<oneshot::Sender<()>>,
 }
 
+// Another handle to the same controller, to serve the extension service next to the base one.
+// Only the handle created by `new` can be used for `on_leave`.
+impl Clone for GrpcHandler {
+    fn clone(&self) -> Self {
+        GrpcHandler {
+            stub: self.stub.clone(),
+            settings: self.settings.clone(),
+            task_dispatcher: self.task_dispatcher.clone(),
+            set_close: None,
+        }
+    }
+}
+
 struct ControllerDispatch<OT>(ControllerAction, oneshot::Receiver<OT>);
 
 impl<OT: 'static + Debug + Send> ControllerDispatch<OT> {
@@ -73,6 +92,7 @@ impl ServerLeave {
 impl GrpcHandler {
     pub fn new(stub: Controller, settings: Settings) -> Self {
         let mut persist_interval = tokio::time::interval(std::time::Duration::from_secs(stub.settings.persist_interval as u64));
+        let mut timer_interval = tokio::time::interval(std::time::Duration::from_secs(1));
 
         let stub = Arc::new(RwLock::new(stub));
         //we always wait so the size of channel is no matter
@@ -97,12 +117,16 @@ impl GrpcHandler {
                         task(stub_for_dispatch.clone()).await;
                     }
                     _ = persist_interval.tick() => {
-                        let stub_rd = stub_for_dispatch.read().await;
+                        let mut stub_wr = stub_for_dispatch.write().await;
+                        stub_wr.balance_audit(BalanceAuditRequest {}).ok();
                         log::info!("Start a persisting task");
                         unsafe {
-                            crate::persist::fork_and_make_slice(&*stub_rd);
+                            crate::persist::fork_and_make_slice(&*stub_wr);
                         }
                     }
+                    _ = timer_interval.tick() => {
+                        stub_for_dispatch.write().await.on_timer();
+                    }
                     _ = &mut rx_close => {
                         log::info!("Server scheduler is notified to close");
                         rx.close();
@@ -122,6 +146,10 @@ impl GrpcHandler {
         ret
     }
 
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
     pub fn on_leave(&mut self) -> ServerLeave {
         ServerLeave(
             self.task_dispatcher.clone(),
@@ -157,6 +185,207 @@ impl GrpcHandler {
     }
 }
 
+fn map_ext_err(e: anyhow::Error) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+// The calls of the extension service, see proto/matchengine_ext.proto. The messages are turned
+// into the dto structs, which are what the controller takes and writes into the operation log.
+#[tonic::async_trait]
+impl matchengine_ext_server::MatchengineExt for GrpcHandler {
+    async fn order_put(&self, request: Request<ext::OrderPutRequest>) -> ServerRet<ext::OrderInfo> {
+        let req = OrderPutRequestExt::try_from(request.into_inner()).map_err(map_ext_err)?;
+        self.check_order_signature(&req.base).await?;
+
+        let ControllerDispatch(act, rt) =
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.order_put(true, req).map(Into::into) }));
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn order_cancel(&self, request: Request<ext::OrderCancelRequest>) -> ServerRet<ext::OrderInfo> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.order_cancel(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn order_detail(&self, request: Request<ext::OrderDetailRequest>) -> ServerRet<ext::OrderInfo> {
+        let stub = self.stub.read().await;
+        Ok(Response::new(stub.order_detail(request.into_inner().into())?.into()))
+    }
+
+    async fn order_amend(&self, request: Request<ext::OrderAmendRequest>) -> ServerRet<ext::OrderInfo> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.order_amend(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn order_group_put(&self, request: Request<ext::OrderGroupPutRequest>) -> ServerRet<ext::OrderGroupPutResponse> {
+        let req = OrderGroupPutRequest::try_from(request.into_inner()).map_err(map_ext_err)?;
+        if req.orders.len() > MAX_BATCH_ORDER_NUM {
+            return Err(Status::invalid_argument(format!(
+                "out of maximum support order number ({})",
+                MAX_BATCH_ORDER_NUM
+            )));
+        }
+        for order_req in &req.orders {
+            self.check_order_signature(&order_req.base).await?;
+        }
+
+        let ControllerDispatch(act, rt) =
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.order_group_put(true, req).map(Into::into) }));
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn order_mass_cancel(&self, request: Request<ext::OrderMassCancelRequest>) -> ServerRet<ext::OrderMassCancelResponse> {
+        let req = OrderMassCancelRequest::try_from(request.into_inner()).map_err(map_ext_err)?;
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.order_mass_cancel(true, req).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    // the dead man's switch of a user, see `Controller::cancel_all_timers`
+    async fn cancel_all_after(&self, request: Request<ext::CancelAllAfterRequest>) -> ServerRet<ext::CancelAllAfterResponse> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.cancel_all_after(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn order_book_snapshot(&self, request: Request<ext::OrderBookSnapshotRequest>) -> ServerRet<ext::OrderBookSnapshotResponse> {
+        let stub = self.stub.read().await;
+        Ok(Response::new(stub.order_book_snapshot(request.into_inner().into())?.into()))
+    }
+
+    async fn order_book_orders(&self, request: Request<ext::OrderBookOrdersRequest>) -> ServerRet<ext::OrderBookOrdersResponse> {
+        let req = OrderBookOrdersRequest::try_from(request.into_inner()).map_err(map_ext_err)?;
+        let stub = self.stub.read().await;
+        Ok(Response::new(stub.order_book_orders(req, false)?.into()))
+    }
+
+    async fn market_list(&self, _request: Request<ext::MarketListRequest>) -> ServerRet<ext::MarketListResponse> {
+        let stub = self.stub.read().await;
+        Ok(Response::new(stub.market_list(MarketListRequest {})?.into()))
+    }
+
+    async fn market_summary(&self, request: Request<ext::MarketSummaryRequest>) -> ServerRet<ext::MarketSummaryResponse> {
+        let stub = self.stub.read().await;
+        Ok(Response::new(stub.market_summary(request.into_inner().into())?.into()))
+    }
+
+    async fn market_phase_update(&self, request: Request<ext::MarketPhaseUpdateRequest>) -> ServerRet<ext::MarketPhaseUpdateResponse> {
+        let req = MarketPhaseUpdateRequest::try_from(request.into_inner()).map_err(map_ext_err)?;
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.market_phase_update(true, req).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    // also applied by `reload_markets` from the status in the market table, see `Controller::market_reload`
+    async fn market_status_update(&self, request: Request<ext::MarketStatusUpdateRequest>) -> ServerRet<ext::SimpleSuccessResponse> {
+        let req = MarketStatusUpdateRequest::try_from(request.into_inner()).map_err(map_ext_err)?;
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.market_status_update(true, req).map(|_| ext::SimpleSuccessResponse {}) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn fee_override_update(&self, request: Request<ext::FeeOverrideUpdateRequest>) -> ServerRet<ext::SimpleSuccessResponse> {
+        let req = FeeOverrideUpdateRequest::try_from(request.into_inner()).map_err(map_ext_err)?;
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.fee_override_update(true, req).map(|_| ext::SimpleSuccessResponse {}) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn collateral_pool_update(
+        &self,
+        request: Request<ext::CollateralPoolUpdateRequest>,
+    ) -> ServerRet<ext::CollateralPoolUpdateResponse> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.collateral_pool_update(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    // runs the balance audit right away, the mismatches are logged as well
+    async fn balance_audit(&self, _request: Request<ext::BalanceAuditRequest>) -> ServerRet<ext::BalanceAuditResponse> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.balance_audit(BalanceAuditRequest {}).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn eth_block_update(&self, request: Request<ext::EthBlockRequest>) -> ServerRet<ext::EthBlockResponse> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.eth_block_update(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn eth_block_revert(&self, request: Request<ext::EthBlockRequest>) -> ServerRet<ext::EthBlockResponse> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.eth_block_revert(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn withdraw_request(&self, request: Request<ext::WithdrawRequest>) -> ServerRet<ext::WithdrawResponse> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.withdraw_request(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn withdraw_confirm(&self, request: Request<ext::WithdrawFinishRequest>) -> ServerRet<ext::WithdrawResponse> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.withdraw_confirm(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+
+    async fn withdraw_reject(&self, request: Request<ext::WithdrawFinishRequest>) -> ServerRet<ext::WithdrawResponse> {
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.withdraw_reject(true, request.into_inner().into()).map(Into::into) })
+        });
+
+        self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
+        map_dispatch_ret(rt.await)
+    }
+}
+
 #[tonic::async_trait]
 impl matchengine_server::Matchengine for GrpcHandler {
     async fn asset_list(&self, request: Request<AssetListRequest>) -> Result<Response<AssetListResponse>, Status> {
@@ -182,18 +411,18 @@ impl matchengine_server::Matchengine for GrpcHandler {
     }
     async fn order_detail(&self, request: tonic::Request<OrderDetailRequest>) -> Result<tonic::Response<OrderInfo>, tonic::Status> {
         let stub = self.stub.read().await;
-        Ok(Response::new(stub.order_detail(request.into_inner())?))
+        Ok(Response::new(stub.order_detail(request.into_inner().into())?))
     }
     async fn market_list(&self, request: tonic::Request<MarketListRequest>) -> Result<tonic::Response<MarketListResponse>, tonic::Status> {
         let stub = self.stub.read().await;
-        Ok(Response::new(stub.market_list(request.into_inner())?))
+        Ok(Response::new(stub.market_list(request.into_inner())?.into()))
     }
     async fn market_summary(
         &self,
         request: tonic::Request<MarketSummaryRequest>,
     ) -> Result<tonic::Response<MarketSummaryResponse>, tonic::Status> {
         let stub = self.stub.read().await;
-        Ok(Response::new(stub.market_summary(request.into_inner())?))
+        Ok(Response::new(stub.market_summary(request.into_inner())?.into()))
     }
 
     /*---------------------------- following are "written ops" ---------------------------------*/
@@ -218,7 +447,7 @@ impl matchengine_server::Matchengine for GrpcHandler {
         self.check_order_signature(&req).await?;
 
         let ControllerDispatch(act, rt) =
-            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.order_put(true, req) }));
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.order_put(true, req.into()) }));
 
         self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
         map_dispatch_ret(rt.await)
@@ -237,15 +466,16 @@ impl matchengine_server::Matchengine for GrpcHandler {
         }
 
         let ControllerDispatch(act, rt) =
-            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.batch_order_put(true, req) }));
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.batch_order_put(true, req.into()) }));
 
         self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
         map_dispatch_ret(rt.await)
     }
 
     async fn order_cancel(&self, request: tonic::Request<OrderCancelRequest>) -> Result<tonic::Response<OrderInfo>, tonic::Status> {
-        let ControllerDispatch(act, rt) =
-            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.order_cancel(true, request.into_inner()) }));
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move { ctrl.order_cancel(true, request.into_inner().into()) })
+        });
 
         self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
         map_dispatch_ret(rt.await)
@@ -255,7 +485,7 @@ impl matchengine_server::Matchengine for GrpcHandler {
         request: tonic::Request<OrderCancelAllRequest>,
     ) -> Result<tonic::Response<OrderCancelAllResponse>, tonic::Status> {
         let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
-            Box::pin(async move { ctrl.order_cancel_all(true, request.into_inner()) })
+            Box::pin(async move { ctrl.order_cancel_all(true, request.into_inner().into()) })
         });
 
         self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
@@ -326,3 +556,13 @@ impl matchengine_server::Matchengine for GrpcHandler {
         Ok(Response::new(DebugReloadResponse {}))
     }
 }
+
+// The admin service, served on its own address, see `Settings::admin_listen`.
+#[tonic::async_trait]
+impl matchengine_admin_server::MatchengineAdmin for GrpcHandler {
+    async fn order_book_orders_with_users(&self, request: Request<ext::OrderBookOrdersRequest>) -> ServerRet<ext::OrderBookOrdersResponse> {
+        let req = OrderBookOrdersRequest::try_from(request.into_inner()).map_err(map_ext_err)?;
+        let stub = self.stub.read().await;
+        Ok(Response::new(stub.order_book_orders(req, true)?.into()))
+    }
+}
@@ -1,8 +1,9 @@
-use crate::market::Order;
+use crate::market::{Order, OrderSide};
 pub use crate::models::{AccountDesc, BalanceHistory, InternalTx};
-use crate::types::OrderEventType;
+use crate::types::{OrderEventType, WithdrawStatus};
 
 use anyhow::Result;
+use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::utils::timeutil::FTimestamp;
 use serde::{Deserialize, Serialize};
 
@@ -11,7 +12,8 @@ pub mod persist;
 pub mod producer;
 
 pub use producer::{
-    BALANCES_TOPIC, DEPOSITS_TOPIC, INTERNALTX_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, UNIFY_TOPIC, USER_TOPIC, WITHDRAWS_TOPIC,
+    BALANCES_TOPIC, DEPOSITS_TOPIC, INTERNALTX_TOPIC, ORDERBOOK_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, UNIFY_TOPIC, USER_TOPIC,
+    WITHDRAWS_TOPIC, WITHDRAW_STATUS_TOPIC,
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -126,6 +128,21 @@ impl From<&BalanceHistory> for WithdrawMessage {
     }
 }
 
+// A step of a two-phase withdrawal. A confirmed withdrawal is published as a `WithdrawMessage` as well.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WithdrawStatusMessage {
+    pub timestamp: f64,
+    pub user_id: u32,
+    pub business_id: u64,
+    pub asset: String,
+    pub business: String,
+    pub amount: String,
+    pub status: WithdrawStatus,
+    pub balance_available: String,
+    pub balance_withdraw_pending: String,
+    pub detail: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TransferMessage {
     pub time: f64,
@@ -161,12 +178,28 @@ impl OrderMessage {
     pub fn from_order(order: &Order, at_step: OrderEventType) -> Self {
         Self {
             event: at_step,
-            order: *order,
+            // the full size of an iceberg order is only revealed once it is finished
+            order: match at_step {
+                OrderEventType::FINISH | OrderEventType::EXPIRED => *order,
+                _ => order.displayed(),
+            },
             base: order.base.to_string(),
             quote: order.quote.to_string(),
         }
     }
 }
+// The new total size of a price level of the orderbook, zero once the level is gone. The sequence
+// goes up by one with each delta of the market, so a gap means a delta is lost and the book has to
+// be fetched again. The book snapshot carries the sequence of the last delta it includes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OrderBookDeltaMessage {
+    pub market: String,
+    pub sequence: u64,
+    pub side: OrderSide,
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
 //re-export from market, act as TradeMessage
 pub use crate::market::Trade;
 
@@ -186,8 +219,10 @@ pub trait MessageManager: Sync + Send {
     fn push_balance_message(&mut self, balance: &BalanceMessage);
     fn push_deposit_message(&mut self, balance: &DepositMessage);
     fn push_withdraw_message(&mut self, balance: &WithdrawMessage);
+    fn push_withdraw_status_message(&mut self, status: &WithdrawStatusMessage);
     fn push_transfer_message(&mut self, tx: &TransferMessage);
     fn push_user_message(&mut self, user: &UserMessage);
+    fn push_orderbook_delta_message(&mut self, delta: &OrderBookDeltaMessage);
 }
 
 pub struct RdProducerStub<T> {
@@ -267,6 +302,10 @@ impl<T: producer::MessageScheme> MessageManager for RdProducerStub<T> {
         let message = serde_json::to_string(&withdraw).unwrap();
         self.push_message_and_topic(message, WITHDRAWS_TOPIC)
     }
+    fn push_withdraw_status_message(&mut self, status: &WithdrawStatusMessage) {
+        let message = serde_json::to_string(&status).unwrap();
+        self.push_message_and_topic(message, WITHDRAW_STATUS_TOPIC)
+    }
     fn push_transfer_message(&mut self, tx: &TransferMessage) {
         let message = serde_json::to_string(&tx).unwrap();
         self.push_message_and_topic(message, INTERNALTX_TOPIC)
@@ -275,6 +314,10 @@ impl<T: producer::MessageScheme> MessageManager for RdProducerStub<T> {
         let message = serde_json::to_string(&user).unwrap();
         self.push_message_and_topic(message, USER_TOPIC)
     }
+    fn push_orderbook_delta_message(&mut self, delta: &OrderBookDeltaMessage) {
+        let message = serde_json::to_string(&delta).unwrap();
+        self.push_message_and_topic(message, ORDERBOOK_TOPIC)
+    }
 }
 
 pub type SimpleMessageManager = RdProducerStub<producer::SimpleMessageScheme>;
@@ -293,10 +336,12 @@ pub enum Message {
     BalanceMessage(Box<BalanceMessage>),
     DepositMessage(Box<BalanceMessage>),
     OrderMessage(Box<OrderMessage>),
+    OrderBookDeltaMessage(Box<OrderBookDeltaMessage>),
     TradeMessage(Box<Trade>),
     TransferMessage(Box<TransferMessage>),
     UserMessage(Box<UserMessage>),
     WithdrawMessage(Box<BalanceMessage>),
+    WithdrawStatusMessage(Box<WithdrawStatusMessage>),
 }
 
 /*
@@ -592,6 +592,10 @@ impl MsgDataTransformer<models::OrderHistory> for ClosedOrder {
     fn into(order: &Self::MsgType) -> Option<models::OrderHistory> {
         match order.event {
             OrderEventType::FINISH => Some(order.into()),
+            OrderEventType::EXPIRED => Some(models::OrderHistory {
+                status: models::OrderStatus::Expired,
+                ..order.into()
+            }),
             _ => None,
         }
     }
@@ -185,11 +185,13 @@ impl<T: MessageScheme> RdProducerContext<T> {
 pub const BALANCES_TOPIC: &str = "balances";
 pub const DEPOSITS_TOPIC: &str = "deposits";
 pub const INTERNALTX_TOPIC: &str = "internaltransfer";
+pub const ORDERBOOK_TOPIC: &str = "orderbook";
 pub const ORDERS_TOPIC: &str = "orders";
 pub const TRADES_TOPIC: &str = "trades";
 pub const UNIFY_TOPIC: &str = "unifyevents";
 pub const USER_TOPIC: &str = "registeruser";
 pub const WITHDRAWS_TOPIC: &str = "withdraws";
+pub const WITHDRAW_STATUS_TOPIC: &str = "withdrawstatus";
 
 use std::collections::LinkedList;
 
@@ -197,9 +199,11 @@ use std::collections::LinkedList;
 pub struct SimpleMessageScheme {
     balances_list: LinkedList<String>,
     internaltxs_list: LinkedList<String>,
+    orderbook_list: LinkedList<String>,
     orders_list: LinkedList<String>,
     trades_list: LinkedList<String>,
     users_list: LinkedList<String>,
+    withdraw_status_list: LinkedList<String>,
     last_poped: Option<(&'static str, String)>,
 }
 
@@ -214,18 +218,22 @@ impl MessageScheme for SimpleMessageScheme {
     fn is_full(&self) -> bool {
         self.balances_list.len() >= 100
             || self.internaltxs_list.len() >= 100
+            || self.orderbook_list.len() >= 100
             || self.orders_list.len() >= 100
             || self.trades_list.len() >= 100
             || self.users_list.len() >= 100
+            || self.withdraw_status_list.len() >= 100
     }
 
     fn on_message(&mut self, title_tip: &'static str, message: String) {
         let list = match title_tip {
             BALANCES_TOPIC => &mut self.balances_list,
             INTERNALTX_TOPIC => &mut self.internaltxs_list,
+            ORDERBOOK_TOPIC => &mut self.orderbook_list,
             ORDERS_TOPIC => &mut self.orders_list,
             TRADES_TOPIC => &mut self.trades_list,
             USER_TOPIC => &mut self.users_list,
+            WITHDRAW_STATUS_TOPIC => &mut self.withdraw_status_list,
             _ => return,
         };
 
@@ -240,13 +248,22 @@ impl MessageScheme for SimpleMessageScheme {
 
         let mut candi_list = [
             &mut self.internaltxs_list,
+            &mut self.orderbook_list,
             &mut self.orders_list,
             &mut self.trades_list,
             &mut self.users_list,
+            &mut self.withdraw_status_list,
         ];
-        let iters = [INTERNALTX_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, USER_TOPIC]
-            .iter()
-            .zip(&mut candi_list);
+        let iters = [
+            INTERNALTX_TOPIC,
+            ORDERBOOK_TOPIC,
+            ORDERS_TOPIC,
+            TRADES_TOPIC,
+            USER_TOPIC,
+            WITHDRAW_STATUS_TOPIC,
+        ]
+        .iter()
+        .zip(&mut candi_list);
 
         for i in iters.into_iter() {
             let (tp_name, l) = i;
@@ -309,7 +326,7 @@ impl MessageScheme for FullOrderMessageScheme {
 
     fn on_message(&mut self, title_tip: &'static str, message: String) {
         match title_tip {
-            DEPOSITS_TOPIC | INTERNALTX_TOPIC | ORDERS_TOPIC | TRADES_TOPIC | USER_TOPIC | WITHDRAWS_TOPIC => {
+            DEPOSITS_TOPIC | INTERNALTX_TOPIC | ORDERS_TOPIC | TRADES_TOPIC | USER_TOPIC | WITHDRAWS_TOPIC | WITHDRAW_STATUS_TOPIC => {
                 self.ordered_list.push_back((title_tip, message))
             }
             _ => {}
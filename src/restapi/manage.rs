@@ -97,4 +97,20 @@ pub mod market {
             Ok("done")
         }
     }
+
+    // the status is written into the market table and applied by the matchengine when reloading
+    #[api_v2_operation]
+    pub async fn update_status(
+        req: web::Json<types::MarketStatusUpdateReq>,
+        app_state: web::Data<state::AppState>,
+    ) -> Result<&'static str, actix_web::Error> {
+        let status_req = req.into_inner();
+        log::debug!("Update market {} status to {:?}", status_req.market, status_req.status);
+
+        if let Err(e) = storage::config::persist_market_status_to_db(&app_state.db, &status_req.market, status_req.status).await {
+            return Err(InternalError::new(e.to_string(), StatusCode::BAD_REQUEST).into());
+        }
+
+        do_reload(&app_state.into_inner()).await
+    }
 }
@@ -1,4 +1,5 @@
 use crate::config::{Asset, Market};
+use crate::types::TradingStatus;
 use paperclip::actix::Apiv2Schema;
 use serde::{Deserialize, Serialize};
 
@@ -75,3 +76,9 @@ pub struct NewTradePairReq {
     #[serde(default)]
     pub not_reload: bool,
 }
+
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct MarketStatusUpdateReq {
+    pub market: String,
+    pub status: TradingStatus,
+}
@@ -1,6 +1,8 @@
 use super::models::{tablenames, AssetDesc, DbType, MarketDesc, TimestampDbType};
 use crate::config;
+use crate::types::TradingStatus;
 use anyhow::Result;
+use std::convert::{TryFrom, TryInto};
 
 impl From<AssetDesc> for config::Asset {
     fn from(origin: AssetDesc) -> Self {
@@ -18,11 +20,15 @@ impl From<AssetDesc> for config::Asset {
     }
 }
 
-impl From<MarketDesc> for config::Market {
-    fn from(origin: MarketDesc) -> Self {
+impl TryFrom<MarketDesc> for config::Market {
+    type Error = anyhow::Error;
+
+    fn try_from(origin: MarketDesc) -> Result<Self> {
         let market_name = origin.market_name.unwrap_or(origin.base_asset.clone() + "_" + &origin.quote_asset);
+        let fee_tiers =
+            serde_json::from_str(&origin.fee_tiers).map_err(|e| anyhow::anyhow!("invalid fee tiers of market {}: {}", market_name, e))?;
 
-        config::Market {
+        Ok(config::Market {
             base: origin.base_asset,
             quote: origin.quote_asset,
             price_prec: origin.precision_price as u32,
@@ -30,6 +36,43 @@ impl From<MarketDesc> for config::Market {
             fee_prec: origin.precision_fee as u32,
             name: market_name,
             min_amount: origin.min_amount,
+            self_trade_prevention: origin.self_trade_prevention,
+            price_band: origin.price_band,
+            halt_band: origin.halt_band,
+            halt_cooldown: origin.halt_cooldown as u64,
+            taker_fee: origin.taker_fee,
+            maker_fee: origin.maker_fee,
+            fee_tiers,
+            matching: origin.matching,
+            pro_rata_top_order_share: origin.pro_rata_top_order_share,
+            pro_rata_min_allocation: origin.pro_rata_min_allocation,
+        })
+    }
+}
+
+// the row a market is stored as, `id` and `create_time` are given by the db
+impl From<&config::Market> for MarketDesc {
+    fn from(market: &config::Market) -> Self {
+        MarketDesc {
+            id: 0,
+            create_time: None,
+            base_asset: market.base.clone(),
+            quote_asset: market.quote.clone(),
+            precision_amount: market.amount_prec as i16,
+            precision_price: market.price_prec as i16,
+            precision_fee: market.fee_prec as i16,
+            min_amount: market.min_amount,
+            market_name: Some(market.name.clone()),
+            self_trade_prevention: market.self_trade_prevention,
+            price_band: market.price_band,
+            halt_band: market.halt_band,
+            halt_cooldown: market.halt_cooldown as i64,
+            taker_fee: market.taker_fee,
+            maker_fee: market.maker_fee,
+            fee_tiers: serde_json::to_string(&market.fee_tiers).unwrap(),
+            matching: market.matching,
+            pro_rata_top_order_share: market.pro_rata_top_order_share,
+            pro_rata_min_allocation: market.pro_rata_min_allocation,
         }
     }
 }
@@ -64,7 +107,9 @@ fn sqlverf_loadmarket_from_db() -> impl std::any::Any {
         MarketDesc,
         "select id, create_time, base_asset, quote_asset, 
         precision_amount, precision_price, precision_fee,
-        min_amount, market_name from market where create_time > $1",
+        min_amount, market_name, self_trade_prevention, price_band, halt_band, halt_cooldown,
+        taker_fee, maker_fee, fee_tiers, matching, pro_rata_top_order_share, pro_rata_min_allocation
+        from market where create_time > $1",
         t
     )
 }
@@ -118,7 +163,9 @@ impl MarketConfigs {
         let query = format!(
             "select id, create_time, base_asset, quote_asset, 
         precision_amount, precision_price, precision_fee,
-        min_amount, market_name from {} where create_time > $1",
+        min_amount, market_name, self_trade_prevention, price_band, halt_band, halt_cooldown,
+        taker_fee, maker_fee, fee_tiers, matching, pro_rata_top_order_share, pro_rata_min_allocation
+        from {} where create_time > $1",
             tablenames::MARKET
         );
 
@@ -130,7 +177,7 @@ impl MarketConfigs {
                 .create_time
                 .and_then(|t| if self.market_load_time < t { Some(t) } else { None })
                 .unwrap_or(self.market_load_time);
-            ret.push(item.into());
+            ret.push(item.try_into()?);
         }
 
         log::info!("Load {} market and update load time to {}", ret.len(), self.market_load_time);
@@ -192,22 +239,110 @@ pub async fn persist_market_to_db<'c, 'e, T>(db_conn: T, market: &config::Market
 where
     T: sqlx::Executor<'e, Database = DbType>,
 {
+    let desc = MarketDesc::from(market);
     sqlx::query(&format!(
         "insert into {} (base_asset, quote_asset, 
             precision_amount, precision_price, precision_fee, 
-            min_amount, market_name) 
-            values ($1, $2, $3, $4, $5, $6, $7)",
+            min_amount, market_name, self_trade_prevention, price_band, halt_band, halt_cooldown,
+            taker_fee, maker_fee, fee_tiers, matching, pro_rata_top_order_share, pro_rata_min_allocation) 
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)",
+        tablenames::MARKET
+    ))
+    .bind(&desc.base_asset)
+    .bind(&desc.quote_asset)
+    .bind(desc.precision_amount)
+    .bind(desc.precision_price)
+    .bind(desc.precision_fee)
+    .bind(desc.min_amount)
+    .bind(&desc.market_name)
+    .bind(desc.self_trade_prevention)
+    .bind(desc.price_band)
+    .bind(desc.halt_band)
+    .bind(desc.halt_cooldown)
+    .bind(desc.taker_fee)
+    .bind(desc.maker_fee)
+    .bind(&desc.fee_tiers)
+    .bind(desc.matching)
+    .bind(desc.pro_rata_top_order_share)
+    .bind(desc.pro_rata_min_allocation)
+    .execute(db_conn)
+    .await?;
+
+    Ok(())
+}
+
+// the trading status requested for each market, by market name
+pub async fn load_market_status_from_db<'c, 'e, T>(db_conn: T) -> Result<Vec<(String, TradingStatus)>>
+where
+    T: sqlx::Executor<'e, Database = DbType>,
+{
+    let query = format!(
+        "select coalesce(market_name, base_asset || '_' || quote_asset), status from {}",
+        tablenames::MARKET
+    );
+    let rows: Vec<(String, TradingStatus)> = sqlx::query_as(&query).fetch_all(db_conn).await?;
+    Ok(rows)
+}
+
+pub async fn persist_market_status_to_db<'c, 'e, T>(db_conn: T, market_name: &str, status: TradingStatus) -> Result<()>
+where
+    T: sqlx::Executor<'e, Database = DbType>,
+{
+    let ret = sqlx::query(&format!(
+        "update {} set status = $1 where coalesce(market_name, base_asset || '_' || quote_asset) = $2",
         tablenames::MARKET
     ))
-    .bind(&market.base)
-    .bind(&market.quote)
-    .bind(market.amount_prec as i16)
-    .bind(market.price_prec as i16)
-    .bind(market.fee_prec as i16)
-    .bind(market.min_amount)
-    .bind(&market.name)
+    .bind(status)
+    .bind(market_name)
     .execute(db_conn)
     .await?;
+    if ret.rows_affected() == 0 {
+        anyhow::bail!("market {} not found", market_name);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{MatchingMode, SelfTradePrevention};
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_market_desc_round_trip() {
+        let market = config::Market {
+            name: "ETH_USDT".to_owned(),
+            base: "ETH".to_owned(),
+            quote: "USDT".to_owned(),
+            amount_prec: 4,
+            price_prec: 2,
+            fee_prec: 4,
+            min_amount: dec!(0.001),
+            self_trade_prevention: Some(SelfTradePrevention::CANCEL_OLDEST),
+            price_band: dec!(0.1),
+            halt_band: dec!(0.2),
+            halt_cooldown: 60,
+            taker_fee: dec!(0.002),
+            maker_fee: dec!(0.001),
+            fee_tiers: vec![config::FeeTier {
+                volume: dec!(10000),
+                taker_fee: dec!(0.001),
+                maker_fee: dec!(0),
+            }],
+            matching: MatchingMode::PRO_RATA,
+            pro_rata_top_order_share: dec!(0.2),
+            pro_rata_min_allocation: dec!(0.01),
+        };
+        let desc = MarketDesc::from(&market);
+        assert_eq!(config::Market::try_from(desc.clone()).unwrap(), market);
+
+        // a market stored before the settings were added
+        let desc = MarketDesc {
+            market_name: None,
+            fee_tiers: "[]".to_owned(),
+            ..desc
+        };
+        assert_eq!(config::Market::try_from(desc).unwrap().name, "ETH_USDT");
+    }
+}
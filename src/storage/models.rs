@@ -36,8 +36,20 @@ pub mod tablenames {
     pub const ORDERSLICE: &str = "order_slice";
     pub const BALANCESLICE: &str = "balance_slice";
     pub const SLICEHISTORY: &str = "slice_history";
+    pub const MARKETSLICE: &str = "market_slice";
+    pub const FEEOVERRIDESLICE: &str = "fee_override_slice";
+    pub const USERVOLUMESLICE: &str = "user_volume_slice";
+    pub const COLLATERALPOOLSLICE: &str = "collateral_pool_slice";
+    pub const WITHDRAWSLICE: &str = "withdraw_slice";
+    pub const ASSETFLOWSLICE: &str = "asset_flow_slice";
+    pub const BALANCEUPDATESLICE: &str = "balance_update_slice";
+    pub const ETHBLOCKSLICE: &str = "eth_block_slice";
+    pub const ETHLOGSLICE: &str = "eth_log_slice";
+    pub const CLIENTORDERSLICE: &str = "client_order_slice";
+    pub const CANCELALLTIMERSLICE: &str = "cancel_all_timer_slice";
     pub const MARKETTRADE: &str = "market_trade";
     pub const INTERNALTX: &str = "internal_tx";
+    pub const WITHDRAWHISTORY: &str = "withdraw_history";
 }
 
 use tablenames::*;
@@ -67,6 +79,18 @@ pub struct MarketDesc {
     pub precision_fee: i16,
     pub min_amount: DecimalDbType,
     pub market_name: Option<String>,
+    // the settings of `config::Market` which are not above
+    pub self_trade_prevention: Option<types::SelfTradePrevention>,
+    pub price_band: DecimalDbType,
+    pub halt_band: DecimalDbType,
+    pub halt_cooldown: i64,
+    pub taker_fee: DecimalDbType,
+    pub maker_fee: DecimalDbType,
+    // `config::FeeTier`s in json
+    pub fee_tiers: String,
+    pub matching: types::MatchingMode,
+    pub pro_rata_top_order_share: DecimalDbType,
+    pub pro_rata_min_allocation: DecimalDbType,
 }
 
 #[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize, Apiv2Schema)]
@@ -199,6 +223,129 @@ pub struct OrderSlice {
     pub finished_fee: DecimalDbType,
     pub post_only: bool,
     pub signature: Vec<u8>,
+    pub trigger_price: DecimalDbType,
+    pub time_in_force: types::TimeInForce,
+    pub priority: i64,
+    pub self_trade_prevention: types::SelfTradePrevention,
+    pub client_order_id: i64,
+    pub display_amount: DecimalDbType,
+    pub visible: DecimalDbType,
+    pub group_id: i64,
+    pub oco_trigger: types::OcoTrigger,
+    pub shared_collateral: bool,
+    pub expire_time: Option<TimestampDbType>,
+    pub peg: types::PegReference,
+    pub peg_offset: DecimalDbType,
+}
+
+// the state of a market which is not kept in its orders
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct MarketSlice {
+    pub slice_id: i64,
+    pub market: String,
+    pub phase: types::MarketPhase,
+    pub trading_status: types::TradingStatus,
+    pub halted_until: Option<TimestampDbType>,
+    pub book_sequence: i64,
+    // the last traded price, the reference of the bands and the stop triggers
+    pub price: DecimalDbType,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct FeeOverrideSlice {
+    pub slice_id: i64,
+    pub market: String,
+    pub user_id: i32,
+    pub taker_fee: DecimalDbType,
+    pub maker_fee: DecimalDbType,
+}
+
+// the traded quote volume of a user in a day, for the fee tiers
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct UserVolumeSlice {
+    pub slice_id: i64,
+    pub market: String,
+    pub user_id: i32,
+    pub day: i32,
+    pub volume: DecimalDbType,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct CollateralPoolSlice {
+    pub slice_id: i64,
+    pub user_id: i32,
+    pub asset: String,
+    pub amount: DecimalDbType,
+}
+
+// a two-phase withdrawal, its pending amount is in the balance slice
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct WithdrawSlice {
+    pub slice_id: i64,
+    pub business_id: i64,
+    pub user_id: i32,
+    pub asset: String,
+    pub business: String,
+    pub amount: DecimalDbType,
+    pub status: types::WithdrawStatus,
+}
+
+// the cumulative deposits and withdrawals of an asset, for the balance audit
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct AssetFlowSlice {
+    pub slice_id: i64,
+    pub asset: String,
+    pub deposit: DecimalDbType,
+    pub withdraw: DecimalDbType,
+}
+
+// a balance update remembered to refuse duplicates, seq keeps them in the order they were applied
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct BalanceUpdateSlice {
+    pub slice_id: i64,
+    pub seq: i64,
+    pub balance_type: i16,
+    pub business_type: i16,
+    pub user_id: i32,
+    pub asset: String,
+    pub business: String,
+    pub business_id: i64,
+}
+
+// a recent L1 block of the eth log guard
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct EthBlockSlice {
+    pub slice_id: i64,
+    pub block_number: i64,
+    pub block_hash: Option<String>,
+}
+
+// a log of a recent L1 block, with what it did as json
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct EthLogSlice {
+    pub slice_id: i64,
+    pub seq: i64,
+    pub block_number: i64,
+    pub tx_hash: String,
+    pub log_index: String,
+    pub effect: String,
+}
+
+// a finished order remembered by its client order id, the order is kept as json
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct ClientOrderSlice {
+    pub slice_id: i64,
+    pub order_id: i64,
+    pub market: String,
+    pub detail: String,
+}
+
+// the time the orders of a user are cancelled at, see `cancel_all_after`
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct CancelAllTimerSlice {
+    pub slice_id: i64,
+    pub user_id: i32,
+    pub trigger_time: TimestampDbType,
 }
 
 // xx_id here means the last persisted entry id
@@ -208,6 +355,9 @@ pub struct SliceHistory {
     pub end_operation_log_id: i64,
     pub end_order_id: i64,
     pub end_trade_id: i64,
+    // the block the eth log guard is at
+    pub eth_block_number: i64,
+    pub end_priority: i64,
 }
 
 #[derive(sqlx::FromRow, Debug, Clone, Serialize, Apiv2Schema)]
@@ -222,6 +372,19 @@ pub struct MarketTrade {
     pub taker_side: OrderSide,
 }
 
+// a step of a two-phase withdrawal
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct WithdrawHistory {
+    pub time: TimestampDbType,
+    pub user_id: i32,
+    pub business_id: i64,
+    pub asset: String,
+    pub business: String,
+    pub amount: DecimalDbType,
+    pub status: types::WithdrawStatus,
+    pub detail: String,
+}
+
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct InternalTx {
     pub time: TimestampDbType,
@@ -261,6 +424,29 @@ impl sqlxextend::BindQueryArg<'_, DbType> for InternalTx {
 
 impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for InternalTx {}
 
+/* --------------------- models::WithdrawHistory -----------------------------*/
+impl sqlxextend::TableSchemas for WithdrawHistory {
+    fn table_name() -> &'static str {
+        WITHDRAWHISTORY
+    }
+    const ARGN: i32 = 8;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for WithdrawHistory {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.time);
+        arg.add(self.user_id);
+        arg.add(self.business_id);
+        arg.add(&self.asset);
+        arg.add(&self.business);
+        arg.add(self.amount);
+        arg.add(self.status);
+        arg.add(&self.detail);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for WithdrawHistory {}
+
 /* --------------------- models::AccountDesc -----------------------------*/
 impl sqlxextend::TableSchemas for AccountDesc {
     fn table_name() -> &'static str {
@@ -398,7 +584,7 @@ impl sqlxextend::TableSchemas for OrderSlice {
     fn table_name() -> &'static str {
         ORDERSLICE
     }
-    const ARGN: i32 = 19;
+    const ARGN: i32 = 32;
     //fn default_argsn() -> Vec<i32>{ vec![1] }
 }
 
@@ -423,11 +609,256 @@ impl sqlxextend::BindQueryArg<'_, DbType> for OrderSlice {
         arg.add(&self.finished_fee);
         arg.add(&self.post_only);
         arg.add(&self.signature);
+        arg.add(&self.trigger_price);
+        arg.add(self.time_in_force);
+        arg.add(self.priority);
+        arg.add(self.self_trade_prevention);
+        arg.add(self.client_order_id);
+        arg.add(&self.display_amount);
+        arg.add(&self.visible);
+        arg.add(self.group_id);
+        arg.add(self.oco_trigger);
+        arg.add(self.shared_collateral);
+        arg.add(self.expire_time);
+        arg.add(self.peg);
+        arg.add(&self.peg_offset);
     }
 }
 
 impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for OrderSlice {}
 
+/* --------------------- models::MarketSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for MarketSlice {
+    fn table_name() -> &'static str {
+        MARKETSLICE
+    }
+    const ARGN: i32 = 7;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for MarketSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.market);
+        arg.add(self.phase);
+        arg.add(self.trading_status);
+        arg.add(self.halted_until);
+        arg.add(self.book_sequence);
+        arg.add(self.price);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for MarketSlice {}
+
+/* --------------------- models::FeeOverrideSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for FeeOverrideSlice {
+    fn table_name() -> &'static str {
+        FEEOVERRIDESLICE
+    }
+    const ARGN: i32 = 5;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for FeeOverrideSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.market);
+        arg.add(self.user_id);
+        arg.add(&self.taker_fee);
+        arg.add(&self.maker_fee);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for FeeOverrideSlice {}
+
+/* --------------------- models::UserVolumeSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for UserVolumeSlice {
+    fn table_name() -> &'static str {
+        USERVOLUMESLICE
+    }
+    const ARGN: i32 = 5;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for UserVolumeSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.market);
+        arg.add(self.user_id);
+        arg.add(self.day);
+        arg.add(&self.volume);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for UserVolumeSlice {}
+
+/* --------------------- models::CollateralPoolSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for CollateralPoolSlice {
+    fn table_name() -> &'static str {
+        COLLATERALPOOLSLICE
+    }
+    const ARGN: i32 = 4;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for CollateralPoolSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.amount);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for CollateralPoolSlice {}
+
+/* --------------------- models::WithdrawSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for WithdrawSlice {
+    fn table_name() -> &'static str {
+        WITHDRAWSLICE
+    }
+    const ARGN: i32 = 7;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for WithdrawSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.business_id);
+        arg.add(self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.business);
+        arg.add(&self.amount);
+        arg.add(self.status);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for WithdrawSlice {}
+
+/* --------------------- models::AssetFlowSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for AssetFlowSlice {
+    fn table_name() -> &'static str {
+        ASSETFLOWSLICE
+    }
+    const ARGN: i32 = 4;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for AssetFlowSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.asset);
+        arg.add(&self.deposit);
+        arg.add(&self.withdraw);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for AssetFlowSlice {}
+
+/* --------------------- models::BalanceUpdateSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for BalanceUpdateSlice {
+    fn table_name() -> &'static str {
+        BALANCEUPDATESLICE
+    }
+    const ARGN: i32 = 8;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for BalanceUpdateSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.seq);
+        arg.add(self.balance_type);
+        arg.add(self.business_type);
+        arg.add(self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.business);
+        arg.add(self.business_id);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for BalanceUpdateSlice {}
+
+/* --------------------- models::EthBlockSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for EthBlockSlice {
+    fn table_name() -> &'static str {
+        ETHBLOCKSLICE
+    }
+    const ARGN: i32 = 3;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for EthBlockSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.block_number);
+        arg.add(&self.block_hash);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for EthBlockSlice {}
+
+/* --------------------- models::EthLogSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for EthLogSlice {
+    fn table_name() -> &'static str {
+        ETHLOGSLICE
+    }
+    const ARGN: i32 = 6;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for EthLogSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.seq);
+        arg.add(self.block_number);
+        arg.add(&self.tx_hash);
+        arg.add(&self.log_index);
+        arg.add(&self.effect);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for EthLogSlice {}
+
+/* --------------------- models::ClientOrderSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for ClientOrderSlice {
+    fn table_name() -> &'static str {
+        CLIENTORDERSLICE
+    }
+    const ARGN: i32 = 4;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for ClientOrderSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.order_id);
+        arg.add(&self.market);
+        arg.add(&self.detail);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for ClientOrderSlice {}
+
+/* --------------------- models::CancelAllTimerSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for CancelAllTimerSlice {
+    fn table_name() -> &'static str {
+        CANCELALLTIMERSLICE
+    }
+    const ARGN: i32 = 3;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for CancelAllTimerSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.user_id);
+        arg.add(self.trigger_time);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for CancelAllTimerSlice {}
+
 /* --------------------- models::BalanceSliceInsert -----------------------------*/
 
 impl sqlxextend::TableSchemas for BalanceSliceInsert {
@@ -458,7 +889,7 @@ impl sqlxextend::TableSchemas for SliceHistory {
     fn table_name() -> &'static str {
         SLICEHISTORY
     }
-    const ARGN: i32 = 4;
+    const ARGN: i32 = 6;
     fn default_argsn() -> Vec<i32> {
         vec![1]
     }
@@ -470,6 +901,8 @@ impl sqlxextend::BindQueryArg<'_, DbType> for SliceHistory {
         arg.add(self.end_operation_log_id);
         arg.add(self.end_order_id);
         arg.add(self.end_trade_id);
+        arg.add(self.eth_block_number);
+        arg.add(self.end_priority);
     }
 }
 
@@ -23,12 +23,185 @@ pub enum OrderSide {
     BID,
 }
 // TryFromPrimitive
+#[allow(non_camel_case_types)]
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
 #[sqlx(type_name = "varchar")]
 #[sqlx(rename_all = "lowercase")]
 pub enum OrderType {
     LIMIT,
     MARKET,
+    // stop orders rest in the trigger book of the market until the last price
+    // reaches their trigger price, then they become LIMIT / MARKET orders
+    STOP_LIMIT,
+    STOP_MARKET,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum TimeInForce {
+    // good till cancelled, the remain part rests in the orderbook
+    GTC,
+    // immediate or cancel, the remain part is cancelled after matching
+    IOC,
+    // fill or kill, the order is rejected unless it can be fully filled at once
+    FOK,
+}
+
+impl Default for TimeInForce {
+    fn default() -> Self {
+        TimeInForce::GTC
+    }
+}
+
+// which requests a market accepts, changed by the operators
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum TradingStatus {
+    OPEN,
+    // nothing is accepted
+    HALTED,
+    // orders can be cancelled, but not placed
+    CANCEL_ONLY,
+    // only post only orders can be placed, so no trade happens
+    POST_ONLY,
+}
+
+impl Default for TradingStatus {
+    fn default() -> Self {
+        TradingStatus::OPEN
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum MarketPhase {
+    // orders are matched as soon as they arrive
+    CONTINUOUS,
+    // limit orders are collected without matching, until the book is uncrossed at a single price
+    AUCTION,
+}
+
+impl Default for MarketPhase {
+    fn default() -> Self {
+        MarketPhase::CONTINUOUS
+    }
+}
+
+// what happens when an order would trade against an order of the same user
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum SelfTradePrevention {
+    // self trades are allowed
+    NONE,
+    // the incoming order is cancelled
+    CANCEL_NEWEST,
+    // the resting order is cancelled, and the incoming order goes on matching
+    CANCEL_OLDEST,
+    // both orders are cancelled
+    CANCEL_BOTH,
+    // both orders are decreased by the smaller remain, and the one left with nothing is cancelled
+    DECREMENT_AND_CANCEL,
+}
+
+impl Default for SelfTradePrevention {
+    fn default() -> Self {
+        SelfTradePrevention::CANCEL_NEWEST
+    }
+}
+
+// the steps of a two-phase withdrawal
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum WithdrawStatus {
+    // the amount is held in the pending balance
+    REQUESTED,
+    // the amount is gone for good
+    CONFIRMED,
+    // the amount is given back as available balance
+    REJECTED,
+}
+
+// how an incoming order is shared among the orders at a price level
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum MatchingMode {
+    // the orders are filled one after another by time priority
+    PRICE_TIME,
+    // the orders are filled in proportion to their sizes, see `config::Market`
+    PRO_RATA,
+}
+
+impl Default for MatchingMode {
+    fn default() -> Self {
+        MatchingMode::PRICE_TIME
+    }
+}
+
+// when a filled order of a group cancels the other orders of the group
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum OcoTrigger {
+    // as soon as anything of the order is traded
+    PARTIAL_FILL,
+    // only when the order is fully traded
+    FULL_FILL,
+}
+
+impl Default for OcoTrigger {
+    fn default() -> Self {
+        OcoTrigger::PARTIAL_FILL
+    }
+}
+
+// the price a pegged order follows, it is taken from the orders which are not pegged
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+pub enum PegReference {
+    // not pegged
+    NONE,
+    BEST_BID,
+    BEST_ASK,
+    // the middle of the best bid and the best ask
+    MID,
+}
+
+impl Default for PegReference {
+    fn default() -> Self {
+        PegReference::NONE
+    }
+}
+
+// why an order is finished before being fully filled
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum CancelReason {
+    USER,
+    POST_ONLY,
+    SELF_TRADE,
+    IMMEDIATE_OR_CANCEL,
+    FILL_OR_KILL,
+    // another order of its group is filled
+    ORDER_GROUP,
+    // nothing is left in the shared collateral pool it draws on
+    COLLATERAL_EXHAUSTED,
+    // its expire time has passed
+    EXPIRED,
+    // a pegged bid moved up and the user could not pay for the new price
+    PEG_UNFUNDED,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
@@ -36,7 +209,10 @@ pub enum OrderEventType {
     PUT = 1,
     UPDATE = 2,
     FINISH = 3,
+    // the order is finished by the engine after its expire time
     EXPIRED = 4,
+    // a stop order is triggered and becomes an ordinary order
+    TRIGGERED = 5,
 }
 
 //pub type DbType = diesel::mysql::Mysql;